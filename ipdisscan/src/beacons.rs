@@ -1,31 +1,48 @@
+use crate::known_keys::{KnownKeys, Verification};
 use color_eyre::eyre::Report;
 use crossbeam::channel::{unbounded, Receiver, Sender, TrySendError};
 use ipdisserver::Answer;
 use std::collections::HashMap;
 use std::fmt;
-use std::net::IpAddr;
+use std::net::SocketAddr;
 use std::thread::sleep;
 use std::time::Duration;
-use tracing::trace;
+use tracing::{debug, error, info, trace, warn};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BeaconAnswer {
-    pub addr: IpAddr,
+    /// Source of the answer. IPv6 link-local addresses keep the scope id of the interface
+    /// where the answer was received, to be reachable.
+    pub addr: SocketAddr,
     pub payload: Answer,
+    pub verification: Verification,
+    /// False once the beacon said goodbye, until it answers again.
+    pub online: bool,
+}
+
+impl BeaconAnswer {
+    /// Beacon IP address, with `%scope_id` for scoped IPv6 addresses, e.g. `fe80::1%2`.
+    pub fn host(&self) -> String {
+        match self.addr {
+            SocketAddr::V6(a) if a.scope_id() != 0 => format!("{}%{}", a.ip(), a.scope_id()),
+            a => a.ip().to_string(),
+        }
+    }
 }
 
 impl fmt::Display for BeaconAnswer {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}: {}", self.addr, self.payload)
+        write!(f, "{}: {}", self.host(), self.payload)
     }
 }
 
-type BeaconAnswers = HashMap<IpAddr, BeaconAnswer>;
+type BeaconAnswers = HashMap<SocketAddr, BeaconAnswer>;
 
 pub fn run(
     channel_receiving_end: Receiver<BeaconAnswer>,
     output_channel_send_end: Sender<Vec<BeaconAnswer>>,
     new_beacon_notification_channel_send_end: Sender<()>,
+    mut known_keys: KnownKeys,
 ) -> Result<(), Report> {
     let mut servers = BeaconAnswers::new();
     trace!("Starting server answers update loop.");
@@ -34,6 +51,7 @@ pub fn run(
             servers,
             channel_receiving_end.clone(),
             new_beacon_notification_channel_send_end.clone(),
+            &mut known_keys,
         )?;
         output_channel_send_end.try_send(servers.values().map(|x| x.to_owned()).collect())?;
         sleep(Duration::from_secs_f64(0.1)); // Ease CPU load
@@ -52,12 +70,24 @@ fn beacons_update(
     mut beacons: BeaconAnswers,
     channel_receiving_end: Receiver<BeaconAnswer>,
     new_beacon_notification_channel_send_end: Sender<()>,
+    known_keys: &mut KnownKeys,
 ) -> Result<BeaconAnswers, Report> {
     loop {
-        let beacon = match channel_receiving_end.try_recv() {
+        let mut beacon = match channel_receiving_end.try_recv() {
             Ok(b) => b,
             _ => return Ok(beacons),
         };
+        if !beacon.online {
+            mark_offline(&mut beacons, beacon, known_keys);
+            continue;
+        }
+        if beacon.verification == Verification::Legacy
+            && answers_current_protocol(&beacons, &beacon, known_keys)
+        {
+            debug!(addr = %beacon.addr, "Legacy answer from a beacon answering the current protocol, ignored.");
+            continue;
+        }
+        beacon.verification = known_keys.verify(&beacon.host(), beacon.verification);
         trace!(?beacon, "Updating beacons.");
         if beacons.insert(beacon.addr, beacon).is_none() {
             trace!("New beacon added.");
@@ -73,12 +103,47 @@ fn beacons_update(
     }
 }
 
+/// True if the beacon signed its answers, or answered without the legacy format: it answers
+/// both the requests with protocol header and the legacy ones.
+fn answers_current_protocol(
+    beacons: &BeaconAnswers,
+    beacon: &BeaconAnswer,
+    known_keys: &KnownKeys,
+) -> bool {
+    known_keys.contains(&beacon.host())
+        || beacons
+            .get(&beacon.addr)
+            .is_some_and(|b| b.verification != Verification::Legacy)
+}
+
+/// Mark the beacon offline if the goodbye is signed with its key: unsigned goodbyes can be
+/// forged by anyone on the network. Goodbyes older than the last message of the beacon are
+/// replays, duplicates are dropped by the listening thread.
+fn mark_offline(beacons: &mut BeaconAnswers, goodbye: BeaconAnswer, known_keys: &mut KnownKeys) {
+    let Some(beacon) = beacons.get_mut(&goodbye.addr) else {
+        debug!(addr = %goodbye.addr, "Goodbye from an unknown beacon, ignored.");
+        return;
+    };
+    let verification = known_keys.verify(&goodbye.host(), goodbye.verification);
+    match verification {
+        Verification::Verified(_) => {
+            info!(addr = %goodbye.addr, "Beacon said goodbye, marked offline.");
+            beacon.online = false;
+        }
+        Verification::Replayed(_) => {
+            error!(addr = %goodbye.addr, %verification, "REPLAYED GOODBYE! Older than the last message of the beacon, ignored.")
+        }
+        _ => warn!(addr = %goodbye.addr, %verification, "Goodbye not verified, ignored."),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::broadcast::init_notification_channel;
 
     use super::*;
-    use std::net::Ipv4Addr;
+    use ipdisserver::identity::DeviceKey;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV6};
 
     #[test]
     #[tracing_test::traced_test]
@@ -86,27 +151,36 @@ mod test {
         let (sender, receiver) = init_input_channel();
         let (notifier, _notif_recv) = init_notification_channel();
         let answer1 = BeaconAnswer {
-            addr: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
+            addr: SocketAddr::from((Ipv4Addr::new(192, 168, 0, 1), 1901)),
             payload: Answer::default(),
+            verification: Verification::Unverified,
+            online: true,
         };
         let answer1_new = BeaconAnswer {
-            addr: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
+            addr: SocketAddr::from((Ipv4Addr::new(192, 168, 0, 1), 1901)),
             payload: Answer::default(),
+            verification: Verification::Unverified,
+            online: true,
         };
         let answer2 = BeaconAnswer {
-            addr: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
+            addr: SocketAddr::from((Ipv4Addr::new(192, 168, 0, 2), 1901)),
             payload: Answer::default(),
+            verification: Verification::Unverified,
+            online: true,
         };
         let answer2_new = BeaconAnswer {
-            addr: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
+            addr: SocketAddr::from((Ipv4Addr::new(192, 168, 0, 2), 1901)),
             payload: Answer::default(),
+            verification: Verification::Unverified,
+            online: true,
         };
         sender.send(answer2.clone()).unwrap();
         sender.send(answer1.clone()).unwrap();
         sender.send(answer1_new.clone()).unwrap();
         sender.send(answer2_new.clone()).unwrap();
         let mut beacons = BeaconAnswers::new();
-        beacons = beacons_update(beacons, receiver, notifier).unwrap();
+        let mut known_keys = KnownKeys::default();
+        beacons = beacons_update(beacons, receiver, notifier, &mut known_keys).unwrap();
         assert_eq!(
             beacons.get(&answer1.addr).unwrap().payload,
             answer1_new.payload
@@ -117,13 +191,154 @@ mod test {
         );
     }
 
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_goodbye() {
+        let (sender, receiver) = init_input_channel();
+        let (notifier, _notif_recv) = init_notification_channel();
+        let key = DeviceKey::generate().unwrap().public_key();
+        let answer = BeaconAnswer {
+            addr: SocketAddr::from((Ipv4Addr::new(192, 168, 0, 1), 1901)),
+            payload: Answer::default(),
+            verification: Verification::Verified(key),
+            online: true,
+        };
+        let goodbye = BeaconAnswer {
+            online: false,
+            ..answer.clone()
+        };
+        let mut known_keys = KnownKeys::default();
+        let mut beacons = BeaconAnswers::new();
+        let mut update = |beacons, sent: &[&BeaconAnswer]| {
+            for beacon in sent {
+                sender.send((*beacon).clone()).unwrap();
+            }
+            beacons_update(beacons, receiver.clone(), notifier.clone(), &mut known_keys).unwrap()
+        };
+        let unsigned = BeaconAnswer {
+            verification: Verification::Unverified,
+            ..goodbye.clone()
+        };
+        let unknown = BeaconAnswer {
+            addr: SocketAddr::from((Ipv4Addr::new(192, 168, 0, 2), 1901)),
+            ..goodbye.clone()
+        };
+        beacons = update(beacons, &[&answer, &unsigned, &unknown]);
+        assert!(beacons[&answer.addr].online);
+        assert!(!beacons.contains_key(&unknown.addr));
+        assert!(logs_contain("Goodbye not verified"));
+        beacons = update(beacons, &[&goodbye]);
+        assert!(!beacons[&answer.addr].online);
+        beacons = update(beacons, &[&answer]);
+        assert!(beacons[&answer.addr].online);
+        let replayed = BeaconAnswer {
+            verification: Verification::Replayed(key),
+            ..goodbye.clone()
+        };
+        beacons = update(beacons, &[&replayed]);
+        assert!(beacons[&answer.addr].online);
+        assert!(logs_contain("REPLAYED GOODBYE!"));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_signature_missing() {
+        let (sender, receiver) = init_input_channel();
+        let (notifier, _notif_recv) = init_notification_channel();
+        let key = DeviceKey::generate().unwrap().public_key();
+        let signed = BeaconAnswer {
+            addr: SocketAddr::from((Ipv4Addr::new(192, 168, 0, 1), 1901)),
+            payload: Answer::default(),
+            verification: Verification::Verified(key),
+            online: true,
+        };
+        let stripped = BeaconAnswer {
+            verification: Verification::Unverified,
+            ..signed.clone()
+        };
+        let unsigned_beacon = BeaconAnswer {
+            addr: SocketAddr::from((Ipv4Addr::new(192, 168, 0, 2), 1901)),
+            ..stripped.clone()
+        };
+        for beacon in [&signed, &stripped, &unsigned_beacon] {
+            sender.send(beacon.clone()).unwrap();
+        }
+        let mut known_keys = KnownKeys::default();
+        let beacons =
+            beacons_update(BeaconAnswers::new(), receiver, notifier, &mut known_keys).unwrap();
+        assert_eq!(
+            beacons[&signed.addr].verification,
+            Verification::SignatureMissing { known: key }
+        );
+        assert!(logs_contain("BEACON SIGNATURE MISSING!"));
+        assert_eq!(
+            beacons[&unsigned_beacon.addr].verification,
+            Verification::Unverified
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_legacy_answer() {
+        let (sender, receiver) = init_input_channel();
+        let (notifier, _notif_recv) = init_notification_channel();
+        let key = DeviceKey::generate().unwrap().public_key();
+        let signed = BeaconAnswer {
+            addr: SocketAddr::from((Ipv4Addr::new(192, 168, 0, 1), 1901)),
+            payload: Answer::from(r#"{"hostname":"signed"}"#.to_string()),
+            verification: Verification::Verified(key),
+            online: true,
+        };
+        let legacy = BeaconAnswer {
+            payload: Answer::from(r#"{"hostname":"legacy"}"#.to_string()),
+            verification: Verification::Legacy,
+            ..signed.clone()
+        };
+        let old_beacon = BeaconAnswer {
+            addr: SocketAddr::from((Ipv4Addr::new(192, 168, 0, 2), 1901)),
+            ..legacy.clone()
+        };
+        // Legacy answer first, replaced by the signed one.
+        for beacon in [&legacy, &signed, &legacy, &old_beacon, &old_beacon] {
+            sender.send(beacon.clone()).unwrap();
+        }
+        let mut known_keys = KnownKeys::default();
+        let beacons =
+            beacons_update(BeaconAnswers::new(), receiver, notifier, &mut known_keys).unwrap();
+        assert_eq!(beacons[&signed.addr], signed);
+        assert_eq!(beacons[&old_beacon.addr], old_beacon);
+        assert!(logs_contain(
+            "Legacy answer from a beacon answering the current protocol"
+        ));
+        assert!(!logs_contain("BEACON SIGNATURE MISSING!"));
+    }
+
+    #[test]
+    fn test_host() {
+        let answer = |addr| BeaconAnswer {
+            addr,
+            payload: Answer::default(),
+            verification: Verification::Unverified,
+            online: true,
+        };
+        let v4 = SocketAddr::from((Ipv4Addr::new(192, 168, 0, 1), 1901));
+        assert_eq!(answer(v4).host(), "192.168.0.1");
+        let link_local = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+        let scoped = SocketAddr::V6(SocketAddrV6::new(link_local, 1901, 0, 2));
+        assert_eq!(answer(scoped).host(), "fe80::1%2");
+        let global = SocketAddr::from((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 1901));
+        assert_eq!(answer(global).host(), "2001:db8::1");
+    }
+
     #[test]
     #[tracing_test::traced_test]
     fn test_put_in_queue() {
         let (sender, receiver) = init_input_channel();
         let an_answer = BeaconAnswer {
-            addr: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            addr: SocketAddr::from((Ipv4Addr::new(127, 0, 0, 1), 1901)),
             payload: Answer::default(),
+            verification: Verification::Unverified,
+            online: true,
         };
         sender.send(an_answer.clone()).unwrap();
         assert_eq!(receiver.try_recv().unwrap(), an_answer);
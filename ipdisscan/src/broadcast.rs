@@ -1,31 +1,49 @@
 use crate::conf::ScannerConfig;
+use crate::freshness::Freshness;
 use color_eyre::eyre::Report;
 use crossbeam::channel::{bounded, Receiver, Sender, TryRecvError};
+use ipdisserver::auth::AuthRequest;
+use ipdisserver::multicast::{
+    bind_v6, join_v4, join_v6, multicast_interfaces, set_multicast_sender_v4,
+};
+use ipdisserver::protocol::Request;
 use ipdisserver::Signature;
-use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
 use std::thread::sleep;
-use std::time::Duration;
-use tracing::{debug, info, trace};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info, trace, warn};
 
 const SCANNER_ADDR: Ipv4Addr = Ipv4Addr::UNSPECIFIED; // "0.0.0.0"
 
 pub fn run(
     socket: &UdpSocket,
+    socket_v6: Option<&UdpSocket>,
     new_beacon_notification_channel_recv_end: Receiver<()>,
     conf: &ScannerConfig,
+    freshness: &Freshness,
 ) -> Result<(), Report> {
     let mut empty_scans = 0; // incremented up to max_empty_scans+1
     let max_empty_scans = 10; // delay before slowing down scanning
     let slowdown_factor = 10.0; // slowing down x10 broadcasts if no new answer is received
     {
-        info!(?socket, base_frequency=1.0/conf.scan_period, ?conf.signatures, "Scanning for beacons.");
+        let destination = conf.multicast_group.unwrap_or(conf.broadcast_addr);
+        info!(?socket, %destination, base_frequency=1.0/conf.scan_period, ?conf.signatures, ?conf.legacy_signatures, "Scanning for beacons.");
+        // Random start, so that answers to a previous run cannot be replayed.
+        let mut request_id = random_request_id()?;
         loop {
-            send_single(
-                socket,
-                conf.broadcast_addr,
-                conf.target_port,
-                &conf.signatures,
-            )?;
+            request_id = request_id.wrapping_add(1);
+            freshness.request_sent(request_id);
+            let requests = requests(conf, request_id)?;
+            send_single(socket, destination, conf.target_port, &requests)?;
+            if let (Some(socket_v6), Some(group)) = (socket_v6, conf.ipv6_group) {
+                send_single_v6(
+                    socket_v6,
+                    group,
+                    &conf.ipv6_interfaces,
+                    conf.target_port,
+                    &requests,
+                );
+            }
             let scan_period = if empty_scans > max_empty_scans {
                 conf.scan_period * slowdown_factor
             } else {
@@ -55,6 +73,13 @@ pub fn run(
     }
 }
 
+fn random_request_id() -> Result<u32, Report> {
+    let mut bytes = [0; 4];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| Report::msg(format!("Cannot generate request id: {}", e)))?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
 pub fn socket_setup(scanner_port: u16) -> Result<UdpSocket, Report> {
     let socket = UdpSocket::bind(format!("{}:{}", SCANNER_ADDR, scanner_port))
         .expect("Failed to setup broadcasting socket");
@@ -62,17 +87,76 @@ pub fn socket_setup(scanner_port: u16) -> Result<UdpSocket, Report> {
     Ok(socket)
 }
 
+/// Datagrams to send at each scan: requests with a new authenticated request if a key is set,
+/// with the plain signatures otherwise, followed by the legacy requests.
+fn requests(conf: &ScannerConfig, request_id: u32) -> Result<Vec<Signature>, Report> {
+    let request = |credential| Request {
+        answer_key: conf.answer_key.as_ref().map(|k| k.public_key()),
+        query: conf.query.clone(),
+        ..Request::new(request_id, credential)
+    };
+    let mut res: Vec<Signature> = match &conf.auth_key {
+        Some(key) => vec![AuthRequest::new(SystemTime::now())
+            .map_err(|e| Report::msg(format!("Cannot generate nonce: {}", e)))?
+            .authenticate(key, &request(Signature::from("")))],
+        None => conf
+            .signatures
+            .iter()
+            .map(|signature| request(signature.clone()).encode())
+            .collect(),
+    };
+    res.extend(conf.legacy_signatures.iter().cloned());
+    Ok(res)
+}
+
+/// Multicast TTL and outgoing interface, if scanning with a multicast group.
+/// The group is joined too, to receive the announcements sent there.
+pub fn multicast_setup(socket: &UdpSocket, conf: &ScannerConfig) -> Result<(), Report> {
+    let Some(group) = conf.multicast_group else {
+        return Ok(());
+    };
+    let interface = match &conf.multicast_interface {
+        Some(name) => multicast_interfaces(std::slice::from_ref(name))?.pop(),
+        None => None,
+    };
+    set_multicast_sender_v4(socket, conf.multicast_ttl, interface.as_ref())?;
+    let interfaces = match interface {
+        Some(i) => vec![i],
+        None => multicast_interfaces(&[])?,
+    };
+    join_v4(socket, &group, &interfaces);
+    Ok(())
+}
+
+/// Join the IPv6 group, to receive the announcements sent there.
+pub fn multicast_setup_v6(socket: &UdpSocket, conf: &ScannerConfig) -> Result<(), Report> {
+    if let Some(group) = conf.ipv6_group {
+        join_v6(
+            socket,
+            &group,
+            &multicast_interfaces(&conf.ipv6_interfaces)?,
+        );
+    }
+    Ok(())
+}
+
+/// IPv6 only socket, bound on the same port used for IPv4.
+pub fn socket_setup_v6(scanner_port: u16) -> Result<UdpSocket, Report> {
+    bind_v6(scanner_port)
+}
+
 pub fn init_notification_channel() -> (Sender<()>, Receiver<()>) {
     bounded(1)
 }
 
+/// Send to a broadcast or multicast address.
 fn send_single(
     socket: &UdpSocket,
-    broadcast_addr: Ipv4Addr,
+    destination: Ipv4Addr,
     target_port: u16,
     signatures: &[Signature],
 ) -> Result<(), Report> {
-    let beacon_broadcast_addr = SocketAddr::from((broadcast_addr, target_port));
+    let beacon_broadcast_addr = SocketAddr::from((destination, target_port));
     for signature in signatures {
         socket
             .send_to(&signature.0, beacon_broadcast_addr)
@@ -87,6 +171,41 @@ fn send_single(
     Ok(())
 }
 
+/// Send to the multicast group on each interface, so that link-local answers carry the scope.
+/// Interfaces are enumerated at each scan, failures are logged and do not stop scanning.
+fn send_single_v6(
+    socket: &UdpSocket,
+    group: Ipv6Addr,
+    interface_names: &[String],
+    target_port: u16,
+    signatures: &[Signature],
+) {
+    let interfaces = match multicast_interfaces(interface_names) {
+        Ok(i) => i,
+        Err(error) => {
+            warn!(?error, "Cannot select interfaces for IPv6 scanning.");
+            return;
+        }
+    };
+    for interface in interfaces {
+        let dest = SocketAddrV6::new(group, target_port, 0, interface.index);
+        for signature in signatures {
+            match socket.send_to(&signature.0, dest) {
+                Ok(_) => trace!(
+                    ?socket,
+                    %dest,
+                    interface = %interface.name,
+                    payload = ?signature.0,
+                    "Multicasted."
+                ),
+                Err(error) => {
+                    warn!(?error, %dest, interface = %interface.name, "Failed multicasting signature.")
+                }
+            }
+        }
+    }
+}
+
 fn wait_duty_cycle(scan_period: f64) {
     sleep(Duration::from_secs_f64(scan_period));
 }
@@ -94,6 +213,9 @@ fn wait_duty_cycle(scan_period: f64) {
 #[cfg(test)]
 mod test {
     use super::*;
+    use ipdisserver::auth::{AuthKey, Authenticator, AUTH_WINDOW_DEFAULT};
+    use ipdisserver::encryption::AnswerKey;
+    use ipdisserver::query::Query;
     use std::thread;
     use std::time::Duration;
 
@@ -117,4 +239,115 @@ mod test {
         assert_eq!(buf.to_vec(), signature.0);
         sender_handle.join().unwrap();
     }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_send_multicast_loopback() {
+        let group = Ipv4Addr::new(239, 255, 19, 1);
+        let signature = Signature::from("test-signature");
+        let signatures = vec![signature.clone()];
+        let lo = multicast_interfaces(&["lo".into()]).unwrap();
+        let listener_socket = UdpSocket::bind(format!("{}:{}", "0.0.0.0", 0)).unwrap();
+        assert_eq!(join_v4(&listener_socket, &group, &lo), 1);
+        let listener_port = listener_socket.local_addr().unwrap().port();
+        let conf = ScannerConfig {
+            port: 0,
+            scan_period: 1.0,
+            broadcast_addr: Ipv4Addr::BROADCAST,
+            multicast_group: Some(group),
+            multicast_ttl: 1,
+            multicast_interface: Some("lo".into()),
+            ipv6_group: None,
+            ipv6_interfaces: Vec::new(),
+            target_port: listener_port,
+            signatures: signatures.clone(),
+            legacy_signatures: Vec::new(),
+            auth_key: None,
+            query: Query::default(),
+            answer_key: None,
+            passive: false,
+            log_file: None,
+        };
+        let sender_socket = socket_setup(0).unwrap();
+        multicast_setup(&sender_socket, &conf).unwrap();
+        send_single(&sender_socket, group, listener_port, &signatures).unwrap();
+        let mut buf = [0; 14];
+        let (length, _source) = listener_socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..length], &signature.0[..]);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_requests() {
+        let key = AuthKey::from("secret");
+        let mut conf = ScannerConfig {
+            port: 0,
+            scan_period: 1.0,
+            broadcast_addr: Ipv4Addr::BROADCAST,
+            multicast_group: None,
+            multicast_ttl: 1,
+            multicast_interface: None,
+            ipv6_group: None,
+            ipv6_interfaces: Vec::new(),
+            target_port: 1901,
+            signatures: vec![Signature::from("test-signature")],
+            legacy_signatures: vec![Signature::from("legacy-signature")],
+            auth_key: None,
+            query: Query::default(),
+            answer_key: None,
+            passive: false,
+            log_file: None,
+        };
+        let decode = |datagram: &Signature| Request::decode(&datagram.0).unwrap();
+        let plain = requests(&conf, 1).unwrap();
+        assert_eq!(plain.len(), 2);
+        assert_eq!(
+            decode(&plain[0]),
+            Request::new(1, Signature::from("test-signature"))
+        );
+        assert!(decode(&plain[1]).is_legacy());
+        conf.legacy_signatures.clear();
+        conf.auth_key = Some(key.clone());
+        let authenticated = requests(&conf, 2).unwrap();
+        assert_eq!(authenticated.len(), 1);
+        let mut authenticator = Authenticator::new(vec![key.clone()], AUTH_WINDOW_DEFAULT, false);
+        assert_eq!(
+            authenticator.verify(&authenticated[0].0, SystemTime::now()),
+            Ok(&key)
+        );
+        assert_ne!(requests(&conf, 2).unwrap(), authenticated); // new nonce at each scan
+        let answer_key = AnswerKey::generate().unwrap();
+        conf.answer_key = Some(answer_key.clone());
+        conf.query = Query::parse("hostname").unwrap();
+        let datagram = requests(&conf, 3).unwrap().remove(0);
+        let request = decode(&datagram);
+        assert_eq!(request.request_id, 3);
+        assert_eq!(request.answer_key, Some(answer_key.public_key()));
+        assert_eq!(request.query, conf.query);
+        assert_eq!(
+            authenticator.verify(&datagram.0, SystemTime::now()),
+            Ok(&key)
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_send_v6_loopback() {
+        let signature = Signature::from("test-signature");
+        let signatures = vec![signature.clone()];
+        let listener_socket = socket_setup_v6(0).unwrap();
+        let listener_port = listener_socket.local_addr().unwrap().port();
+        let sender_socket = socket_setup_v6(0).unwrap();
+        // Unicast to loopback: the interface scope is ignored.
+        send_single_v6(
+            &sender_socket,
+            Ipv6Addr::LOCALHOST,
+            &["lo".into()],
+            listener_port,
+            &signatures,
+        );
+        let mut buf = [0; 14];
+        let (length, _source) = listener_socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..length], &signature.0[..]);
+    }
 }
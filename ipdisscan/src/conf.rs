@@ -1,17 +1,42 @@
+use ipdisserver::auth::AuthKey;
+use ipdisserver::encryption::AnswerKey;
+use ipdisserver::query::Query;
 use ipdisserver::Signature;
-use std::{net::Ipv4Addr, path::PathBuf};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
 
 pub const SCANNER_PORT_DEFAULT: u16 = 1902;
 pub const BROADCAST_ADDR_DEFAULT: Ipv4Addr = Ipv4Addr::BROADCAST; // 255.255.255.255
 pub const EXTRA_SIGNATURE_DEFAULT: &str = "pang-supremacy-maritime-revoke-afterglow"; // compatibility with original ipdiscan
 pub const SCAN_PERIOD_DEFAULT: f64 = 1.0;
+pub const MULTICAST_TTL_DEFAULT: u32 = 1; // do not cross routers
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct ScannerConfig {
     pub port: u16,
     pub scan_period: f64,
     pub broadcast_addr: Ipv4Addr,
+    /// IPv4 multicast group where requests are sent instead of `broadcast_addr`.
+    pub multicast_group: Option<Ipv4Addr>,
+    pub multicast_ttl: u32,
+    /// Outgoing interface of multicast requests, chosen by the routing table if None.
+    pub multicast_interface: Option<String>,
+    /// IPv6 multicast group where requests are sent too, None to scan IPv4 only.
+    pub ipv6_group: Option<Ipv6Addr>,
+    /// Interfaces where IPv6 requests are sent, all the multicast capable ones if empty.
+    pub ipv6_interfaces: Vec<String>,
     pub target_port: u16,
     pub signatures: Vec<Signature>,
+    /// Sent bare, in the legacy format, for older ipdisserver versions and the original
+    /// ipdiscan beacons.
+    pub legacy_signatures: Vec<Signature>,
+    /// Send authenticated requests with this key instead of the plain signatures.
+    pub auth_key: Option<AuthKey>,
+    /// Keys requested to the beacons, the whole answer if empty.
+    pub query: Query,
+    /// Ask for answers encrypted to this key.
+    pub answer_key: Option<AnswerKey>,
+    /// Send no request, only listen for announcements.
+    pub passive: bool,
     pub log_file: Option<PathBuf>,
 }
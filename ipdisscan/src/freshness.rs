@@ -0,0 +1,114 @@
+use ipdisserver::identity::DevicePublicKey;
+use ipdisserver::protocol::MessageType;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Request ids answers are accepted for, about as many seconds at the default scan period.
+const SENT_REQUESTS_KEPT: usize = 8;
+
+/// Whether a correctly signed message was signed for this scanner, now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Age {
+    Fresh,
+    /// Same timestamp as the last message of the device, received twice.
+    Duplicate,
+    /// Answer to a request not sent recently, or announcement or goodbye older than the last
+    /// message of the device.
+    Replayed,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    sent: VecDeque<u32>,
+    /// Timestamp of the last message of each device key.
+    latest: HashMap<DevicePublicKey, u64>,
+}
+
+/// Replay protection of signed messages, shared by the scanning and the listening threads.
+/// Answers must answer a recent request. Announcements and goodbyes cannot: they must be newer
+/// than the last message of the device, the first one of a device is trusted.
+#[derive(Debug, Clone, Default)]
+pub struct Freshness(Arc<Mutex<State>>);
+
+impl Freshness {
+    pub fn request_sent(&self, request_id: u32) {
+        let mut state = self.0.lock().expect("freshness lock poisoned");
+        if state.sent.len() >= SENT_REQUESTS_KEPT {
+            state.sent.pop_front();
+        }
+        state.sent.push_back(request_id);
+    }
+
+    /// Age of a message signed by `key` at `timestamp`, in milliseconds since the Unix epoch.
+    pub fn check(
+        &self,
+        message_type: MessageType,
+        request_id: u32,
+        key: DevicePublicKey,
+        timestamp: u64,
+    ) -> Age {
+        let mut state = self.0.lock().expect("freshness lock poisoned");
+        match message_type {
+            MessageType::Answer => {
+                if !state.sent.contains(&request_id) {
+                    return Age::Replayed;
+                }
+                // Reset even if older, the device clock may have been set back.
+                state.latest.insert(key, timestamp);
+                Age::Fresh
+            }
+            MessageType::Announcement | MessageType::Goodbye => match state.latest.get(&key) {
+                Some(latest) if timestamp == *latest => Age::Duplicate,
+                Some(latest) if timestamp < *latest => Age::Replayed,
+                _ => {
+                    state.latest.insert(key, timestamp);
+                    Age::Fresh
+                }
+            },
+            MessageType::Request => Age::Replayed, // never sent by servers
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ipdisserver::identity::DeviceKey;
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_check() {
+        let freshness = Freshness::default();
+        let key = DeviceKey::generate().unwrap().public_key();
+        let other_key = DeviceKey::generate().unwrap().public_key();
+        assert_eq!(
+            freshness.check(MessageType::Answer, 1, key, 100),
+            Age::Replayed
+        );
+        for request_id in 1..=SENT_REQUESTS_KEPT as u32 + 1 {
+            freshness.request_sent(request_id);
+        }
+        assert_eq!(
+            freshness.check(MessageType::Answer, 1, key, 100),
+            Age::Replayed
+        );
+        assert_eq!(
+            freshness.check(MessageType::Answer, 2, key, 100),
+            Age::Fresh
+        );
+        let announcement = MessageType::Announcement;
+        assert_eq!(freshness.check(announcement, 0, key, 99), Age::Replayed);
+        assert_eq!(freshness.check(announcement, 0, key, 100), Age::Duplicate);
+        assert_eq!(freshness.check(announcement, 0, key, 101), Age::Fresh);
+        assert_eq!(freshness.check(announcement, 0, other_key, 1), Age::Fresh);
+        // An answer resets the baseline, the clock was set back.
+        assert_eq!(freshness.check(MessageType::Answer, 3, key, 50), Age::Fresh);
+        assert_eq!(freshness.check(announcement, 0, key, 51), Age::Fresh);
+        // A captured goodbye cannot mark the device offline again once it is back.
+        let goodbye = MessageType::Goodbye;
+        assert_eq!(freshness.check(goodbye, 0, key, 52), Age::Fresh);
+        assert_eq!(freshness.check(goodbye, 0, key, 52), Age::Duplicate);
+        assert_eq!(freshness.check(announcement, 0, key, 53), Age::Fresh);
+        assert_eq!(freshness.check(goodbye, 0, key, 52), Age::Replayed);
+    }
+}
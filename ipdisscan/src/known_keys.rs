@@ -0,0 +1,221 @@
+use color_eyre::eyre::{Report, WrapErr};
+use ipdisserver::identity::DevicePublicKey;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::{error, info, warn};
+
+/// How much an answer can be trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    /// Signed with the key known for the beacon, or with a key seen for the first time.
+    Verified(DevicePublicKey),
+    /// Not signed.
+    Unverified,
+    /// Not signed, answer to a legacy request without protocol header.
+    Legacy,
+    /// Signature not matching the answer.
+    Invalid,
+    /// Correctly signed, but with a key different from the one known for the beacon.
+    KeyChanged {
+        known: DevicePublicKey,
+        received: DevicePublicKey,
+    },
+    /// Not signed, while the beacon signed its previous answers: the signature may have been
+    /// stripped by a forger.
+    SignatureMissing { known: DevicePublicKey },
+    /// Correctly signed, but for a request not sent recently, or before the last announcement.
+    Replayed(DevicePublicKey),
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Verified(key) => write!(f, "verified, key {}", key),
+            Self::Unverified => write!(f, "unverified, answer not signed"),
+            Self::Legacy => write!(f, "unverified, legacy answer"),
+            Self::Invalid => write!(f, "INVALID SIGNATURE"),
+            Self::KeyChanged { known, received } => {
+                write!(f, "KEY CHANGED, known key {}, received {}", known, received)
+            }
+            Self::SignatureMissing { known } => {
+                write!(f, "SIGNATURE MISSING, known key {}", known)
+            }
+            Self::Replayed(key) => write!(f, "REPLAYED, old message signed by {}", key),
+        }
+    }
+}
+
+impl Verification {
+    /// True if the answer may be forged.
+    pub fn is_suspicious(&self) -> bool {
+        matches!(
+            self,
+            Self::Invalid
+                | Self::KeyChanged { .. }
+                | Self::SignatureMissing { .. }
+                | Self::Replayed(_)
+        )
+    }
+}
+
+/// Trust-on-first-use store of the beacons public keys, keyed by host.
+/// Saved as `host key` lines.
+#[derive(Debug, Clone, Default)]
+pub struct KnownKeys {
+    path: Option<PathBuf>,
+    keys: BTreeMap<String, DevicePublicKey>,
+}
+
+impl KnownKeys {
+    /// Read the store from `path`, a missing file is an empty store.
+    /// Keys are kept in memory only if `path` is None.
+    pub fn load(path: Option<PathBuf>) -> Result<Self, Report> {
+        let mut keys = BTreeMap::new();
+        if let Some(p) = &path {
+            match fs::read_to_string(p) {
+                Ok(content) => {
+                    for line in content.lines().map(str::trim) {
+                        if line.is_empty() || line.starts_with('#') {
+                            continue;
+                        }
+                        let (host, key) = line.split_once(' ').ok_or_else(|| {
+                            Report::msg(format!("Invalid line in {:?}: {:?}", p, line))
+                        })?;
+                        keys.insert(host.to_string(), key.trim().parse()?);
+                    }
+                }
+                Err(error) if error.kind() == io::ErrorKind::NotFound => (),
+                Err(error) => {
+                    return Err(error).wrap_err_with(|| format!("Cannot read {:?}", p));
+                }
+            }
+        }
+        Ok(Self { path, keys })
+    }
+
+    /// Compare the key to the known one for `host`, store it if `host` is new.
+    pub fn check(&mut self, host: &str, key: DevicePublicKey) -> Verification {
+        match self.keys.get(host) {
+            Some(known) if *known == key => Verification::Verified(key),
+            Some(known) => {
+                error!(%host, %known, received = %key, "BEACON KEY CHANGED! The answer may be forged. Remove the host from the known keys file if the change is expected.");
+                Verification::KeyChanged {
+                    known: *known,
+                    received: key,
+                }
+            }
+            None => {
+                info!(%host, %key, "New beacon key stored.");
+                self.keys.insert(host.to_string(), key);
+                if let Err(error) = self.save() {
+                    warn!(?error, ?self.path, "Cannot save known keys.");
+                }
+                Verification::Verified(key)
+            }
+        }
+    }
+
+    /// True if a key is known for `host`.
+    pub fn contains(&self, host: &str) -> bool {
+        self.keys.contains_key(host)
+    }
+
+    /// Verification of an unsigned answer from `host`: suspicious if a key is known for it.
+    pub fn check_unsigned(&self, host: &str) -> Verification {
+        match self.keys.get(host) {
+            Some(known) => {
+                error!(%host, %known, "BEACON SIGNATURE MISSING! The beacon has a known key but the answer is not signed, it may be forged. Remove the host from the known keys file if the beacon no longer signs its answers.");
+                Verification::SignatureMissing { known: *known }
+            }
+            None => Verification::Unverified,
+        }
+    }
+
+    /// Check the verification of an answer from `host` against the known keys.
+    pub fn verify(&mut self, host: &str, verification: Verification) -> Verification {
+        match verification {
+            Verification::Verified(key) => self.check(host, key),
+            Verification::Unverified => self.check_unsigned(host),
+            other => other,
+        }
+    }
+
+    fn save(&self) -> Result<(), Report> {
+        let path = match &self.path {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let content: String = self
+            .keys
+            .iter()
+            .map(|(host, key)| format!("{} {}\n", host, key))
+            .collect();
+        fs::write(path, content)?;
+        Ok(())
+    }
+}
+
+/// `$XDG_DATA_HOME/ipdisscan/known_keys`, or `~/.local/share/ipdisscan/known_keys`.
+pub fn default_known_keys_path() -> Option<PathBuf> {
+    let data_home = match env::var_os("XDG_DATA_HOME") {
+        Some(d) if !d.is_empty() => PathBuf::from(d),
+        _ => Path::new(&env::var_os("HOME")?).join(".local/share"),
+    };
+    Some(data_home.join("ipdisscan").join("known_keys"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ipdisserver::identity::DeviceKey;
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_trust_on_first_use() {
+        let path = std::env::temp_dir()
+            .as_path()
+            .join("rust-ipdisscan-test-known-keys/known_keys");
+        let _ = fs::remove_file(&path);
+        let key = DeviceKey::generate().unwrap().public_key();
+        let other_key = DeviceKey::generate().unwrap().public_key();
+        let mut known_keys = KnownKeys::load(Some(path.clone())).unwrap();
+        assert_eq!(
+            known_keys.check("192.168.0.1", key),
+            Verification::Verified(key)
+        );
+        assert_eq!(
+            known_keys.check("192.168.0.1", key),
+            Verification::Verified(key)
+        );
+        let mut known_keys = KnownKeys::load(Some(path)).unwrap(); // saved on first use
+        let changed = known_keys.check("192.168.0.1", other_key);
+        assert_eq!(
+            changed,
+            Verification::KeyChanged {
+                known: key,
+                received: other_key
+            }
+        );
+        assert!(changed.is_suspicious());
+        assert!(logs_contain("BEACON KEY CHANGED!"));
+        assert_eq!(
+            known_keys.check("192.168.0.2", other_key),
+            Verification::Verified(other_key)
+        );
+        let missing = known_keys.verify("192.168.0.1", Verification::Unverified);
+        assert_eq!(missing, Verification::SignatureMissing { known: key });
+        assert!(missing.is_suspicious());
+        assert!(logs_contain("BEACON SIGNATURE MISSING!"));
+        assert_eq!(
+            known_keys.verify("192.168.0.3", Verification::Unverified),
+            Verification::Unverified
+        );
+    }
+}
@@ -1,5 +1,7 @@
 pub mod beacons;
 pub mod broadcast;
 pub mod conf;
+pub mod freshness;
+pub mod known_keys;
 pub mod listen;
 pub mod ui;
@@ -1,45 +1,268 @@
 use crate::beacons::BeaconAnswer;
+use crate::freshness::{Age, Freshness};
+use crate::known_keys::Verification;
 use color_eyre::eyre::Report;
 use crossbeam::channel::Sender;
+use ipdisserver::chunks::{Chunk, DATAGRAM_MAX_LENGTH};
+use ipdisserver::encryption::AnswerKey;
+use ipdisserver::identity::{open_signed_answer, AnswerSigner, SignatureContext};
+use ipdisserver::protocol::{Flags, MessageType, ServerMessage, LEGACY_VERSION};
 use ipdisserver::Answer;
-use std::net::UdpSocket;
-use tracing::{debug, info, trace};
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, trace, warn};
 
-const RECV_BUFFER_LENGHT: usize = 2usize.pow(10); // 1KiB
+const RECV_BUFFER_LENGHT: usize = 2usize.pow(16); // 64KiB, larger than any UDP datagram
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5); // incomplete answers are dropped after it
+const MAX_PENDING_ANSWERS: usize = 256; // incomplete answers kept at the same time
+const MAX_PENDING_PER_SOURCE: usize = 8; // incomplete answers of a single IP address
+const MAX_CHUNKS: u16 = 1024; // ~1.2MiB answers, chunks are at most DATAGRAM_MAX_LENGTH long
+const MAX_BUFFERED: usize = 8 * 1024 * 1024; // bytes of all the incomplete answers
 
-pub fn run(socket: &UdpSocket, input_channel_send_end: Sender<BeaconAnswer>) -> Result<(), Report> {
-    info!(?socket, "Listening for beacon answers.");
+pub fn run(
+    socket: &UdpSocket,
+    answer_key: Option<&AnswerKey>,
+    freshness: &Freshness,
+    input_channel_send_end: Sender<BeaconAnswer>,
+) -> Result<(), Report> {
+    info!(
+        ?socket,
+        encrypted = answer_key.is_some(),
+        "Listening for beacon answers."
+    );
+    let mut reassembler = Reassembler::default();
     loop {
-        serve_single(socket, input_channel_send_end.clone())?;
+        serve_single(
+            socket,
+            &mut reassembler,
+            answer_key,
+            freshness,
+            input_channel_send_end.clone(),
+        )?;
     }
 }
 
 fn serve_single(
     socket: &UdpSocket,
+    reassembler: &mut Reassembler,
+    answer_key: Option<&AnswerKey>,
+    freshness: &Freshness,
     input_channel_send_end: Sender<BeaconAnswer>,
 ) -> Result<(), Report> {
-    let beacon_answer = receive(socket)?;
+    let (source, datagram) = receive(socket)?;
+    let answer = match reassembler.push(source, &datagram, Instant::now()) {
+        Some(a) => a,
+        None => return Ok(()),
+    };
+    let message = match ServerMessage::decode(&answer) {
+        Ok(m) => m,
+        Err(error) => {
+            warn!(%source, %error, "Invalid answer, ignored.");
+            return Ok(());
+        }
+    };
+    // Announcements are answers the server sent unsolicited, handled the same way.
+    trace!(%source, version = message.version, message_type = ?message.message_type, request_id = message.request_id, flags = %message.flags, "Answer received.");
+    let signed = match decrypt(&message, answer_key) {
+        Some(a) => a,
+        None => {
+            warn!(%source, "Cannot decrypt answer, ignored.");
+            return Ok(());
+        }
+    };
+    let (payload, verification) = match verify(&signed, &message, freshness) {
+        Some(v) => v,
+        None => {
+            debug!(%source, "Duplicated message, ignored.");
+            return Ok(());
+        }
+    };
+    match verification {
+        Verification::Invalid => warn!(%source, "Answer with invalid signature."),
+        Verification::Replayed(key) => {
+            error!(%source, %key, request_id = message.request_id, "REPLAYED ANSWER! Correctly signed, but not for a recent request or older than the last announcement. The answer may be forged.")
+        }
+        _ => (),
+    }
+    let beacon_answer = BeaconAnswer {
+        addr: source,
+        payload,
+        verification,
+        online: message.message_type != MessageType::Goodbye,
+    };
     trace!(?beacon_answer.addr, %beacon_answer.payload, "Putting in queue.");
     input_channel_send_end.send(beacon_answer)?;
     Ok(())
 }
 
-fn receive(socket: &UdpSocket) -> Result<BeaconAnswer, Report> {
-    let mut buf = [0; RECV_BUFFER_LENGHT];
+/// Plain text of the payload, None if it cannot be decrypted.
+fn decrypt(message: &ServerMessage, answer_key: Option<&AnswerKey>) -> Option<Answer> {
+    if !message.flags.contains(Flags::ENCRYPTED) {
+        if answer_key.is_some() {
+            debug!("Answer not encrypted.");
+        }
+        return Some(message.payload.clone());
+    }
+    match answer_key?.decrypt(&message.payload) {
+        Ok(Some(plain)) => Some(plain),
+        Ok(None) => {
+            debug!("Encrypted flag set on a plain answer.");
+            None
+        }
+        Err(error) => {
+            debug!(%error, "Decryption failed.");
+            None
+        }
+    }
+}
+
+/// Answer without the signature, and the signature verification if the answer is signed.
+/// Signatures must be fresh, see `Freshness`. None for a duplicated message.
+/// The key is checked against the known ones by the beacons thread.
+fn verify(
+    signed: &Answer,
+    message: &ServerMessage,
+    freshness: &Freshness,
+) -> Option<(Answer, Verification)> {
+    if message.version == LEGACY_VERSION {
+        return Some((signed.clone(), Verification::Legacy));
+    }
+    if !message.flags.contains(Flags::SIGNED) {
+        return Some((signed.clone(), Verification::Unverified));
+    }
+    let context = SignatureContext {
+        message_type: message.message_type,
+        request_id: message.request_id,
+    };
+    match open_signed_answer(signed, &context) {
+        (payload, AnswerSigner::Valid { key, timestamp }) => {
+            match freshness.check(message.message_type, message.request_id, key, timestamp) {
+                Age::Fresh => Some((payload, Verification::Verified(key))),
+                Age::Duplicate => None,
+                Age::Replayed => Some((payload, Verification::Replayed(key))),
+            }
+        }
+        (payload, AnswerSigner::Unsigned | AnswerSigner::Invalid) => {
+            Some((payload, Verification::Invalid))
+        }
+    }
+}
+
+fn receive(socket: &UdpSocket) -> Result<(SocketAddr, Vec<u8>), Report> {
+    let mut buf = vec![0; RECV_BUFFER_LENGHT];
     trace!(?socket, "Listening.");
     let (length, source) = socket.recv_from(&mut buf)?;
-    let payload: Answer = (&buf[..length]).into();
+    buf.truncate(length);
     debug!(%length, %source, "Datagram received.");
-    Ok(BeaconAnswer {
-        addr: source.ip(),
-        payload,
-    })
+    Ok((source, buf))
+}
+
+/// Chunks received so far of an answer.
+#[derive(Debug)]
+struct PendingAnswer {
+    chunks: Vec<Option<Chunk>>,
+    missing: usize,
+    /// Bytes of the chunks received.
+    length: usize,
+    started: Instant,
+}
+
+/// Rebuild answers split in chunks by the server. Memory is bounded: chunks longer than the
+/// server sends them are rejected, as well as chunks beyond the limits of incomplete answers
+/// per source, in total, and of buffered bytes.
+#[derive(Debug, Default)]
+struct Reassembler {
+    pending: HashMap<(SocketAddr, u32), PendingAnswer>,
+    /// Bytes of all the pending answers.
+    buffered: usize,
+}
+
+impl Reassembler {
+    /// Return the answer once all its chunks are received.
+    /// Datagrams that are not chunks are whole answers, returned as they are.
+    fn push(&mut self, source: SocketAddr, datagram: &[u8], now: Instant) -> Option<Answer> {
+        let chunk = match Chunk::decode(datagram) {
+            Some(c) => c,
+            None => return Some(Answer::from(datagram)),
+        };
+        self.drop_expired(now);
+        if datagram.len() > DATAGRAM_MAX_LENGTH {
+            warn!(%source, length = datagram.len(), "Chunk too long, ignored.");
+            return None;
+        }
+        if chunk.count > MAX_CHUNKS {
+            warn!(%source, count = chunk.count, "Too many chunks, answer ignored.");
+            return None;
+        }
+        let key = (source, chunk.message_id);
+        if !self.pending.contains_key(&key) {
+            let from_source = self
+                .pending
+                .keys()
+                .filter(|(s, _)| s.ip() == source.ip())
+                .count();
+            if self.pending.len() >= MAX_PENDING_ANSWERS || from_source >= MAX_PENDING_PER_SOURCE {
+                warn!(%source, "Too many incomplete answers, chunk ignored.");
+                return None;
+            }
+        }
+        if self.buffered + chunk.data.len() > MAX_BUFFERED {
+            warn!(%source, buffered = self.buffered, "Too many bytes buffered, chunk ignored.");
+            return None;
+        }
+        let pending = self.pending.entry(key).or_insert_with(|| PendingAnswer {
+            chunks: vec![None; chunk.count as usize],
+            missing: chunk.count as usize,
+            length: 0,
+            started: now,
+        });
+        if pending.chunks.len() != chunk.count as usize {
+            warn!(%source, message_id = chunk.message_id, "Inconsistent chunk count, chunk ignored.");
+            return None;
+        }
+        let index = chunk.index as usize;
+        match &pending.chunks[index] {
+            None => pending.missing -= 1,
+            Some(previous) => {
+                pending.length -= previous.data.len();
+                self.buffered -= previous.data.len();
+            }
+        }
+        trace!(%source, message_id = chunk.message_id, index, missing = pending.missing, "Chunk received.");
+        pending.length += chunk.data.len();
+        self.buffered += chunk.data.len();
+        pending.chunks[index] = Some(chunk);
+        if pending.missing > 0 {
+            return None;
+        }
+        let complete = self.pending.remove(&key)?;
+        self.buffered -= complete.length;
+        Some(Answer::from_chunks(
+            complete.chunks.iter().flatten().map(|c| &c.data),
+        ))
+    }
+
+    fn drop_expired(&mut self, now: Instant) {
+        let buffered = &mut self.buffered;
+        self.pending.retain(|(source, message_id), pending| {
+            let expired = now.duration_since(pending.started) >= REASSEMBLY_TIMEOUT;
+            if expired {
+                debug!(%source, message_id, missing = pending.missing, "Incomplete answer dropped.");
+                *buffered -= pending.length;
+            }
+            !expired
+        });
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::net::SocketAddr;
+    use ipdisserver::chunks::CHUNK_HEADER_LENGTH;
+    use ipdisserver::identity::DeviceKey;
+    use ipdisserver::protocol::Request;
+    use std::net::Ipv4Addr;
     use std::thread;
     use std::time::Duration;
 
@@ -59,8 +282,248 @@ mod test {
             println!("[{}] -> {}", listener_addr, payload);
         });
 
-        let answer = receive(&listener_socket).unwrap();
-        assert_eq!(answer.payload.0, expected.0);
+        let (_source, datagram) = receive(&listener_socket).unwrap();
+        assert_eq!(datagram, expected.0);
         sender_handle.join().unwrap();
     }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_serve_announcement() {
+        let device_key = DeviceKey::generate().unwrap();
+        let answer = Answer::from(r#"{"hostname":"dummy"}"#.to_string());
+        let context = SignatureContext {
+            message_type: MessageType::Announcement,
+            request_id: 0,
+        };
+        let announcement =
+            ServerMessage::announcement(Flags::SIGNED, device_key.sign_answer(&answer, &context));
+        let listener_socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let sending_socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        sending_socket
+            .send_to(
+                &announcement.encode().0,
+                listener_socket.local_addr().unwrap(),
+            )
+            .unwrap();
+        let (send_end, receive_end) = crossbeam::channel::unbounded();
+        let freshness = Freshness::default();
+        let mut reassembler = Reassembler::default();
+        serve_single(
+            &listener_socket,
+            &mut reassembler,
+            None,
+            &freshness,
+            send_end.clone(),
+        )
+        .unwrap();
+        let beacon_answer = receive_end.try_recv().unwrap();
+        assert_eq!(beacon_answer.addr, sending_socket.local_addr().unwrap());
+        assert_eq!(beacon_answer.payload, answer);
+        assert_eq!(
+            beacon_answer.verification,
+            Verification::Verified(device_key.public_key())
+        );
+        // Received again, e.g. through another interface.
+        sending_socket
+            .send_to(
+                &announcement.encode().0,
+                listener_socket.local_addr().unwrap(),
+            )
+            .unwrap();
+        serve_single(
+            &listener_socket,
+            &mut reassembler,
+            None,
+            &freshness,
+            send_end,
+        )
+        .unwrap();
+        assert!(receive_end.try_recv().is_err());
+        assert!(logs_contain("Duplicated message, ignored."));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_reassembly() {
+        let answer = Answer::from(format!(r#"{{"key":"{}"}}"#, "x".repeat(100)));
+        let other_answer = Answer::from(format!(r#"{{"other":"{}"}}"#, "y".repeat(100)));
+        let source = SocketAddr::from(([192, 168, 0, 1], 1901));
+        let other_source = SocketAddr::from(([192, 168, 0, 2], 1901));
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        let mut datagrams = answer.to_datagrams(1, 32).unwrap();
+        datagrams.reverse();
+        let last = datagrams.pop().unwrap();
+        // Same message id from another server, interleaved.
+        let other_datagrams = other_answer.to_datagrams(1, 32).unwrap();
+        for (datagram, other) in datagrams.iter().zip(other_datagrams.iter()) {
+            assert_eq!(reassembler.push(source, datagram, now), None);
+            assert_eq!(reassembler.push(source, datagram, now), None); // duplicated
+            assert_eq!(reassembler.push(other_source, other, now), None);
+        }
+        assert_eq!(reassembler.push(source, &last, now), Some(answer.clone()));
+        assert_eq!(
+            reassembler.push(other_source, other_datagrams.last().unwrap(), now),
+            Some(other_answer)
+        );
+        assert!(reassembler.pending.is_empty());
+        // Unchunked answers are passed through.
+        let short = Answer::from(r#"{"key":"value"}"#.to_string());
+        assert_eq!(reassembler.push(source, &short.0, now), Some(short));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_reassembly_timeout() {
+        let answer = Answer::from(format!(r#"{{"key":"{}"}}"#, "x".repeat(100)));
+        let source = SocketAddr::from(([192, 168, 0, 1], 1901));
+        let mut reassembler = Reassembler::default();
+        let start = Instant::now();
+        let datagrams = answer.to_datagrams(1, 32).unwrap();
+        let (last, firsts) = datagrams.split_last().unwrap();
+        for datagram in firsts {
+            assert_eq!(reassembler.push(source, datagram, start), None);
+        }
+        assert_eq!(
+            reassembler.push(source, last, start + REASSEMBLY_TIMEOUT),
+            None
+        );
+        assert!(logs_contain("Incomplete answer dropped."));
+        // Only the last chunk is buffered, as the start of a new answer.
+        assert_eq!(reassembler.buffered, last.len() - CHUNK_HEADER_LENGTH);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_reassembly_limits() {
+        let chunk = |message_id, index, count, length| {
+            Chunk {
+                message_id,
+                index,
+                count,
+                data: vec![b'x'; length].into(),
+            }
+            .encode()
+        };
+        let data_max = DATAGRAM_MAX_LENGTH - CHUNK_HEADER_LENGTH;
+        let source = SocketAddr::from(([192, 168, 0, 1], 1901));
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        assert_eq!(
+            reassembler.push(source, &chunk(1, 0, 2, data_max + 1), now),
+            None
+        );
+        assert!(logs_contain("Chunk too long"));
+        assert!(reassembler.pending.is_empty());
+        for message_id in 0..MAX_PENDING_PER_SOURCE as u32 + 1 {
+            reassembler.push(source, &chunk(message_id, 0, 2, data_max), now);
+        }
+        assert_eq!(reassembler.pending.len(), MAX_PENDING_PER_SOURCE);
+        assert!(logs_contain("Too many incomplete answers"));
+        // Another port of the same address shares the limit.
+        let same_host = SocketAddr::from(([192, 168, 0, 1], 1902));
+        assert_eq!(reassembler.push(same_host, &chunk(0, 0, 2, 1), now), None);
+        assert_eq!(reassembler.pending.len(), MAX_PENDING_PER_SOURCE);
+        assert_eq!(reassembler.buffered, MAX_PENDING_PER_SOURCE * data_max);
+        // Spoofed sources filling the buffer with the largest answers.
+        let mut reassembler = Reassembler::default();
+        for i in 0..MAX_BUFFERED / (data_max * MAX_CHUNKS as usize) + 1 {
+            let source = SocketAddr::from(([10, 0, 0, i as u8], 1901));
+            for index in 0..MAX_CHUNKS - 1 {
+                reassembler.push(source, &chunk(0, index, MAX_CHUNKS, data_max), now);
+            }
+        }
+        assert!(logs_contain("Too many bytes buffered"));
+        assert!(reassembler.buffered <= MAX_BUFFERED);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_decrypt() {
+        let key = AnswerKey::generate().unwrap();
+        let answer = Answer::from(r#"{"key":"value"}"#.to_string());
+        let request = Request::new(1, "ipdisbeacon".into());
+        let plain = ServerMessage::answer(&request, Flags::NONE, answer.clone());
+        let encrypted = ServerMessage::answer(
+            &request,
+            Flags::ENCRYPTED,
+            ipdisserver::encryption::encrypt_answer(&answer, &key.public_key()).unwrap(),
+        );
+        assert_eq!(decrypt(&encrypted, Some(&key)), Some(answer.clone()));
+        assert_eq!(decrypt(&plain, Some(&key)), Some(answer.clone()));
+        assert_eq!(decrypt(&plain, None), Some(answer.clone()));
+        assert_eq!(decrypt(&encrypted, None), None);
+        let other_key = AnswerKey::generate().unwrap();
+        assert_eq!(decrypt(&encrypted, Some(&other_key)), None);
+        let flagged = ServerMessage::answer(&request, Flags::ENCRYPTED, answer);
+        assert_eq!(decrypt(&flagged, Some(&key)), None);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_verify() {
+        let device_key = DeviceKey::generate().unwrap();
+        let public_key = device_key.public_key();
+        let answer = Answer::from(r#"{"key":"value"}"#.to_string());
+        let freshness = Freshness::default();
+        freshness.request_sent(7);
+        let message = |request_id, flags, payload| {
+            ServerMessage::answer(
+                &Request::new(request_id, "ipdisbeacon".into()),
+                flags,
+                payload,
+            )
+        };
+        let sign = |request_id| {
+            let context = SignatureContext {
+                message_type: MessageType::Answer,
+                request_id,
+            };
+            device_key.sign_answer(&answer, &context)
+        };
+        let signed = sign(7);
+        assert_eq!(
+            verify(
+                &signed,
+                &message(7, Flags::SIGNED, signed.clone()),
+                &freshness
+            ),
+            Some((answer.clone(), Verification::Verified(public_key)))
+        );
+        assert_eq!(
+            verify(
+                &answer,
+                &message(7, Flags::NONE, answer.clone()),
+                &freshness
+            ),
+            Some((answer.clone(), Verification::Unverified))
+        );
+        let bare = ServerMessage::decode(&answer).unwrap();
+        assert_eq!(
+            verify(&answer, &bare, &freshness),
+            Some((answer.clone(), Verification::Legacy))
+        );
+        // Signed flag without signature: stripped by a forger.
+        let stripped = message(7, Flags::SIGNED, answer.clone());
+        assert_eq!(
+            verify(&answer, &stripped, &freshness).unwrap().1,
+            Verification::Invalid
+        );
+        // Captured answer sent again with the request id of a later request.
+        let relabeled = message(8, Flags::SIGNED, signed.clone());
+        freshness.request_sent(8);
+        assert_eq!(
+            verify(&signed, &relabeled, &freshness).unwrap().1,
+            Verification::Invalid
+        );
+        // Captured answer to a request sent long ago.
+        let old = sign(1);
+        assert_eq!(
+            verify(&old, &message(1, Flags::SIGNED, old.clone()), &freshness)
+                .unwrap()
+                .1,
+            Verification::Replayed(public_key)
+        );
+    }
 }
@@ -3,16 +3,21 @@ mod setup;
 use clap::Parser;
 use color_eyre::eyre::Report;
 use ipdisscan::conf::{
-    ScannerConfig, BROADCAST_ADDR_DEFAULT, EXTRA_SIGNATURE_DEFAULT, SCANNER_PORT_DEFAULT,
-    SCAN_PERIOD_DEFAULT,
+    ScannerConfig, BROADCAST_ADDR_DEFAULT, EXTRA_SIGNATURE_DEFAULT, MULTICAST_TTL_DEFAULT,
+    SCANNER_PORT_DEFAULT, SCAN_PERIOD_DEFAULT,
 };
+use ipdisscan::freshness::Freshness;
+use ipdisscan::known_keys::{default_known_keys_path, KnownKeys};
 use ipdisscan::{
     beacons,
-    broadcast::{self, socket_setup},
+    broadcast::{self, multicast_setup, multicast_setup_v6, socket_setup, socket_setup_v6},
     listen, ui,
 };
+use ipdisserver::conf::{ServerConfig, MULTICAST_V6_ADDR_DEFAULT};
+use ipdisserver::encryption::AnswerKey;
+use ipdisserver::query::Query;
 use ipdisserver::{Signature, SERVER_PORT_DEFAULT, SIGNATURE_DEFAULT};
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::path::PathBuf;
 use std::thread;
 
@@ -30,6 +35,35 @@ struct Cli {
     #[arg(short, long, default_value_t = BROADCAST_ADDR_DEFAULT)]
     broadcast_addr: Ipv4Addr,
 
+    /// Send requests to this IPv4 multicast group instead of the broadcast address,
+    /// e.g. `239.255.19.1`. ipdisserver instances must join it (`--multicast-group`).
+    #[arg(short, long)]
+    multicast_group: Option<Ipv4Addr>,
+
+    /// Time-to-live of multicast requests: how many routers they can cross.
+    #[arg(long, default_value_t = MULTICAST_TTL_DEFAULT)]
+    multicast_ttl: u32,
+
+    /// Network interface where multicast requests are sent, e.g. `eth0`.
+    /// If not specified, the interface is chosen by the routing table.
+    #[arg(long)]
+    multicast_interface: Option<String>,
+
+    /// Scan IPv6 too, sending to the link-local multicast group `--ipv6-group`
+    /// on each interface.
+    #[arg(long)]
+    ipv6: bool,
+
+    /// IPv6 multicast group joined by ipdisserver instances.
+    #[arg(long, default_value_t = MULTICAST_V6_ADDR_DEFAULT)]
+    ipv6_group: Ipv6Addr,
+
+    /// Network interface where IPv6 scan requests are sent, e.g. `eth0`.
+    /// Repeat the option for each interface.
+    /// If not specified, all the multicast capable interfaces are used.
+    #[arg(long, action = clap::ArgAction::Append)]
+    ipv6_interface: Vec<String>,
+
     /// ipdisserver listening UDP port.
     #[arg(short, long, default_value_t = SERVER_PORT_DEFAULT)]
     target_port: u16,
@@ -37,12 +71,44 @@ struct Cli {
     /// String used to recognize ipdisserver instances.
     /// UTF-8 characters are allowed.
     /// Signature length must be 128 bytes at most.
-    /// [default: (NB: multiple signatures):
-    /// `ipdisbeacon` and `pang-supremacy-maritime-revoke-afterglow`
-    /// (the second one is for backward compatibility)]
+    /// [default: `ipdisbeacon`]
     #[arg(short, long)]
     signature: Option<String>,
 
+    /// Do not send the bare signature, without protocol header, for ipdisserver instances
+    /// older than the protocol version 1 (and, without `--signature`,
+    /// `pang-supremacy-maritime-revoke-afterglow` for the original ipdiscan beacons).
+    /// Current ipdisserver instances answer these requests in plain text.
+    #[arg(long)]
+    no_legacy: bool,
+
+    /// Path of a file containing the key used to authenticate requests (first line).
+    /// When set, authenticated requests are sent instead of the plain signatures.
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    auth_key_file: Option<PathBuf>,
+
+    /// Comma separated keys requested to ipdisserver instances, instead of the whole answer.
+    /// Keys ending with `*` are prefixes, e.g. `hostname,fw_*`.
+    #[arg(long)]
+    keys: Option<String>,
+
+    /// Ask ipdisserver instances to encrypt their answers, with a key generated at each start.
+    #[arg(long)]
+    encrypt: bool,
+
+    /// File where the public keys of the beacons are stored on first use.
+    /// Answers signed with a different key are flagged.
+    /// [default: `$XDG_DATA_HOME/ipdisscan/known_keys` or
+    /// `~/.local/share/ipdisscan/known_keys`]
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    known_keys: Option<PathBuf>,
+
+    /// Send no request, only listen for the announcements of ipdisserver instances
+    /// (`ipdisserver --announce`) on `--port`, and on `--multicast-group` or `--ipv6-group`
+    /// if given.
+    #[arg(long, conflicts_with_all = ["auth_key_file", "encrypt", "keys"])]
+    passive: bool,
+
     /// Scan period, in seconds.
     #[arg(long, default_value_t = SCAN_PERIOD_DEFAULT)]
     scan_period: f64,
@@ -56,40 +122,115 @@ struct Cli {
 fn main() -> Result<(), Report> {
     setup::eyre_setup()?;
     let cli = Cli::parse();
-    let signatures: Vec<Signature> = match cli.signature.as_deref() {
-        Some(s) => vec![Signature::from(s)],
-        None => vec![
+    let signatures = vec![Signature::from(
+        cli.signature.as_deref().unwrap_or(SIGNATURE_DEFAULT),
+    )];
+    let legacy_signatures: Vec<Signature> = match (cli.no_legacy, cli.signature.as_deref()) {
+        (true, _) => Vec::new(),
+        (false, Some(s)) => vec![Signature::from(s)],
+        (false, None) => vec![
             Signature::from(SIGNATURE_DEFAULT),
             Signature::from(EXTRA_SIGNATURE_DEFAULT),
         ],
     };
+    let auth_key = match &cli.auth_key_file {
+        Some(path) => Some(
+            ServerConfig::parse_auth_keys_file(path)?
+                .into_iter()
+                .next()
+                .ok_or_else(|| Report::msg(format!("No key in {:?}", path)))?,
+        ),
+        None => None,
+    };
     let conf = ScannerConfig {
         port: cli.port,
         scan_period: cli.scan_period,
         broadcast_addr: cli.broadcast_addr,
+        multicast_group: cli.multicast_group,
+        multicast_ttl: cli.multicast_ttl,
+        multicast_interface: cli.multicast_interface,
+        ipv6_group: cli.ipv6.then_some(cli.ipv6_group),
+        ipv6_interfaces: cli.ipv6_interface,
         target_port: cli.target_port,
         log_file: cli.log_file,
         signatures,
+        legacy_signatures,
+        auth_key,
+        query: Query::parse(cli.keys.as_deref().unwrap_or_default())?,
+        answer_key: match cli.encrypt {
+            true => Some(AnswerKey::generate()?),
+            false => None,
+        },
+        passive: cli.passive,
     };
     setup::log_setup(&conf.log_file)?;
+    let known_keys = KnownKeys::load(cli.known_keys.or_else(default_known_keys_path))?;
 
     let socket = socket_setup(conf.port)?;
+    multicast_setup(&socket, &conf)?;
     let socket_c = socket.try_clone()?;
+    let socket_v6 = match conf.ipv6_group {
+        Some(_) => {
+            let socket_v6 = socket_setup_v6(conf.port)?;
+            multicast_setup_v6(&socket_v6, &conf)?;
+            Some(socket_v6)
+        }
+        None => None,
+    };
     let (input_channel_send_end, input_channel_receive_end) = beacons::init_input_channel();
     let (output_channel_send_end, output_channel_receive_end) = beacons::init_output_channel();
     let (new_beacon_notification_channel_send_end, new_beacon_notification_channel_receive_end) =
         broadcast::init_notification_channel();
-    thread::spawn(move || listen::run(&socket_c, input_channel_send_end));
+    let freshness = Freshness::default();
+    if let Some(socket_v6) = &socket_v6 {
+        let socket_v6_c = socket_v6.try_clone()?;
+        let input_channel_send_end = input_channel_send_end.clone();
+        let answer_key = conf.answer_key.clone();
+        let freshness = freshness.clone();
+        thread::spawn(move || {
+            listen::run(
+                &socket_v6_c,
+                answer_key.as_ref(),
+                &freshness,
+                input_channel_send_end,
+            )
+        });
+    }
+    let answer_key = conf.answer_key.clone();
+    let listen_freshness = freshness.clone();
     thread::spawn(move || {
-        broadcast::run(&socket, new_beacon_notification_channel_receive_end, &conf)
+        listen::run(
+            &socket_c,
+            answer_key.as_ref(),
+            &listen_freshness,
+            input_channel_send_end,
+        )
     });
+    let passive = conf.passive;
+    // Kept open while passive, the beacons thread stops if it is disconnected.
+    let _new_beacon_notification_channel_receive_end = match passive {
+        true => Some(new_beacon_notification_channel_receive_end),
+        false => {
+            thread::spawn(move || {
+                broadcast::run(
+                    &socket,
+                    socket_v6.as_ref(),
+                    new_beacon_notification_channel_receive_end,
+                    &conf,
+                    &freshness,
+                )
+            });
+            None
+        }
+    };
     thread::spawn(move || {
         beacons::run(
             input_channel_receive_end,
             output_channel_send_end,
             new_beacon_notification_channel_send_end,
+            known_keys,
         )
     });
-    ui::run(output_channel_receive_end)?;
+    ui::run(output_channel_receive_end, passive)?;
     Ok(())
 }
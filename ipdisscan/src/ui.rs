@@ -1,4 +1,5 @@
 use crate::beacons::BeaconAnswer;
+use crate::known_keys::Verification;
 use color_eyre::eyre::Report;
 use crossbeam::channel::Receiver;
 use crossterm::event::{self, Event, KeyCode};
@@ -18,9 +19,16 @@ type ConcreteTerminal = Terminal<CrosstermBackend<Stdout>>;
 
 const HELP: &str = "q: close, j/↓: next, k/↑: previous";
 
-pub fn run(channel_receiving_end: Receiver<Vec<BeaconAnswer>>) -> Result<(), Report> {
+/// With `passive`, the title tells that no request is sent.
+pub fn run(
+    channel_receiving_end: Receiver<Vec<BeaconAnswer>>,
+    passive: bool,
+) -> Result<(), Report> {
     let mut terminal = init_terminal()?;
-    let mut app = App::default();
+    let mut app = App {
+        passive,
+        ..Default::default()
+    };
     app.next();
     loop {
         sleep(Duration::from_secs_f64(0.05)); // Ease CPU load, even if at the cost of reducing UI responsiveness
@@ -45,6 +53,8 @@ enum AppAction {
 struct App {
     server_answers: Vec<BeaconAnswer>,
     list_state: ListState,
+    /// Listening for announcements only.
+    passive: bool,
 }
 
 impl App {
@@ -89,15 +99,51 @@ impl App {
         };
         let info_text = match self.server_answers.get(index) {
             None => String::default(),
-            Some(a) => a.payload.pretty_format(),
+            Some(a) => format!(
+                "Signature: {}\n{}{}",
+                a.verification,
+                match a.online {
+                    true => "",
+                    false => "Offline: the device said goodbye\n",
+                },
+                a.payload.pretty_format()
+            ),
         };
         info_text
     }
 
-    fn get_list_items(&self) -> Vec<ListItem> {
+    fn get_list_items(&self) -> Vec<ListItem<'_>> {
         self.server_answers
             .iter()
-            .map(|a| ListItem::new(a.addr.to_string()))
+            .map(|a| {
+                let (label, color) = match a.verification {
+                    Verification::Verified(_) => (a.host(), None),
+                    Verification::Unverified | Verification::Legacy => {
+                        (format!("{} (unverified)", a.host()), None)
+                    }
+                    Verification::Invalid => (format!("{} INVALID", a.host()), Some(Color::Red)),
+                    Verification::KeyChanged { .. } => {
+                        (format!("{} KEY CHANGED", a.host()), Some(Color::Red))
+                    }
+                    Verification::SignatureMissing { .. } => {
+                        (format!("{} UNSIGNED", a.host()), Some(Color::Red))
+                    }
+                    Verification::Replayed(_) => {
+                        (format!("{} REPLAYED", a.host()), Some(Color::Red))
+                    }
+                };
+                let (label, color) = match a.online {
+                    true => (label, color),
+                    false => (
+                        format!("{} (offline)", label),
+                        color.or(Some(Color::DarkGray)),
+                    ),
+                };
+                match color {
+                    Some(c) => ListItem::new(label).style(Style::default().fg(c)),
+                    None => ListItem::new(label),
+                }
+            })
             .collect()
     }
 
@@ -156,7 +202,10 @@ fn draw_frame(terminal: &mut ConcreteTerminal, app: &mut App) -> Result<(), Repo
         // Surrounding block
         let block = Block::default()
             .borders(Borders::ALL)
-            .title(format!(" ipdisscan - ({}) ", HELP))
+            .title(match app.passive {
+                true => format!(" ipdisscan - PASSIVE, listening only - ({}) ", HELP),
+                false => format!(" ipdisscan - ({}) ", HELP),
+            })
             .title_alignment(Alignment::Center)
             .border_type(BorderType::Rounded);
         f.render_widget(block, f.size());
@@ -0,0 +1,88 @@
+use crate::answers::Answer;
+use crate::profiles::FULL_PROFILE_NAME;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+pub const ANNOUNCE_PORT_DEFAULT: u16 = 1902; // ipdisscan default port
+pub const ANNOUNCE_INTERVAL_DEFAULT: Duration = Duration::from_secs(300);
+const ANNOUNCE_HOLDOFF: Duration = Duration::from_secs(5); // min delay between announcements of a changing inventory
+
+/// Unsolicited answers sent to scanners at start, when the inventory changes and periodically.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceConfig {
+    /// Broadcast or multicast addresses, announcements are disabled if empty.
+    pub addrs: Vec<IpAddr>,
+    /// Port of the scanners.
+    pub port: u16,
+    /// Between two announcements of the same inventory, None to announce only at start and
+    /// on changes.
+    pub interval: Option<Duration>,
+    /// Name of the access profile applied to the announced inventory.
+    pub profile: String,
+}
+
+impl Default for AnnounceConfig {
+    fn default() -> Self {
+        Self {
+            addrs: Vec::new(),
+            port: ANNOUNCE_PORT_DEFAULT,
+            interval: Some(ANNOUNCE_INTERVAL_DEFAULT),
+            profile: FULL_PROFILE_NAME.into(),
+        }
+    }
+}
+
+impl AnnounceConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.addrs.is_empty()
+    }
+}
+
+/// Remember the last announcement, to tell when the next one is due.
+#[derive(Debug, Default)]
+pub struct Announcer {
+    last: Option<(Answer, Instant)>,
+}
+
+impl Announcer {
+    /// Return true if `answer` must be announced now, and remember it as announced: nothing
+    /// was announced yet, the answer changed (at most every `ANNOUNCE_HOLDOFF`) or `interval`
+    /// elapsed.
+    pub fn is_due(&mut self, answer: &Answer, now: Instant, interval: Option<Duration>) -> bool {
+        let due = match &self.last {
+            None => true,
+            Some((last, sent)) => {
+                let elapsed = now.saturating_duration_since(*sent);
+                (last != answer && elapsed >= ANNOUNCE_HOLDOFF)
+                    || interval.is_some_and(|i| elapsed >= i)
+            }
+        };
+        if due {
+            self.last = Some((answer.clone(), now));
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_due() {
+        let answer = Answer::from(r#"{"hostname":"dummy"}"#.to_string());
+        let changed = Answer::from(r#"{"hostname":"renamed"}"#.to_string());
+        let interval = Some(Duration::from_secs(60));
+        let start = Instant::now();
+        let mut announcer = Announcer::default();
+        assert!(announcer.is_due(&answer, start, interval));
+        assert!(!announcer.is_due(&answer, start + Duration::from_secs(1), interval));
+        // Changes are held off, then announced.
+        assert!(!announcer.is_due(&changed, start + Duration::from_secs(1), interval));
+        let later = start + ANNOUNCE_HOLDOFF;
+        assert!(announcer.is_due(&changed, later, interval));
+        assert!(!announcer.is_due(&changed, later + Duration::from_secs(59), interval));
+        assert!(announcer.is_due(&changed, later + Duration::from_secs(60), interval));
+        assert!(!announcer.is_due(&changed, later + Duration::from_secs(3600), None));
+    }
+}
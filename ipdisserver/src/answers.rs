@@ -1,12 +1,10 @@
 use crate::bytes::safe_format_bytes;
-use crate::inventory::{ExecuteInventory, InternalInventory, InventoryFile};
 use bytes::Bytes;
 use color_eyre::eyre::Report;
 use serde_json;
 use serde_json::value::Value;
 use std::fmt;
-use std::path::Path;
-use tracing::{debug, error, trace, warn};
+use tracing::{error, warn};
 
 const FALLBACK_INFO_KEY: &str = "info";
 
@@ -111,29 +109,52 @@ impl Answer {
     }
 }
 
-pub fn get_answer<P>(inventory_files: &[P]) -> Result<Answer, Report>
-where
-    P: AsRef<Path>,
-{
-    get_answer_hostname_and_files(InternalInventory::default(), inventory_files)
+impl Answer {
+    /// Answer of at most `max_length` bytes, to fit a single datagram: the longest keys are
+    /// omitted until it fits. Answers that are not JSON objects are truncated.
+    pub fn fit(&self, max_length: usize) -> Self {
+        if self.0.len() <= max_length {
+            return self.clone();
+        }
+        let mut infos: BeaconInfos = match serde_json::from_slice(&self.0) {
+            Ok(i) => i,
+            Err(_) => return Self(self.0.slice(..max_length)),
+        };
+        let mut longest: Vec<(String, usize)> = infos
+            .iter()
+            .map(|(k, v)| (k.clone(), k.len() + v.to_string().len()))
+            .collect();
+        longest.sort_by_key(|(_, length)| std::cmp::Reverse(*length));
+        let mut res = self.clone();
+        let mut omitted = Vec::new();
+        for (key, _) in longest {
+            if res.0.len() <= max_length {
+                break;
+            }
+            infos.remove(&key);
+            omitted.push(key);
+            res = Answer::from(Value::Object(infos.clone()).to_string());
+        }
+        warn!(
+            ?omitted,
+            length = self.0.len(),
+            max_length,
+            "Answer too long for a single datagram, keys omitted."
+        );
+        res
+    }
 }
 
-fn get_answer_hostname_and_files<P>(
-    hostname_inventory: InternalInventory,
-    inventory_files: &[P],
-) -> Result<Answer, Report>
+/// Build the answer from inventory outputs, in order: later keys replace earlier ones.
+pub fn answer_from_outputs<I>(outputs: I) -> Result<Answer, Report>
 where
-    P: AsRef<Path>,
+    I: IntoIterator<Item = BeaconInfos>,
 {
-    let mut hostname_answer = get_internal_inventory_answer(hostname_inventory);
-    debug!(?hostname_answer);
-    let mut inventory_answer = get_inventory_files_answer(inventory_files);
-    debug!(?inventory_answer);
-    let answer = Answer::from(serde_json::to_string(&join_answers(
-        &mut hostname_answer,
-        &mut inventory_answer,
-    ))?);
-    Ok(answer)
+    let mut res = BeaconInfos::new();
+    for mut output in outputs {
+        res = join_answers(&mut res, &mut output);
+    }
+    Ok(Answer::from(serde_json::to_string(&res)?))
 }
 
 fn join_answers(first: &mut BeaconInfos, second: &mut BeaconInfos) -> BeaconInfos {
@@ -141,32 +162,16 @@ fn join_answers(first: &mut BeaconInfos, second: &mut BeaconInfos) -> BeaconInfo
     first.clone()
 }
 
-fn get_internal_inventory_answer(inventory: InternalInventory) -> BeaconInfos {
-    inventory.execute().output
-}
-
-fn get_inventory_files_answer<P>(inventory_file_paths: &[P]) -> BeaconInfos
-where
-    P: AsRef<Path>,
-{
-    let mut res = BeaconInfos::new();
-    for inventory_path in inventory_file_paths {
-        let inventory = InventoryFile::from(inventory_path.as_ref());
-        trace!(?inventory, "Executing inventory file.");
-        let mut inventory_result = inventory.execute();
-        trace!(?inventory_result, ?inventory, "Inventory file executed.");
-        res = join_answers(&mut res, &mut inventory_result.output);
-        trace!(?res, "Updated inventory info.");
-    }
-    res
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::cache::{InventoryCache, InventorySource};
+    use crate::inventory::{InternalInventory, InventoryFile};
     use std::collections::HashMap;
     use std::os::unix::fs::PermissionsExt;
     use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
 
     #[test]
     #[tracing_test::traced_test]
@@ -258,7 +263,7 @@ mod test {
         let empty_file_path = write_inventory_file("empty-file", "");
         let nonexisting_path = PathBuf::from("non-existing-file");
 
-        let inventory_files = vec![
+        let inventory_files: Vec<InventoryFile> = [
             echo_list_path,
             echo_multiple_lines_path,
             echo_nothing_path,
@@ -266,21 +271,23 @@ mod test {
             return_error_path,
             empty_file_path,
             nonexisting_path,
-        ];
+        ]
+        .iter()
+        .map(|p| InventoryFile::from(p.as_path()))
+        .collect();
         let expected = r#"{"foo":["bar","baz"],"foo1":"1","foo2":"2","foo3 ":" 3","hostname":"dummy-hostname"}"#;
+        let mut sources: Vec<InventorySource> = vec![Arc::new(InternalInventory {
+            key: "hostname".to_string(),
+            source: Box::new(|| "dummy-hostname".into()),
+            ttl: Duration::ZERO,
+        })]; // mock hostname
+        for file in inventory_files {
+            sources.push(Arc::new(file));
+        }
+        let cache = InventoryCache::new(sources, 2);
+        cache.refresh_expired(Instant::now());
         assert_eq!(
-            std::str::from_utf8(
-                &get_answer_hostname_and_files(
-                    InternalInventory {
-                        key: "hostname".to_string(),
-                        source: Box::new(|| "dummy-hostname".to_string())
-                    }, // mock hostname
-                    inventory_files.as_slice()
-                )
-                .unwrap()
-                .0
-            )
-            .unwrap(),
+            std::str::from_utf8(&cache.answer().unwrap().0).unwrap(),
             expected
         );
     }
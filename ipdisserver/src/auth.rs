@@ -0,0 +1,302 @@
+use crate::protocol::Request;
+use crate::signature::Signature;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::{trace, warn};
+
+/// Start of every authenticated request. Plain signatures are not expected to start with it.
+pub const AUTH_MAGIC: &[u8; 4] = b"IPDA";
+pub const NONCE_LENGTH: usize = 16;
+const MAC_LENGTH: usize = 32; // HMAC-SHA256
+/// Magic, timestamp (u64, seconds since the Unix epoch, big-endian), nonce, HMAC.
+/// The HMAC covers the whole datagram before it, header and body included.
+pub const AUTH_REQUEST_LENGTH: usize = AUTH_MAGIC.len() + 8 + NONCE_LENGTH + MAC_LENGTH;
+pub const AUTH_WINDOW_DEFAULT: Duration = Duration::from_secs(30); // max clock difference with scanners
+const MAX_SEEN_NONCES: usize = 65536; // requests are rejected when full, until nonces expire
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Secret shared between scanners and servers.
+#[derive(Clone, PartialEq, Eq)]
+pub struct AuthKey(pub Bytes);
+
+impl fmt::Debug for AuthKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AuthKey(<{} bytes>)", self.0.len()) // never log secrets
+    }
+}
+
+impl From<&str> for AuthKey {
+    fn from(string: &str) -> Self {
+        Self(Bytes::copy_from_slice(string.as_bytes()))
+    }
+}
+
+/// Reason why an authenticated request is rejected.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum AuthError {
+    #[error("not an authenticated request")]
+    NotAuthenticated,
+    #[error("timestamp {0} out of the accepted window")]
+    Expired(u64),
+    #[error("HMAC not matching any key")]
+    BadMac,
+    #[error("nonce already used")]
+    Replayed,
+    #[error("too many recent requests to check replays")]
+    TooManyNonces,
+}
+
+/// Discovery request proving the knowledge of a shared key, without disclosing it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthRequest {
+    pub timestamp: u64,
+    pub nonce: [u8; NONCE_LENGTH],
+}
+
+impl AuthRequest {
+    /// New request with a random nonce.
+    pub fn new(now: SystemTime) -> Result<Self, getrandom::Error> {
+        let mut nonce = [0; NONCE_LENGTH];
+        getrandom::getrandom(&mut nonce)?;
+        Ok(Self {
+            timestamp: unix_seconds(now),
+            nonce,
+        })
+    }
+
+    /// Request signed with `key`, to be sent in place of a plain signature in the legacy format.
+    pub fn encode(&self, key: &AuthKey) -> Signature {
+        self.seal(key, BytesMut::with_capacity(AUTH_REQUEST_LENGTH))
+    }
+
+    /// `request` with this as credential, the HMAC covering the whole datagram so that
+    /// its header, answer key and query cannot be changed.
+    pub fn authenticate(&self, key: &AuthKey, request: &Request) -> Signature {
+        let unsealed = Request {
+            credential: Signature(Bytes::new()),
+            ..request.clone()
+        }
+        .encode();
+        let mut datagram = BytesMut::with_capacity(unsealed.0.len() + AUTH_REQUEST_LENGTH);
+        datagram.put_slice(&unsealed.0);
+        self.seal(key, datagram)
+    }
+
+    fn seal(&self, key: &AuthKey, mut datagram: BytesMut) -> Signature {
+        datagram.put_slice(&self.signed_part());
+        let mac = new_mac(key).chain_update(&datagram).finalize().into_bytes();
+        datagram.put_slice(&mac);
+        Signature(datagram.freeze())
+    }
+
+    /// Request ending the datagram, the part covered by the HMAC and the HMAC.
+    /// None if the datagram does not end with an authenticated request.
+    fn decode(datagram: &[u8]) -> Option<(Self, &[u8], &[u8])> {
+        let start = datagram.len().checked_sub(AUTH_REQUEST_LENGTH)?;
+        if !datagram[start..].starts_with(AUTH_MAGIC) {
+            return None;
+        }
+        let mut fields = &datagram[start + AUTH_MAGIC.len()..];
+        let timestamp = fields.get_u64();
+        let mut nonce = [0; NONCE_LENGTH];
+        fields.copy_to_slice(&mut nonce);
+        let (signed, mac) = datagram.split_at(datagram.len() - MAC_LENGTH);
+        Some((Self { timestamp, nonce }, signed, mac))
+    }
+
+    fn signed_part(&self) -> Bytes {
+        let mut res = BytesMut::with_capacity(AUTH_REQUEST_LENGTH - MAC_LENGTH);
+        res.put_slice(AUTH_MAGIC);
+        res.put_u64(self.timestamp);
+        res.put_slice(&self.nonce);
+        res.freeze()
+    }
+}
+
+fn new_mac(key: &AuthKey) -> HmacSha256 {
+    HmacSha256::new_from_slice(&key.0).expect("HMAC accepts keys of any length")
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Verify authenticated requests, remembering the nonces of the accepted ones
+/// until their timestamp leaves the window.
+#[derive(Debug, Clone)]
+pub struct Authenticator {
+    /// Any of them is accepted, to allow rotation.
+    pub keys: Vec<AuthKey>,
+    /// Maximum difference between the request timestamp and the local clock.
+    pub window: Duration,
+    /// Accept plain signatures too. Always true if there are no keys.
+    pub allow_unauthenticated: bool,
+    seen_nonces: HashMap<[u8; NONCE_LENGTH], u64>,
+}
+
+impl Authenticator {
+    pub fn new(keys: Vec<AuthKey>, window: Duration, allow_unauthenticated: bool) -> Self {
+        Self {
+            keys,
+            window,
+            allow_unauthenticated,
+            seen_nonces: HashMap::new(),
+        }
+    }
+
+    /// True if plain signatures must be checked, when the request is not authenticated.
+    pub fn accepts_unauthenticated(&self) -> bool {
+        self.keys.is_empty() || self.allow_unauthenticated
+    }
+
+    /// Return the key the request is authenticated with. `datagram` is the whole request, its
+    /// credential last.
+    pub fn verify(&mut self, datagram: &[u8], now: SystemTime) -> Result<&AuthKey, AuthError> {
+        let (request, signed, mac) =
+            AuthRequest::decode(datagram).ok_or(AuthError::NotAuthenticated)?;
+        let now = unix_seconds(now);
+        if now.abs_diff(request.timestamp) > self.window.as_secs() {
+            return Err(AuthError::Expired(request.timestamp));
+        }
+        let key_index = self
+            .keys
+            .iter()
+            .position(|k| new_mac(k).chain_update(signed).verify_slice(mac).is_ok())
+            .ok_or(AuthError::BadMac)?;
+        self.forget_expired_nonces(now);
+        if self.seen_nonces.contains_key(&request.nonce) {
+            return Err(AuthError::Replayed);
+        }
+        if self.seen_nonces.len() >= MAX_SEEN_NONCES {
+            warn!(
+                nonces = self.seen_nonces.len(),
+                "Nonce cache full, rejecting request."
+            );
+            return Err(AuthError::TooManyNonces);
+        }
+        self.seen_nonces.insert(request.nonce, request.timestamp);
+        trace!(
+            timestamp = request.timestamp,
+            "Authenticated request verified."
+        );
+        Ok(&self.keys[key_index])
+    }
+
+    fn forget_expired_nonces(&mut self, now: u64) {
+        let oldest = now.saturating_sub(self.window.as_secs());
+        self.seen_nonces.retain(|_, timestamp| *timestamp >= oldest);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encryption::AnswerKey;
+    use crate::query::Query;
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_verify() {
+        let now = SystemTime::now();
+        let old_key = AuthKey::from("old-secret");
+        let new_key = AuthKey::from("new-secret");
+        let mut authenticator = Authenticator::new(
+            vec![old_key.clone(), new_key.clone()],
+            AUTH_WINDOW_DEFAULT,
+            false,
+        );
+        for key in [&old_key, &new_key] {
+            let request = AuthRequest::new(now).unwrap().encode(key);
+            assert_eq!(request.0.len(), AUTH_REQUEST_LENGTH);
+            assert_eq!(authenticator.verify(&request.0, now), Ok(key));
+            assert_eq!(
+                authenticator.verify(&request.0, now),
+                Err(AuthError::Replayed)
+            );
+        }
+        let request = AuthRequest::new(now)
+            .unwrap()
+            .encode(&AuthKey::from("wrong-secret"));
+        assert_eq!(
+            authenticator.verify(&request.0, now),
+            Err(AuthError::BadMac)
+        );
+        assert_eq!(
+            authenticator.verify(b"ipdisbeacon", now),
+            Err(AuthError::NotAuthenticated)
+        );
+        assert!(!authenticator.accepts_unauthenticated());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_verify_window() {
+        let now = SystemTime::now();
+        let key = AuthKey::from("secret");
+        let mut authenticator = Authenticator::new(vec![key.clone()], AUTH_WINDOW_DEFAULT, false);
+        let late = now + AUTH_WINDOW_DEFAULT + Duration::from_secs(1);
+        let request = AuthRequest::new(now).unwrap();
+        assert_eq!(
+            authenticator.verify(&request.encode(&key).0, late),
+            Err(AuthError::Expired(request.timestamp))
+        );
+        let early = AuthRequest::new(late).unwrap();
+        assert_eq!(
+            authenticator.verify(&early.encode(&key).0, now),
+            Err(AuthError::Expired(early.timestamp))
+        );
+        // Nonces are forgotten once out of the window, replays are rejected by timestamp.
+        assert_eq!(authenticator.verify(&request.encode(&key).0, now), Ok(&key));
+        authenticator.verify(&early.encode(&key).0, late).unwrap();
+        assert_eq!(authenticator.seen_nonces.len(), 1);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_verify_whole_request() {
+        let now = SystemTime::now();
+        let key = AuthKey::from("secret");
+        let mut authenticator = Authenticator::new(vec![key.clone()], AUTH_WINDOW_DEFAULT, false);
+        let request = Request {
+            answer_key: Some(AnswerKey::generate().unwrap().public_key()),
+            query: Query::parse("hostname").unwrap(),
+            ..Request::new(1, Signature::from(""))
+        };
+        let authenticated = AuthRequest::new(now).unwrap().authenticate(&key, &request);
+        let decoded = Request::decode(&authenticated.0).unwrap();
+        assert_eq!(decoded.answer_key, request.answer_key);
+        assert_eq!(decoded.credential.0.len(), AUTH_REQUEST_LENGTH);
+        // The credential of a captured request is moved to a request with another answer key.
+        let swapped = Request {
+            answer_key: Some(AnswerKey::generate().unwrap().public_key()),
+            ..decoded.clone()
+        };
+        assert_eq!(
+            authenticator.verify(&swapped.encode().0, now),
+            Err(AuthError::BadMac)
+        );
+        let everything = Request {
+            query: Query::default(),
+            ..decoded
+        };
+        assert_eq!(
+            authenticator.verify(&everything.encode().0, now),
+            Err(AuthError::BadMac)
+        );
+        assert_eq!(authenticator.verify(&authenticated.0, now), Ok(&key));
+    }
+
+    #[test]
+    fn test_key_not_logged() {
+        assert_eq!(
+            format!("{:?}", AuthKey::from("secret")),
+            "AuthKey(<6 bytes>)"
+        );
+    }
+}
@@ -0,0 +1,543 @@
+use crate::answers::{answer_from_outputs, Answer, BeaconInfos};
+use crate::exec::InventoryError;
+use crate::inventory::{
+    execute_parallel, ExecuteInventory, InternalInventory, InventoryFile, InventoryOutput,
+};
+use crate::profiles::AccessProfile;
+use crate::query::Query;
+use color_eyre::eyre::Report;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, sleep, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, trace, warn};
+
+const REFRESH_PERIOD: Duration = Duration::from_secs(1); // how often expired entries are looked for
+
+pub type InventorySource = Arc<dyn ExecuteInventory + Send + Sync>;
+
+#[derive(Debug, Clone, Default)]
+struct CachedOutput {
+    /// If the last execution failed, the output of the previous one with the error.
+    output: InventoryOutput,
+    expiry: Option<Instant>, // None if never executed or flushed
+    /// An answer was built since the last execution.
+    queried: bool,
+    /// An answer since the last execution contained keys of this source.
+    wanted: bool,
+}
+
+struct CacheEntry {
+    source: InventorySource,
+    cached: Mutex<CachedOutput>,
+}
+
+/// Result of the last execution of an inventory source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceStatus {
+    pub name: String,
+    /// Why the last execution failed, if it did.
+    pub error: Option<InventoryError>,
+    /// The output answered is the one of a previous execution, the last one failed.
+    pub stale: bool,
+}
+
+/// Last output of each inventory source, re-executed when its TTL expires.
+/// Sources whose keys no query matched since their last execution are not re-executed until
+/// one does: their last output is then answered, and refreshed just after. Queries for the
+/// whole answer want every source, and sources that failed or output nothing are always
+/// re-executed, their keys are unknown.
+/// Cloning is cheap, clones share the same cache.
+#[derive(Clone)]
+pub struct InventoryCache {
+    entries: Arc<Vec<CacheEntry>>,
+    max_workers: usize,
+    /// End of the last refresh, to tell if refreshing is stuck.
+    refreshed: Arc<Mutex<Option<Instant>>>,
+}
+
+impl fmt::Debug for InventoryCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InventoryCache")
+            .field(
+                "sources",
+                &self
+                    .entries
+                    .iter()
+                    .map(|e| e.source.name())
+                    .collect::<Vec<String>>(),
+            )
+            .finish()
+    }
+}
+
+impl InventoryCache {
+    /// Sources are kept in order: keys from later sources replace earlier ones in the answer.
+    /// Up to `max_workers` sources are executed concurrently on refresh.
+    pub fn new(sources: Vec<InventorySource>, max_workers: usize) -> Self {
+        let entries = sources
+            .into_iter()
+            .map(|source| CacheEntry {
+                source,
+                cached: Mutex::default(),
+            })
+            .collect();
+        Self {
+            entries: Arc::new(entries),
+            max_workers,
+            refreshed: Arc::default(),
+        }
+    }
+
+    /// Built-in inventory sources followed by the inventory files.
+    pub fn from_inventory_files(inventory_files: &[InventoryFile], max_workers: usize) -> Self {
+        let mut sources: Vec<InventorySource> = Vec::new();
+        for internal in InternalInventory::builtins() {
+            sources.push(Arc::new(internal));
+        }
+        for file in inventory_files {
+            sources.push(Arc::new(file.clone()));
+        }
+        Self::new(sources, max_workers)
+    }
+
+    /// Execute the sources whose output is expired at `now` and still wanted, return how many
+    /// were executed. The cache is not locked while sources execute.
+    pub fn refresh_expired(&self, now: Instant) -> usize {
+        let expired: Vec<&CacheEntry> = self
+            .entries
+            .iter()
+            .filter(|e| e.needs_refresh(now))
+            .collect();
+        if expired.is_empty() {
+            self.mark_refreshed();
+            return 0;
+        }
+        let sources: Vec<InventorySource> = expired.iter().map(|e| e.source.clone()).collect();
+        trace!(sources = ?sources.iter().map(|s| s.name()).collect::<Vec<String>>(), "Refreshing inventories.");
+        let outputs = execute_parallel(&sources, self.max_workers);
+        for (entry, output) in expired.iter().zip(outputs) {
+            let expiry = Some(now + entry.source.ttl());
+            let mut cached = entry.lock();
+            let output = match output.error {
+                Some(error) if !cached.output.output.is_empty() => {
+                    warn!(source = %entry.source.name(), %error, "Inventory source failed, previous output kept.");
+                    InventoryOutput {
+                        error: Some(error),
+                        ..cached.output.clone()
+                    }
+                }
+                _ => output,
+            };
+            *cached = CachedOutput {
+                output,
+                expiry,
+                ..Default::default()
+            };
+        }
+        debug!(refreshed = expired.len(), "Inventory cache refreshed.");
+        self.mark_refreshed();
+        expired.len()
+    }
+
+    /// When the last refresh ended, None if never refreshed.
+    pub fn last_refresh(&self) -> Option<Instant> {
+        *self
+            .refreshed
+            .lock()
+            .expect("Inventory cache lock poisoned")
+    }
+
+    fn mark_refreshed(&self) {
+        *self
+            .refreshed
+            .lock()
+            .expect("Inventory cache lock poisoned") = Some(Instant::now());
+    }
+
+    /// Mark all the outputs as expired, to be re-executed at next refresh.
+    pub fn flush(&self) {
+        for entry in self.entries.iter() {
+            entry.lock().expiry = None;
+        }
+        info!("Inventory cache flushed.");
+    }
+
+    /// Last execution result of each source, in order.
+    pub fn status(&self) -> Vec<SourceStatus> {
+        self.entries
+            .iter()
+            .map(|e| {
+                let cached = e.lock();
+                SourceStatus {
+                    name: e.source.name(),
+                    error: cached.output.error.clone(),
+                    stale: cached.output.error.is_some() && !cached.output.output.is_empty(),
+                }
+            })
+            .collect()
+    }
+
+    /// Answer built from the cached outputs, without executing any source.
+    pub fn answer(&self) -> Result<Answer, Report> {
+        answer_from_outputs(self.entries.iter().map(|e| e.lock().output.output.clone()))
+    }
+
+    /// Answer with only the sources and keys allowed by the profile, and requested by the query.
+    /// The sources providing them are marked as wanted, see `refresh_expired`.
+    pub fn answer_for(
+        &self,
+        profile: &AccessProfile,
+        query: &Query,
+        metadata: BeaconInfos,
+    ) -> Result<Answer, Report> {
+        let mut outputs = Vec::new();
+        for entry in self.entries.iter() {
+            let mut cached = entry.lock();
+            cached.queried = true;
+            if !profile.allows_source(&entry.source.name()) {
+                continue;
+            }
+            let output = cached.output.output.clone();
+            if query.is_empty()
+                || output
+                    .keys()
+                    .any(|k| profile.allows_key(k) && query.matches(k))
+            {
+                cached.wanted = true;
+            }
+            outputs.push(output);
+        }
+        answer_from_outputs(outputs.into_iter().chain(std::iter::once(metadata)).map(
+            |mut output| {
+                output.retain(|key, _| profile.allows_key(key) && query.matches(key));
+                output
+            },
+        ))
+    }
+}
+
+impl CacheEntry {
+    fn lock(&self) -> std::sync::MutexGuard<'_, CachedOutput> {
+        self.cached.lock().expect("Inventory cache lock poisoned")
+    }
+
+    /// Never executed, or expired and either wanted, not queried at all since the last
+    /// execution (kept fresh for the next query), or without known keys.
+    fn needs_refresh(&self, now: Instant) -> bool {
+        let cached = self.lock();
+        let keys_known = cached.output.error.is_none() && !cached.output.output.is_empty();
+        match cached.expiry {
+            None => true,
+            Some(expiry) if now < expiry => false,
+            Some(_) if keys_known && cached.queried && !cached.wanted => {
+                trace!(source = %self.source.name(), "No query for the source keys, not refreshed.");
+                false
+            }
+            Some(_) => true,
+        }
+    }
+}
+
+/// Periodically refresh expired cache entries on a dedicated thread.
+/// The thread stops when all the clones of the cache are dropped.
+pub fn spawn_refresh_worker(cache: &InventoryCache) -> Result<JoinHandle<()>, Report> {
+    let entries = Arc::downgrade(&cache.entries);
+    let max_workers = cache.max_workers;
+    let refreshed = cache.refreshed.clone();
+    let handle = thread::Builder::new()
+        .name("inventory-refresh".into())
+        .spawn(move || {
+            while let Some(entries) = entries.upgrade() {
+                InventoryCache {
+                    entries,
+                    max_workers,
+                    refreshed: refreshed.clone(),
+                }
+                .refresh_expired(Instant::now());
+                sleep(REFRESH_PERIOD);
+            }
+            debug!("Inventory cache dropped, refresh worker stopped.");
+        })?;
+    Ok(handle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::value::Value;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn counting_source(counter: Arc<AtomicUsize>, ttl: Duration) -> InventorySource {
+        Arc::new(InternalInventory {
+            key: "count".into(),
+            source: Box::new(move || (counter.fetch_add(1, Ordering::SeqCst) + 1).into()),
+            ttl,
+        })
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_refresh_expired() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let ttl = Duration::from_secs(30);
+        let cache = InventoryCache::new(vec![counting_source(counter.clone(), ttl)], 1);
+        let start = Instant::now();
+        assert_eq!(cache.answer().unwrap().0, "{}");
+        assert_eq!(cache.last_refresh(), None);
+        assert_eq!(cache.refresh_expired(start), 1);
+        assert!(cache.last_refresh().is_some());
+        assert_eq!(cache.answer().unwrap().0, r#"{"count":1}"#);
+        assert_eq!(cache.refresh_expired(start + ttl / 2), 0);
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.refresh_expired(start + ttl), 1);
+        assert_eq!(cache.answer().unwrap().0, r#"{"count":2}"#);
+        cache.flush();
+        assert_eq!(cache.refresh_expired(start + ttl), 1);
+        assert_eq!(counter.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_refresh_wanted() {
+        let source = |key: &str, counter: Arc<AtomicUsize>| -> InventorySource {
+            Arc::new(InternalInventory {
+                key: key.into(),
+                source: Box::new(move || (counter.fetch_add(1, Ordering::SeqCst) + 1).into()),
+                ttl: Duration::from_secs(30),
+            })
+        };
+        let hostname = Arc::new(AtomicUsize::new(0));
+        let serial = Arc::new(AtomicUsize::new(0));
+        let cache = InventoryCache::new(
+            vec![
+                source("hostname", hostname.clone()),
+                source("serial", serial.clone()),
+            ],
+            1,
+        );
+        let full = AccessProfile::full(Vec::new(), Vec::new());
+        let query = |keys| {
+            cache
+                .answer_for(&full, &Query::parse(keys).unwrap(), BeaconInfos::new())
+                .unwrap()
+                .0
+        };
+        let start = Instant::now();
+        let ttl = Duration::from_secs(30);
+        assert_eq!(cache.refresh_expired(start), 2);
+        // Not queried: kept fresh.
+        assert_eq!(cache.refresh_expired(start + ttl), 2);
+        assert_eq!(query("hostname"), r#"{"hostname":2}"#);
+        assert_eq!(cache.refresh_expired(start + ttl * 2), 1);
+        assert_eq!(
+            (
+                hostname.load(Ordering::SeqCst),
+                serial.load(Ordering::SeqCst)
+            ),
+            (3, 2)
+        );
+        // Deferred until queried, then answered once from the last output.
+        assert_eq!(query("hostname"), r#"{"hostname":3}"#);
+        assert_eq!(cache.refresh_expired(start + ttl * 3), 1);
+        assert_eq!(query("serial"), r#"{"serial":2}"#);
+        assert_eq!(cache.refresh_expired(start + ttl * 3), 1);
+        assert_eq!(query("serial"), r#"{"serial":3}"#);
+        // A full query wants every source.
+        query("");
+        assert_eq!(cache.refresh_expired(start + ttl * 4), 2);
+    }
+
+    /// Source outputting `count=N` at its Nth execution, failing when `fails(N)`.
+    struct FlakySource {
+        runs: AtomicUsize,
+        fails: fn(usize) -> bool,
+        ttl: Duration,
+    }
+
+    impl ExecuteInventory for FlakySource {
+        fn execute(&self) -> InventoryOutput {
+            let run = self.runs.fetch_add(1, Ordering::SeqCst) + 1;
+            if (self.fails)(run) {
+                return InventoryOutput {
+                    error: Some(InventoryError::ExitStatus("exit status: 1".into())),
+                    ..Default::default()
+                };
+            }
+            let mut output = BeaconInfos::new();
+            output.insert("count".into(), run.into());
+            InventoryOutput {
+                raw_output: format!("count={}", run),
+                output,
+                error: None,
+            }
+        }
+
+        fn name(&self) -> String {
+            "flaky".into()
+        }
+
+        fn ttl(&self) -> Duration {
+            self.ttl
+        }
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_failing_source() {
+        let ttl = Duration::from_secs(30);
+        let source = FlakySource {
+            runs: AtomicUsize::new(0),
+            fails: |run| run == 2,
+            ttl,
+        };
+        let cache = InventoryCache::new(vec![Arc::new(source)], 1);
+        let start = Instant::now();
+        cache.refresh_expired(start);
+        assert_eq!(cache.status()[0].error, None);
+        cache.refresh_expired(start + ttl);
+        assert_eq!(cache.answer().unwrap().0, r#"{"count":1}"#);
+        assert_eq!(
+            cache.status(),
+            vec![SourceStatus {
+                name: "flaky".into(),
+                error: Some(InventoryError::ExitStatus("exit status: 1".into())),
+                stale: true,
+            }]
+        );
+        assert!(logs_contain("previous output kept"));
+        // Answering the previous output, but refreshed even if its keys are not queried.
+        let full = AccessProfile::full(Vec::new(), Vec::new());
+        let other = Query::parse("hostname").unwrap();
+        cache.answer_for(&full, &other, BeaconInfos::new()).unwrap();
+        cache.refresh_expired(start + ttl * 2);
+        assert_eq!(cache.answer().unwrap().0, r#"{"count":3}"#);
+        assert_eq!(cache.status()[0].error, None);
+        assert!(!cache.status()[0].stale);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_failing_source_recovers() {
+        let ttl = Duration::from_secs(30);
+        let source = FlakySource {
+            runs: AtomicUsize::new(0),
+            fails: |run| run == 1,
+            ttl,
+        };
+        let cache = InventoryCache::new(vec![Arc::new(source)], 1);
+        let full = AccessProfile::full(Vec::new(), Vec::new());
+        let query = |keys| {
+            cache
+                .answer_for(&full, &Query::parse(keys).unwrap(), BeaconInfos::new())
+                .unwrap()
+                .0
+        };
+        let start = Instant::now();
+        assert_eq!(cache.refresh_expired(start), 1);
+        assert_eq!(query("count"), "{}");
+        assert_eq!(query("hostname"), "{}");
+        assert_eq!(cache.refresh_expired(start + ttl / 2), 0);
+        assert_eq!(cache.refresh_expired(start + ttl), 1);
+        assert_eq!(query("count"), r#"{"count":2}"#);
+        // Queries for the whole answer want every source.
+        assert_eq!(cache.refresh_expired(start + ttl * 2), 1);
+        query("hostname");
+        query("");
+        assert_eq!(cache.refresh_expired(start + ttl * 3), 1);
+        assert_eq!(query("count"), r#"{"count":4}"#);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_answer_order() {
+        let first = InternalInventory {
+            key: "key".into(),
+            source: Box::new(|| Value::from("first")),
+            ttl: Duration::ZERO,
+        };
+        let second = InternalInventory {
+            key: "key".into(),
+            source: Box::new(|| Value::from("second")),
+            ttl: Duration::ZERO,
+        };
+        let cache = InventoryCache::new(vec![Arc::new(first), Arc::new(second)], 2);
+        cache.refresh_expired(Instant::now());
+        assert_eq!(cache.answer().unwrap().0, r#"{"key":"second"}"#);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_answer_for_profile() {
+        let source = |key: &str, value: &'static str| -> InventorySource {
+            Arc::new(InternalInventory {
+                key: key.into(),
+                source: Box::new(move || Value::from(value)),
+                ttl: Duration::ZERO,
+            })
+        };
+        let cache = InventoryCache::new(
+            vec![
+                source("hostname", "dummy"),
+                source("fw_version", "1.2"),
+                source("serial", "0123"),
+            ],
+            1,
+        );
+        cache.refresh_expired(Instant::now());
+        let pattern = |p: &str| glob::Pattern::new(p).unwrap();
+        let full = AccessProfile::full(Vec::new(), Vec::new());
+        let all = Query::default();
+        let none = BeaconInfos::new;
+        assert_eq!(
+            cache.answer_for(&full, &all, none()).unwrap(),
+            cache.answer().unwrap()
+        );
+        assert_eq!(
+            cache
+                .answer_for(&full, &Query::parse("serial,fw_*").unwrap(), none())
+                .unwrap()
+                .0,
+            r#"{"fw_version":"1.2","serial":"0123"}"#
+        );
+        let mut metadata = BeaconInfos::new();
+        metadata.insert("received".into(), Value::from("eth0"));
+        assert_eq!(
+            cache
+                .answer_for(
+                    &full,
+                    &Query::parse("serial,rec*").unwrap(),
+                    metadata.clone()
+                )
+                .unwrap()
+                .0,
+            r#"{"received":"eth0","serial":"0123"}"#
+        );
+        let public = AccessProfile {
+            allow_keys: vec![pattern("hostname"), pattern("fw_*")],
+            deny_sources: vec![pattern("fw_version")],
+            ..full
+        };
+        assert_eq!(
+            cache.answer_for(&public, &all, metadata).unwrap().0,
+            r#"{"hostname":"dummy"}"#
+        );
+        assert_eq!(
+            cache
+                .answer_for(&public, &Query::parse("serial").unwrap(), none())
+                .unwrap()
+                .0,
+            "{}"
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_refresh_worker_stops() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let cache = InventoryCache::new(vec![counting_source(counter, Duration::ZERO)], 1);
+        let handle = spawn_refresh_worker(&cache).unwrap();
+        drop(cache);
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+use crate::answers::Answer;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use color_eyre::eyre::Report;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Answers longer than this are split in chunks. Datagrams this long fit the IPv6 minimum MTU,
+/// so they are not fragmented at the IP level.
+pub const DATAGRAM_MAX_LENGTH: usize = 1200;
+/// Start of every chunk. Unchunked answers are JSON objects, starting with `{`.
+pub const CHUNK_MAGIC: &[u8; 4] = b"IPDC";
+/// Magic, message id (u32), chunk index (u16), chunk count (u16), all big-endian.
+pub const CHUNK_HEADER_LENGTH: usize = 12;
+
+/// Part of an answer too long for a single datagram.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    /// Same for all the chunks of an answer, different for each answer of a server.
+    pub message_id: u32,
+    /// From 0 to `count - 1`.
+    pub index: u16,
+    pub count: u16,
+    pub data: Bytes,
+}
+
+impl Chunk {
+    pub fn encode(&self) -> Bytes {
+        let mut res = BytesMut::with_capacity(CHUNK_HEADER_LENGTH + self.data.len());
+        res.put_slice(CHUNK_MAGIC);
+        res.put_u32(self.message_id);
+        res.put_u16(self.index);
+        res.put_u16(self.count);
+        res.put_slice(&self.data);
+        res.freeze()
+    }
+
+    /// None if the datagram is not a valid chunk, e.g. a whole answer.
+    pub fn decode(datagram: &[u8]) -> Option<Self> {
+        if datagram.len() < CHUNK_HEADER_LENGTH || !datagram.starts_with(CHUNK_MAGIC) {
+            return None;
+        }
+        let mut header = &datagram[CHUNK_MAGIC.len()..CHUNK_HEADER_LENGTH];
+        let message_id = header.get_u32();
+        let index = header.get_u16();
+        let count = header.get_u16();
+        if index >= count {
+            return None;
+        }
+        Some(Self {
+            message_id,
+            index,
+            count,
+            data: Bytes::copy_from_slice(&datagram[CHUNK_HEADER_LENGTH..]),
+        })
+    }
+}
+
+impl Answer {
+    /// Datagrams carrying the answer: the answer itself if it is at most `max_length` bytes
+    /// long, numbered chunks otherwise.
+    pub fn to_datagrams(&self, message_id: u32, max_length: usize) -> Result<Vec<Bytes>, Report> {
+        if self.0.len() <= max_length {
+            return Ok(vec![self.0.clone()]);
+        }
+        if max_length <= CHUNK_HEADER_LENGTH {
+            return Err(Report::msg(format!(
+                "Datagram length {} too short for chunks",
+                max_length
+            )));
+        }
+        let parts: Vec<&[u8]> = self.0.chunks(max_length - CHUNK_HEADER_LENGTH).collect();
+        let count = u16::try_from(parts.len())
+            .map_err(|_| Report::msg(format!("Answer too long: {} bytes", self.0.len())))?;
+        Ok(parts
+            .into_iter()
+            .enumerate()
+            .map(|(index, data)| {
+                Chunk {
+                    message_id,
+                    index: index as u16,
+                    count,
+                    data: Bytes::copy_from_slice(data),
+                }
+                .encode()
+            })
+            .collect())
+    }
+
+    /// Join the data of all the chunks of an answer, sorted by index.
+    pub fn from_chunks<'a, I>(chunks: I) -> Self
+    where
+        I: IntoIterator<Item = &'a Bytes>,
+    {
+        let mut res = BytesMut::new();
+        for data in chunks {
+            res.put_slice(data);
+        }
+        Self(res.freeze())
+    }
+}
+
+/// Id for the next chunked answer. Starts from a time-based value, so that ids are not
+/// reused right after a restart.
+pub fn next_message_id() -> u32 {
+    static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+    let _ = NEXT_ID.compare_exchange(
+        0,
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |d| d.subsec_nanos() | 1),
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+    );
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_short_answer_not_chunked() {
+        let answer = Answer::from(r#"{"hostname":"dummy"}"#.to_string());
+        assert_eq!(answer.to_datagrams(1, 20).unwrap(), vec![answer.0.clone()]);
+        assert_eq!(Chunk::decode(&answer.0), None);
+    }
+
+    #[test]
+    fn test_chunks_roundtrip() {
+        let answer = Answer::from(format!(r#"{{"key":"{}"}}"#, "x".repeat(100)));
+        let datagrams = answer.to_datagrams(42, 32).unwrap();
+        assert_eq!(datagrams.len(), 6); // 110 bytes, 20 per chunk
+        assert!(datagrams.iter().all(|d| d.len() <= 32));
+        let chunks: Vec<Chunk> = datagrams
+            .iter()
+            .map(|d| Chunk::decode(d).unwrap())
+            .collect();
+        assert!(chunks.iter().all(|c| c.message_id == 42 && c.count == 6));
+        assert_eq!(
+            chunks.iter().map(|c| c.index).collect::<Vec<u16>>(),
+            [0, 1, 2, 3, 4, 5]
+        );
+        assert_eq!(Answer::from_chunks(chunks.iter().map(|c| &c.data)), answer);
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert_eq!(Chunk::decode(b"IPDC"), None);
+        let chunk = Chunk {
+            message_id: 1,
+            index: 2,
+            count: 2,
+            data: Bytes::new(),
+        };
+        assert_eq!(Chunk::decode(&chunk.encode()), None);
+        assert!(Answer::from("an answer longer than a header".to_string())
+            .to_datagrams(1, CHUNK_HEADER_LENGTH)
+            .is_err());
+    }
+
+    #[test]
+    fn test_next_message_id() {
+        assert_ne!(next_message_id(), next_message_id());
+    }
+}
@@ -0,0 +1,44 @@
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Client addresses allowed to get answers. An empty allow list allows everything, the deny
+/// list takes precedence.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientFilter {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl ClientFilter {
+    pub fn allows(&self, ip: &IpAddr) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|n| n.contains(ip)))
+            && !self.deny.iter().any(|n| n.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_allows() {
+        let nets = |n: &[&str]| n.iter().map(|n| n.parse().unwrap()).collect();
+        let allows = |filter: &ClientFilter, ip: &str| filter.allows(&ip.parse().unwrap());
+        assert!(allows(&ClientFilter::default(), "203.0.113.7"));
+        let filter = ClientFilter {
+            allow: nets(&["10.0.0.0/8", "fd00::/8"]),
+            deny: nets(&["10.66.0.0/16"]),
+        };
+        assert!(allows(&filter, "10.1.2.3"));
+        assert!(allows(&filter, "fd12::1"));
+        assert!(!allows(&filter, "10.66.0.1"));
+        assert!(!allows(&filter, "203.0.113.7"));
+        assert!(!allows(&filter, "2001:db8::1"));
+        let filter = ClientFilter {
+            allow: Vec::new(),
+            deny: nets(&["0.0.0.0/0"]),
+        };
+        assert!(!allows(&filter, "10.1.2.3"));
+        assert!(allows(&filter, "fd12::1"));
+    }
+}
@@ -1,22 +1,76 @@
+use crate::announce::AnnounceConfig;
+use crate::auth::{AuthKey, AUTH_WINDOW_DEFAULT};
+use crate::client_filter::ClientFilter;
+use crate::control::ControlConfig;
+use crate::identity::DEVICE_KEY_FILE_DEFAULT;
+use crate::inventory::InventoryFile;
+use crate::profiles::{AccessProfile, FULL_PROFILE_NAME};
+use crate::rate_limit::RateLimitConfig;
+use crate::settings::{
+    AnnounceSettings, ControlSettings, InventoryFileSettings, ProfileSettings, RateLimitSettings,
+    Settings,
+};
 use crate::Signature;
-use color_eyre::eyre::Report;
-use std::fs::File;
+use color_eyre::eyre::{Report, WrapErr};
+use glob::Pattern;
+use std::fs::{self, File};
 use std::io::{self, BufRead, BufReader, Lines};
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
-use tracing::info;
+use std::time::Duration;
+use tracing::{debug, info, warn};
 
 pub const SERVER_PORT_DEFAULT: u16 = 1901;
 pub const SIGNATURE_DEFAULT: &str = "ipdisbeacon"; // must be shorter than RECV_BUFFER_LENGHT
 pub const LISTENING_ADDR_DEFAULT: Ipv4Addr = Ipv4Addr::UNSPECIFIED; // "0.0.0.0"
+pub const INVENTORY_TTL_DEFAULT: Duration = Duration::from_secs(60);
+pub const INTERNAL_INVENTORY_TTL: Duration = Duration::from_secs(10);
+pub const INVENTORY_WORKERS_DEFAULT: usize = 4;
+pub const INVENTORY_DIR_PATTERN_DEFAULT: &str = "mender-inventory-*"; // Mender naming convention
+pub const MULTICAST_V4_ADDR_SUGGESTED: Ipv4Addr = Ipv4Addr::new(239, 255, 19, 1); // organization-local scope
+pub const MULTICAST_V6_ADDR_DEFAULT: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x1901); // link-local scope
+pub const RATE_LIMIT_TIMEOUT_DEFAULT: Duration = Duration::from_secs(10); // do not accept more than a request every 10 s from each IP, after the burst
 
 /// Server configurations.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ServerConfig {
     pub port: u16,
     pub listening_addr: Ipv4Addr,
+    /// Interfaces where requests are accepted, all if empty. They may not exist yet.
+    pub interfaces: Vec<String>,
+    /// IPv4 multicast group joined to receive requests, None to receive only broadcast and
+    /// unicast ones.
+    pub multicast_group: Option<Ipv4Addr>,
+    /// Interfaces where `multicast_group` is joined, `interfaces` or all the multicast capable
+    /// ones if empty.
+    pub multicast_interfaces: Vec<String>,
+    /// IPv6 multicast group joined to receive requests, None if IPv6 is disabled.
+    pub ipv6_group: Option<Ipv6Addr>,
+    /// Interfaces where `ipv6_group` is joined, `interfaces` or all the multicast capable ones
+    /// if empty.
+    pub ipv6_interfaces: Vec<String>,
+    /// Networks of the clients allowed to get answers, checked before anything else.
+    pub client_filter: ClientFilter,
     pub signatures: Vec<Signature>,
-    pub inventory_files: Vec<PathBuf>,
+    pub inventory_files: Vec<InventoryFile>,
+    /// Maximum number of inventory files executed concurrently.
+    pub inventory_workers: usize,
+    pub rate_limit: RateLimitConfig,
+    /// Keys accepted for authenticated requests, any of them is valid.
+    pub auth_keys: Vec<AuthKey>,
+    /// Maximum difference between the timestamp of authenticated requests and the local clock.
+    pub auth_window: Duration,
+    /// Accept plain signatures even if `auth_keys` is not empty.
+    pub allow_unauthenticated: bool,
+    /// Secret key signing the answers, read once at start.
+    pub key_file: PathBuf,
+    /// Inventory subsets, tried in order before `signatures` and `auth_keys`.
+    pub profiles: Vec<AccessProfile>,
+    /// Unsolicited answers to the scanners.
+    pub announce: AnnounceConfig,
+    /// Local control socket, None if disabled.
+    pub control: Option<ControlConfig>,
 }
 
 impl ServerConfig {
@@ -34,20 +88,502 @@ impl ServerConfig {
         Ok(signatures)
     }
 
+    /// Read a sequence of AuthKey from a file, one per line.
+    /// Empty lines are ignored.
+    pub fn parse_auth_keys_file(path: &Path) -> Result<Vec<AuthKey>, Report> {
+        info!(?path, "Reading authentication keys from file.");
+        let mut keys = Vec::new();
+        for line in read_file_lines(path)
+            .wrap_err_with(|| format!("Cannot read authentication keys file {:?}", path))?
+        {
+            match line?.as_str() {
+                "" => continue,
+                s => keys.push(AuthKey::from(s)),
+            };
+        }
+        Ok(keys)
+    }
+
+    /// Resolve the settings: read signatures file, discover inventory files and validate.
+    pub fn from_settings(settings: &Settings) -> Result<Self, Report> {
+        let mut signatures: Vec<Signature> = match &settings.signatures_file {
+            Some(p) => Self::parse_signatures_file(p)?,
+            None => Vec::new(),
+        };
+        signatures.extend(
+            settings
+                .signatures
+                .iter()
+                .map(|s| Signature::from(s.as_str())),
+        );
+        let profiles = settings
+            .profiles
+            .iter()
+            .map(Self::profile_from_settings)
+            .collect::<Result<Vec<AccessProfile>, Report>>()?;
+        for (i, profile) in profiles.iter().enumerate() {
+            if profile.name == FULL_PROFILE_NAME
+                || profiles[..i].iter().any(|p| p.name == profile.name)
+            {
+                return Err(Report::msg(format!(
+                    "Duplicated profile name {:?}",
+                    profile.name
+                )));
+            }
+        }
+        if signatures.is_empty() && profiles.iter().all(|p| p.signatures.is_empty()) {
+            signatures.push(Signature::from(SIGNATURE_DEFAULT));
+        }
+        if let Some(group) = settings.multicast.group {
+            if !group.is_multicast() {
+                return Err(Report::msg(format!(
+                    "multicast.group {} is not a multicast address",
+                    group
+                )));
+            }
+        }
+        let mut auth_keys = match &settings.auth.keys_file {
+            Some(p) => Self::parse_auth_keys_file(p)?,
+            None => Vec::new(),
+        };
+        auth_keys.extend(
+            settings
+                .auth
+                .keys
+                .iter()
+                .map(|k| AuthKey::from(k.0.as_str())),
+        );
+        let ipv6_group = match settings.ipv6.enabled {
+            true if !settings.ipv6.group.is_multicast() => {
+                return Err(Report::msg(format!(
+                    "ipv6.group {} is not a multicast address",
+                    settings.ipv6.group
+                )));
+            }
+            true => Some(settings.ipv6.group),
+            false => None,
+        };
+        let inventory = &settings.inventory;
+        if inventory.workers == 0 {
+            return Err(Report::msg("inventory.workers must be at least 1"));
+        }
+        let pattern = Pattern::new(&inventory.pattern)
+            .wrap_err_with(|| format!("Invalid inventory.pattern {:?}", inventory.pattern))?;
+        let mut file_settings = Vec::new();
+        for dir in inventory.dirs.iter() {
+            for path in Self::discover_inventory_files(dir, &pattern)? {
+                file_settings.push(InventoryFileSettings {
+                    path,
+                    ttl: None,
+                    timeout: None,
+                    max_output: None,
+                });
+            }
+        }
+        file_settings.extend(inventory.files.iter().cloned());
+        let mut inventory_files = Vec::new();
+        for file in file_settings {
+            inventory_files.push(InventoryFile {
+                ttl: Duration::from_secs(file.ttl.unwrap_or(inventory.ttl)),
+                timeout: seconds(
+                    file.timeout.unwrap_or(inventory.timeout),
+                    "inventory timeout",
+                )?,
+                max_output: file.max_output.unwrap_or(inventory.max_output),
+                ..InventoryFile::from(file.path.as_path())
+            });
+        }
+        Ok(Self {
+            port: settings.port,
+            listening_addr: settings.addr,
+            interfaces: settings.interfaces.clone(),
+            multicast_group: settings.multicast.group,
+            multicast_interfaces: settings.multicast.interfaces.clone(),
+            ipv6_group,
+            ipv6_interfaces: settings.ipv6.interfaces.clone(),
+            client_filter: ClientFilter {
+                allow: settings.clients.allow.clone(),
+                deny: settings.clients.deny.clone(),
+            },
+            signatures,
+            inventory_files,
+            inventory_workers: inventory.workers,
+            rate_limit: Self::rate_limit_from_settings(&settings.rate_limit)?,
+            auth_keys,
+            auth_window: seconds(settings.auth.window, "auth.window")?,
+            allow_unauthenticated: settings.auth.allow_unauthenticated,
+            key_file: settings.key_file.clone(),
+            announce: Self::announce_from_settings(&settings.announce, &profiles)?,
+            control: Self::control_from_settings(&settings.control)?,
+            profiles,
+        })
+    }
+
+    fn control_from_settings(settings: &ControlSettings) -> Result<Option<ControlConfig>, Report> {
+        if !settings.enabled {
+            return Ok(None);
+        }
+        if settings.mode > 0o777 {
+            return Err(Report::msg(format!(
+                "Invalid control.mode: {:o}",
+                settings.mode
+            )));
+        }
+        Ok(Some(ControlConfig {
+            path: settings.path.clone(),
+            mode: settings.mode,
+        }))
+    }
+
+    fn announce_from_settings(
+        settings: &AnnounceSettings,
+        profiles: &[AccessProfile],
+    ) -> Result<AnnounceConfig, Report> {
+        if settings.profile != FULL_PROFILE_NAME
+            && !profiles.iter().any(|p| p.name == settings.profile)
+        {
+            return Err(Report::msg(format!(
+                "Unknown announce.profile {:?}",
+                settings.profile
+            )));
+        }
+        let interval = seconds(settings.interval, "announce.interval")?;
+        Ok(AnnounceConfig {
+            addrs: settings.addrs.clone(),
+            port: settings.port,
+            interval: (!interval.is_zero()).then_some(interval),
+            profile: settings.profile.clone(),
+        })
+    }
+
+    fn rate_limit_from_settings(settings: &RateLimitSettings) -> Result<RateLimitConfig, Report> {
+        if settings.burst == 0 {
+            return Err(Report::msg("rate_limit.burst must be at least 1"));
+        }
+        if settings.ipv4_prefix > 32 || settings.ipv6_prefix > 128 {
+            return Err(Report::msg(format!(
+                "Invalid rate_limit prefix: /{} for IPv4, /{} for IPv6",
+                settings.ipv4_prefix, settings.ipv6_prefix
+            )));
+        }
+        if settings.max_clients == 0 {
+            return Err(Report::msg("rate_limit.max_clients must be at least 1"));
+        }
+        Ok(RateLimitConfig {
+            interval: seconds(settings.timeout, "rate_limit.timeout")?,
+            burst: settings.burst,
+            ipv4_prefix: settings.ipv4_prefix,
+            ipv6_prefix: settings.ipv6_prefix,
+            global_rate: settings.global_rate,
+            max_clients: settings.max_clients,
+        })
+    }
+
+    fn profile_from_settings(settings: &ProfileSettings) -> Result<AccessProfile, Report> {
+        if settings.name.is_empty() {
+            return Err(Report::msg("Profile without name"));
+        }
+        let patterns = |patterns: &[String]| -> Result<Vec<Pattern>, Report> {
+            patterns
+                .iter()
+                .map(|p| {
+                    Pattern::new(p).wrap_err_with(|| {
+                        format!("Invalid pattern {:?} in profile {:?}", p, settings.name)
+                    })
+                })
+                .collect()
+        };
+        Ok(AccessProfile {
+            name: settings.name.clone(),
+            signatures: settings
+                .signatures
+                .iter()
+                .map(|s| Signature::from(s.as_str()))
+                .collect(),
+            auth_keys: settings
+                .auth_keys
+                .iter()
+                .map(|k| AuthKey::from(k.0.as_str()))
+                .collect(),
+            allow_keys: patterns(&settings.allow_keys)?,
+            deny_keys: patterns(&settings.deny_keys)?,
+            allow_sources: patterns(&settings.allow_sources)?,
+            deny_sources: patterns(&settings.deny_sources)?,
+        })
+    }
+
+    /// The profiles, followed by the one answering everything to `signatures` and `auth_keys`.
+    pub fn access_profiles(&self) -> Vec<AccessProfile> {
+        let mut res = self.profiles.clone();
+        res.push(AccessProfile::full(
+            self.signatures.clone(),
+            self.auth_keys.clone(),
+        ));
+        res
+    }
+
+    /// Keys accepted for authenticated requests, of any profile.
+    pub fn all_auth_keys(&self) -> Vec<AuthKey> {
+        let mut res = self.auth_keys.clone();
+        for profile in self.profiles.iter() {
+            res.extend(profile.auth_keys.iter().cloned());
+        }
+        res
+    }
+
+    /// List the executable files in `dir` whose name matches the glob `pattern`,
+    /// sorted by name. A missing directory is not an error.
+    pub fn discover_inventory_files(dir: &Path, pattern: &Pattern) -> Result<Vec<PathBuf>, Report> {
+        info!(?dir, %pattern, "Looking for inventory files.");
+        let entries = match fs::read_dir(dir) {
+            Ok(e) => e,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                warn!(?dir, "Inventory directory not found.");
+                return Ok(Vec::new());
+            }
+            Err(error) => {
+                return Err(error).wrap_err_with(|| format!("Cannot read {:?}", dir));
+            }
+        };
+        let mut files = Vec::new();
+        for entry in entries {
+            let path = match entry {
+                Ok(e) => e.path(),
+                Err(error) => {
+                    warn!(
+                        ?error,
+                        ?dir,
+                        "Cannot read inventory directory entry, ignored."
+                    );
+                    continue;
+                }
+            };
+            let matches = path
+                .file_name()
+                .is_some_and(|n| pattern.matches(&n.to_string_lossy()));
+            if !matches {
+                continue;
+            }
+            let metadata = match fs::metadata(&path) {
+                Ok(m) => m, // symlinks followed
+                Err(error) => {
+                    warn!(
+                        ?error,
+                        ?path,
+                        "Cannot read inventory file metadata, ignored."
+                    );
+                    continue;
+                }
+            };
+            if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+                debug!(?path, "Not an executable file, ignored.");
+                continue;
+            }
+            files.push(path);
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Human readable list of the differences between `self` and `new`.
+    pub fn diff(&self, new: &Self) -> Vec<String> {
+        let mut res = Vec::new();
+        if self.port != new.port {
+            res.push(format!("port: {} -> {}", self.port, new.port));
+        }
+        if self.listening_addr != new.listening_addr {
+            res.push(format!(
+                "listening address: {} -> {}",
+                self.listening_addr, new.listening_addr
+            ));
+        }
+        if self.interfaces != new.interfaces {
+            res.push(format!(
+                "interfaces: {:?} -> {:?}",
+                self.interfaces, new.interfaces
+            ));
+        }
+        if self.multicast_group != new.multicast_group {
+            let show = |g: Option<Ipv4Addr>| g.map_or("disabled".into(), |g| g.to_string());
+            res.push(format!(
+                "multicast group: {} -> {}",
+                show(self.multicast_group),
+                show(new.multicast_group)
+            ));
+        }
+        if self.multicast_interfaces != new.multicast_interfaces {
+            res.push(format!(
+                "multicast interfaces: {:?} -> {:?}",
+                self.multicast_interfaces, new.multicast_interfaces
+            ));
+        }
+        if self.ipv6_group != new.ipv6_group {
+            let show = |g: Option<Ipv6Addr>| g.map_or("disabled".into(), |g| g.to_string());
+            res.push(format!(
+                "IPv6 group: {} -> {}",
+                show(self.ipv6_group),
+                show(new.ipv6_group)
+            ));
+        }
+        if self.ipv6_interfaces != new.ipv6_interfaces {
+            res.push(format!(
+                "IPv6 interfaces: {:?} -> {:?}",
+                self.ipv6_interfaces, new.ipv6_interfaces
+            ));
+        }
+        if self.client_filter.allow != new.client_filter.allow {
+            res.push(format!(
+                "allowed clients: {:?} -> {:?}",
+                self.client_filter.allow, new.client_filter.allow
+            ));
+        }
+        if self.client_filter.deny != new.client_filter.deny {
+            res.push(format!(
+                "denied clients: {:?} -> {:?}",
+                self.client_filter.deny, new.client_filter.deny
+            ));
+        }
+        for signature in self.signatures.iter() {
+            if !new.signatures.contains(signature) {
+                res.push(format!("signature removed: {}", signature));
+            }
+        }
+        for signature in new.signatures.iter() {
+            if !self.signatures.contains(signature) {
+                res.push(format!("signature added: {}", signature));
+            }
+        }
+        let changes_before_files = res.len();
+        for file in self.inventory_files.iter() {
+            match new.inventory_files.iter().find(|f| f.path == file.path) {
+                None => res.push(format!("inventory file removed: {:?}", file.path)),
+                Some(f) if f != file => {
+                    res.push(format!("inventory file changed: {:?} -> {:?}", file, f))
+                }
+                Some(_) => (),
+            }
+        }
+        for file in new.inventory_files.iter() {
+            if !self.inventory_files.iter().any(|f| f.path == file.path) {
+                res.push(format!("inventory file added: {:?}", file.path));
+            }
+        }
+        if res.len() == changes_before_files && self.inventory_files != new.inventory_files {
+            res.push("inventory files order changed".into());
+        }
+        if self.inventory_workers != new.inventory_workers {
+            res.push(format!(
+                "inventory workers: {} -> {}",
+                self.inventory_workers, new.inventory_workers
+            ));
+        }
+        if self.rate_limit != new.rate_limit {
+            res.push(format!(
+                "rate limit: {:?} -> {:?}",
+                self.rate_limit, new.rate_limit
+            ));
+        }
+        if self.auth_keys != new.auth_keys {
+            // Keys are secrets, never logged.
+            res.push(format!(
+                "authentication keys changed: {} -> {} keys",
+                self.auth_keys.len(),
+                new.auth_keys.len()
+            ));
+        }
+        if self.auth_window != new.auth_window {
+            res.push(format!(
+                "authentication window: {:?} -> {:?}",
+                self.auth_window, new.auth_window
+            ));
+        }
+        if self.allow_unauthenticated != new.allow_unauthenticated {
+            res.push(format!(
+                "unauthenticated requests allowed: {} -> {}",
+                self.allow_unauthenticated, new.allow_unauthenticated
+            ));
+        }
+        if self.key_file != new.key_file {
+            res.push(format!(
+                "device key file, used after a restart: {:?} -> {:?}",
+                self.key_file, new.key_file
+            ));
+        }
+        for profile in self.profiles.iter() {
+            match new.profiles.iter().find(|p| p.name == profile.name) {
+                None => res.push(format!("profile removed: {}", profile.name)),
+                Some(p) if p != profile => res.push(format!("profile changed: {}", profile.name)),
+                Some(_) => (),
+            }
+        }
+        for profile in new.profiles.iter() {
+            if !self.profiles.iter().any(|p| p.name == profile.name) {
+                res.push(format!("profile added: {}", profile.name));
+            }
+        }
+        if self.announce != new.announce {
+            res.push(format!(
+                "announcements: {:?} -> {:?}",
+                self.announce, new.announce
+            ));
+        }
+        if self.control != new.control {
+            res.push(format!(
+                "control socket: {:?} -> {:?}",
+                self.control, new.control
+            ));
+        }
+        res
+    }
+
     pub fn dummy() -> Self {
         let port = SERVER_PORT_DEFAULT;
         let listening_addr = LISTENING_ADDR_DEFAULT;
+        let interfaces = Vec::new();
+        let multicast_group = None;
+        let multicast_interfaces = Vec::new();
+        let ipv6_group = None;
+        let ipv6_interfaces = Vec::new();
+        let client_filter = ClientFilter::default();
         let signatures = vec![SIGNATURE_DEFAULT.into()];
         let inventory_files = Vec::new();
+        let inventory_workers = INVENTORY_WORKERS_DEFAULT;
+        let rate_limit = RateLimitConfig::default();
+        let auth_keys = Vec::new();
+        let auth_window = AUTH_WINDOW_DEFAULT;
+        let allow_unauthenticated = false;
+        let key_file = PathBuf::from(DEVICE_KEY_FILE_DEFAULT);
+        let profiles = Vec::new();
+        let announce = AnnounceConfig::default();
+        let control = None;
         Self {
             port,
             listening_addr,
+            interfaces,
+            multicast_group,
+            multicast_interfaces,
+            ipv6_group,
+            ipv6_interfaces,
+            client_filter,
             signatures,
             inventory_files,
+            inventory_workers,
+            rate_limit,
+            auth_keys,
+            auth_window,
+            allow_unauthenticated,
+            key_file,
+            profiles,
+            announce,
+            control,
         }
     }
 }
 
+fn seconds(value: f64, name: &str) -> Result<Duration, Report> {
+    Duration::try_from_secs_f64(value).wrap_err_with(|| format!("Invalid {}: {}", name, value))
+}
+
 /// Returns an Iterator to the Reader of the lines of the file.
 /// The output is wrapped in a Result to allow matching on errors
 fn read_file_lines<P>(filename: P) -> io::Result<Lines<BufReader<File>>>
@@ -91,5 +627,163 @@ mod test {
                 Signature::from("sign line 2")
             ]
         );
+        assert_eq!(
+            ServerConfig::parse_auth_keys_file(&sign_file_path).unwrap(),
+            vec![AuthKey::from("TestSignature"), AuthKey::from("sign line 2")]
+        );
+        assert!(ServerConfig::parse_auth_keys_file(&datadir.join("missing")).is_err());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_discover_inventory_files() {
+        let datadir = std::env::temp_dir()
+            .as_path()
+            .join("rust-ipdisserver-test-conf-inventory-dir/");
+        let _ = fs::remove_dir_all(&datadir);
+        fs::create_dir(&datadir).unwrap();
+        fs::create_dir(datadir.join("mender-inventory-dir")).unwrap();
+        for (name, mode) in [
+            ("mender-inventory-b", 0o755),
+            ("mender-inventory-a", 0o700),
+            ("mender-inventory-not-executable", 0o644),
+            ("other-inventory", 0o755),
+        ] {
+            let path = datadir.join(name);
+            fs::write(&path, "#!/bin/sh\necho").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        }
+        std::os::unix::fs::symlink(
+            datadir.join("missing"),
+            datadir.join("mender-inventory-dangling"),
+        )
+        .unwrap();
+        let pattern = Pattern::new(INVENTORY_DIR_PATTERN_DEFAULT).unwrap();
+        assert_eq!(
+            ServerConfig::discover_inventory_files(&datadir, &pattern).unwrap(),
+            vec![
+                datadir.join("mender-inventory-a"),
+                datadir.join("mender-inventory-b")
+            ]
+        );
+        assert!(logs_contain("Cannot read inventory file metadata"));
+        assert_eq!(
+            ServerConfig::discover_inventory_files(&datadir.join("missing"), &pattern).unwrap(),
+            Vec::<PathBuf>::new()
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_from_settings() {
+        let mut settings = Settings::default();
+        settings.inventory.ttl = 30;
+        settings.inventory.files = vec![
+            "/usr/bin/inventory-fw:300".parse().unwrap(),
+            "/usr/bin/inventory-disk".parse().unwrap(),
+        ];
+        let conf = ServerConfig::from_settings(&settings).unwrap();
+        assert_eq!(conf.signatures, vec![Signature::from(SIGNATURE_DEFAULT)]);
+        assert_eq!(conf.ipv6_group, Some(MULTICAST_V6_ADDR_DEFAULT));
+        let ttls: Vec<Duration> = conf.inventory_files.iter().map(|f| f.ttl).collect();
+        assert_eq!(ttls, [Duration::from_secs(300), Duration::from_secs(30)]);
+
+        settings.signatures = vec!["custom".into()];
+        let new_conf = ServerConfig::from_settings(&settings).unwrap();
+        assert_eq!(new_conf.signatures, vec![Signature::from("custom")]);
+        assert_eq!(new_conf.key_file, PathBuf::from(DEVICE_KEY_FILE_DEFAULT)); // not read
+
+        for timeout in [-1.0, f64::NAN, f64::INFINITY] {
+            settings.inventory.timeout = timeout;
+            assert!(ServerConfig::from_settings(&settings).is_err());
+        }
+        settings.inventory.timeout = 1.0;
+        settings.inventory.files[0].timeout = Some(f64::NAN);
+        assert!(ServerConfig::from_settings(&settings).is_err());
+        settings.inventory.files[0].timeout = None;
+        settings.inventory.workers = 0;
+        assert!(ServerConfig::from_settings(&settings).is_err());
+        settings.inventory.workers = 1;
+        settings.rate_limit.timeout = -1.0;
+        assert!(ServerConfig::from_settings(&settings).is_err());
+        settings.rate_limit.timeout = 1.0;
+        settings.rate_limit.burst = 0;
+        assert!(ServerConfig::from_settings(&settings).is_err());
+        settings.rate_limit.burst = 1;
+        settings.rate_limit.ipv4_prefix = 33;
+        assert!(ServerConfig::from_settings(&settings).is_err());
+        settings.rate_limit.ipv4_prefix = 24;
+        settings.multicast.group = Some(Ipv4Addr::new(192, 168, 1, 1));
+        assert!(ServerConfig::from_settings(&settings).is_err());
+        settings.multicast.group = Some(MULTICAST_V4_ADDR_SUGGESTED);
+        assert!(ServerConfig::from_settings(&settings).is_ok());
+        settings.ipv6.group = "fe80::1".parse().unwrap();
+        assert!(ServerConfig::from_settings(&settings).is_err());
+        settings.ipv6.enabled = false;
+        assert_eq!(
+            ServerConfig::from_settings(&settings).unwrap().ipv6_group,
+            None
+        );
+        settings.announce.interval = 0.0;
+        assert_eq!(
+            ServerConfig::from_settings(&settings)
+                .unwrap()
+                .announce
+                .interval,
+            None
+        );
+        settings.announce.profile = "missing".into();
+        assert!(ServerConfig::from_settings(&settings).is_err());
+        settings.announce.profile = "full".into();
+        settings.control.mode = 0o1777;
+        assert!(ServerConfig::from_settings(&settings).is_err());
+        settings.control.enabled = false;
+        assert_eq!(
+            ServerConfig::from_settings(&settings).unwrap().control,
+            None
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = ServerConfig::dummy();
+        assert!(old.diff(&old).is_empty());
+        let mut new = old.clone();
+        new.port = 1234;
+        new.multicast_group = Some(MULTICAST_V4_ADDR_SUGGESTED);
+        new.client_filter.deny = vec!["192.0.2.0/24".parse().unwrap()];
+        new.signatures = vec![Signature::from("new-signature")];
+        new.inventory_files = vec![InventoryFile::from(Path::new("/usr/bin/inventory"))];
+        new.auth_keys = vec![AuthKey::from("secret")];
+        new.profiles = vec![AccessProfile {
+            name: "public".into(),
+            ..AccessProfile::full(Vec::new(), Vec::new())
+        }];
+        assert_eq!(
+            old.diff(&new),
+            vec![
+                "port: 1901 -> 1234",
+                "multicast group: disabled -> 239.255.19.1",
+                "denied clients: [] -> [192.0.2.0/24]",
+                "signature removed: ipdisbeacon",
+                "signature added: new-signature",
+                "inventory file added: \"/usr/bin/inventory\"",
+                "authentication keys changed: 0 -> 1 keys",
+                "profile added: public",
+            ]
+        );
+        assert_eq!(
+            new.diff(&old),
+            vec![
+                "port: 1234 -> 1901",
+                "multicast group: 239.255.19.1 -> disabled",
+                "denied clients: [192.0.2.0/24] -> []",
+                "signature removed: new-signature",
+                "signature added: ipdisbeacon",
+                "inventory file removed: \"/usr/bin/inventory\"",
+                "authentication keys changed: 1 -> 0 keys",
+                "profile removed: public",
+            ]
+        );
     }
 }
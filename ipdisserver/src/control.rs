@@ -0,0 +1,239 @@
+//! Local control socket: one command line per connection, answered with a status line
+//! (`OK` or `ERR message`) followed by the result.
+
+use color_eyre::eyre::{Report, WrapErr};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+pub const CONTROL_SOCKET_DEFAULT: &str = "/run/ipdisserver/control.sock";
+pub const CONTROL_MODE_DEFAULT: u32 = 0o600; // owner only
+const COMMAND_MAX_LENGTH: u64 = 256;
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1); // max delay a client can hold the server
+
+/// Where the control socket is created, and who can use it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlConfig {
+    pub path: PathBuf,
+    /// Permissions of the socket file, e.g. 0o660 to allow a group.
+    pub mode: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlCommand {
+    /// Answer currently served to the profile, `full` if None.
+    ShowAnswer(Option<String>),
+    Stats,
+    Reload,
+    FlushCache,
+}
+
+impl fmt::Display for ControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ShowAnswer(None) => write!(f, "show-answer"),
+            Self::ShowAnswer(Some(profile)) => write!(f, "show-answer {}", profile),
+            Self::Stats => write!(f, "stats"),
+            Self::Reload => write!(f, "reload"),
+            Self::FlushCache => write!(f, "flush-cache"),
+        }
+    }
+}
+
+impl ControlCommand {
+    pub fn parse(line: &str) -> Result<Self, Report> {
+        let mut words = line.split_whitespace();
+        let command = match (words.next(), words.next()) {
+            (Some("show-answer"), profile) => Self::ShowAnswer(profile.map(str::to_string)),
+            (Some("stats"), None) => Self::Stats,
+            (Some("reload"), None) => Self::Reload,
+            (Some("flush-cache"), None) => Self::FlushCache,
+            _ => return Err(Report::msg(format!("Unknown command {:?}", line.trim()))),
+        };
+        match words.next() {
+            None => Ok(command),
+            Some(_) => Err(Report::msg(format!("Unknown command {:?}", line.trim()))),
+        }
+    }
+}
+
+/// Listening control socket, removed when dropped.
+#[derive(Debug)]
+pub struct ControlSocket {
+    pub listener: UnixListener,
+    path: PathBuf,
+    /// Device and inode of the socket file, not to remove the one of a newer server.
+    file_id: (u64, u64),
+}
+
+impl ControlSocket {
+    /// Create the socket with the configured permissions. It is bound under a temporary name
+    /// and renamed once restricted, so that it is never reachable with looser permissions.
+    /// A socket left by a previous run is replaced.
+    pub fn bind(conf: &ControlConfig) -> Result<Self, Report> {
+        let tmp_path = conf.path.with_extension("tmp");
+        remove_socket(&tmp_path)?;
+        let listener = UnixListener::bind(&tmp_path)
+            .wrap_err_with(|| format!("Cannot create control socket {:?}", tmp_path))?;
+        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(conf.mode))?;
+        remove_socket(&conf.path)?;
+        fs::rename(&tmp_path, &conf.path)?;
+        listener.set_nonblocking(true)?;
+        let metadata = fs::metadata(&conf.path)?;
+        info!(path = ?conf.path, mode = format!("{:o}", conf.mode), "Control socket ready.");
+        Ok(Self {
+            listener,
+            path: conf.path.clone(),
+            file_id: (metadata.dev(), metadata.ino()),
+        })
+    }
+
+    /// Next client waiting with its command, None if there is none.
+    pub fn accept(&self) -> Option<(UnixStream, Result<ControlCommand, Report>)> {
+        let mut stream = match self.listener.accept() {
+            Ok((s, _)) => s,
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => return None,
+            Err(error) => {
+                warn!(?error, "Cannot accept control client.");
+                return None;
+            }
+        };
+        let command = read_command(&mut stream);
+        debug!(?command, "Control command received.");
+        Some((stream, command))
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let is_own =
+            fs::symlink_metadata(&self.path).is_ok_and(|m| (m.dev(), m.ino()) == self.file_id);
+        if !is_own {
+            return;
+        }
+        if let Err(error) = fs::remove_file(&self.path) {
+            debug!(?error, path = ?self.path, "Cannot remove control socket.");
+        }
+    }
+}
+
+/// Remove a socket file, refusing to remove anything else.
+fn remove_socket(path: &Path) -> Result<(), Report> {
+    match fs::symlink_metadata(path) {
+        Ok(m) if m.file_type().is_socket() => Ok(fs::remove_file(path)?),
+        Ok(_) => Err(Report::msg(format!(
+            "{:?} exists and is not a socket",
+            path
+        ))),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(error.into()),
+    }
+}
+
+fn read_command(stream: &mut UnixStream) -> Result<ControlCommand, Report> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(stream.take(COMMAND_MAX_LENGTH)).read_line(&mut line)?;
+    ControlCommand::parse(&line)
+}
+
+/// Send the result, or the error, to the client.
+pub fn reply(mut stream: UnixStream, result: Result<String, Report>) {
+    let text = match result {
+        Ok(body) => format!("OK\n{}", body),
+        Err(error) => format!("ERR {}\n", error),
+    };
+    if let Err(error) = stream.write_all(text.as_bytes()) {
+        warn!(?error, "Cannot reply to control client.");
+    }
+}
+
+/// Send a command to the server listening on `path`, return the result.
+pub fn send_command(path: &Path, command: &ControlCommand) -> Result<String, Report> {
+    let mut stream = UnixStream::connect(path)
+        .wrap_err_with(|| format!("Cannot connect to control socket {:?}", path))?;
+    stream.write_all(format!("{}\n", command).as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    match response.split_once('\n') {
+        Some(("OK", body)) => Ok(body.to_string()),
+        Some((status, _)) if status.starts_with("ERR ") => {
+            Err(Report::msg(status["ERR ".len()..].to_string()))
+        }
+        _ => Err(Report::msg(format!("Invalid response {:?}", response))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_parse() {
+        for command in [
+            ControlCommand::ShowAnswer(None),
+            ControlCommand::ShowAnswer(Some("public".into())),
+            ControlCommand::Stats,
+            ControlCommand::Reload,
+            ControlCommand::FlushCache,
+        ] {
+            assert_eq!(
+                ControlCommand::parse(&format!("{}\n", command)).unwrap(),
+                command
+            );
+        }
+        assert!(ControlCommand::parse("stats now").is_err());
+        assert!(ControlCommand::parse("").is_err());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_control_socket() {
+        let dir = std::env::temp_dir().join("rust-ipdisserver-test-control");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let conf = ControlConfig {
+            path: dir.join("control.sock"),
+            mode: CONTROL_MODE_DEFAULT,
+        };
+        let stale = ControlSocket::bind(&conf).unwrap();
+        let control = ControlSocket::bind(&conf).unwrap(); // replaces the stale one
+        drop(stale);
+        assert!(conf.path.exists());
+        let mode = fs::metadata(&conf.path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(control.accept().is_none());
+        let path = conf.path.clone();
+        let client = thread::spawn(move || {
+            (
+                send_command(&path, &ControlCommand::Stats),
+                send_command(&path, &ControlCommand::Reload),
+            )
+        });
+        for result in [Ok("{}\n".to_string()), Err(Report::msg("failed"))] {
+            let (stream, command) = loop {
+                match control.accept() {
+                    Some(accepted) => break accepted,
+                    None => thread::sleep(Duration::from_millis(10)),
+                }
+            };
+            assert!(command.is_ok());
+            reply(stream, result);
+        }
+        let (stats, reload) = client.join().unwrap();
+        assert_eq!(stats.unwrap(), "{}\n");
+        assert_eq!(reload.unwrap_err().to_string(), "failed");
+        drop(control);
+        assert!(!conf.path.exists());
+        fs::write(&conf.path, "").unwrap();
+        assert!(ControlSocket::bind(&conf).is_err()); // not a socket, kept
+    }
+}
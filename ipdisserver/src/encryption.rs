@@ -0,0 +1,154 @@
+use crate::answers::Answer;
+use bytes::{BufMut, Bytes, BytesMut};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use color_eyre::eyre::Report;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::fmt;
+use thiserror::Error;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Start of encrypted answers, followed by the server ephemeral public key, the nonce
+/// and the ciphertext.
+pub const ENCRYPTED_ANSWER_MAGIC: &[u8; 4] = b"IPDX";
+const ENCRYPTED_ANSWER_HEADER_LENGTH: usize =
+    ENCRYPTED_ANSWER_MAGIC.len() + KEY_LENGTH + NONCE_LENGTH;
+const KEY_LENGTH: usize = 32; // X25519
+const NONCE_LENGTH: usize = 12;
+const KDF_INFO: &[u8] = b"ipdis answer encryption v1";
+
+/// Reason why an encrypted answer cannot be read.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum DecryptionError {
+    #[error("encrypted answer too short")]
+    TooShort,
+    #[error("authentication tag not matching, answer not encrypted for this scanner or corrupted")]
+    BadTag,
+}
+
+/// Scanner side X25519 key, generated at each start and sent in the requests.
+#[derive(Clone)]
+pub struct AnswerKey(StaticSecret);
+
+impl fmt::Debug for AnswerKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AnswerKey({:?})", self.public_key().as_bytes()) // never log the secret key
+    }
+}
+
+impl PartialEq for AnswerKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.public_key() == other.public_key()
+    }
+}
+
+impl AnswerKey {
+    pub fn generate() -> Result<Self, Report> {
+        let mut secret = [0; KEY_LENGTH];
+        getrandom::getrandom(&mut secret)
+            .map_err(|e| Report::msg(format!("Cannot generate answer key: {}", e)))?;
+        Ok(Self(StaticSecret::from(secret)))
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from(&self.0)
+    }
+
+    /// Decrypt an answer encrypted to this key.
+    /// Answers that are not encrypted are returned as they are, None.
+    pub fn decrypt(&self, answer: &Answer) -> Result<Option<Answer>, DecryptionError> {
+        if !answer.0.starts_with(ENCRYPTED_ANSWER_MAGIC) {
+            return Ok(None);
+        }
+        if answer.0.len() < ENCRYPTED_ANSWER_HEADER_LENGTH {
+            return Err(DecryptionError::TooShort);
+        }
+        let key_end = ENCRYPTED_ANSWER_MAGIC.len() + KEY_LENGTH;
+        let server_key: [u8; KEY_LENGTH] = answer.0[ENCRYPTED_ANSWER_MAGIC.len()..key_end]
+            .try_into()
+            .expect("slice length is KEY_LENGTH");
+        let server_key = PublicKey::from(server_key);
+        let nonce = Nonce::from_slice(&answer.0[key_end..ENCRYPTED_ANSWER_HEADER_LENGTH]);
+        let cipher = new_cipher(
+            self.0.diffie_hellman(&server_key).as_bytes(),
+            &self.public_key(),
+            &server_key,
+        );
+        cipher
+            .decrypt(nonce, &answer.0[ENCRYPTED_ANSWER_HEADER_LENGTH..])
+            .map(|plain| Some(Answer(Bytes::from(plain))))
+            .map_err(|_| DecryptionError::BadTag)
+    }
+}
+
+/// Encrypt the answer to the scanner key, with a new server key for each answer.
+pub fn encrypt_answer(answer: &Answer, scanner_key: &PublicKey) -> Result<Answer, Report> {
+    let ephemeral = AnswerKey::generate()?;
+    let server_key = ephemeral.public_key();
+    let cipher = new_cipher(
+        ephemeral.0.diffie_hellman(scanner_key).as_bytes(),
+        scanner_key,
+        &server_key,
+    );
+    let mut nonce = [0; NONCE_LENGTH];
+    getrandom::getrandom(&mut nonce)
+        .map_err(|e| Report::msg(format!("Cannot generate nonce: {}", e)))?;
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), &answer.0[..])
+        .map_err(|_| Report::msg("Cannot encrypt answer"))?;
+    let mut res = BytesMut::with_capacity(ENCRYPTED_ANSWER_HEADER_LENGTH + ciphertext.len());
+    res.put_slice(ENCRYPTED_ANSWER_MAGIC);
+    res.put_slice(server_key.as_bytes());
+    res.put_slice(&nonce);
+    res.put_slice(&ciphertext);
+    Ok(Answer(res.freeze()))
+}
+
+/// ChaCha20-Poly1305 keyed with the shared secret, bound to both public keys.
+fn new_cipher(
+    shared_secret: &[u8; KEY_LENGTH],
+    scanner_key: &PublicKey,
+    server_key: &PublicKey,
+) -> ChaCha20Poly1305 {
+    let mut info = Vec::with_capacity(KDF_INFO.len() + 2 * KEY_LENGTH);
+    info.extend_from_slice(KDF_INFO);
+    info.extend_from_slice(scanner_key.as_bytes());
+    info.extend_from_slice(server_key.as_bytes());
+    let mut key = Key::default();
+    Hkdf::<Sha256>::new(None, shared_secret)
+        .expand(&info, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    ChaCha20Poly1305::new(&key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_and_decrypt() {
+        let scanner_key = AnswerKey::generate().unwrap();
+        let answer = Answer::from(r#"{"serial":"0123456789"}"#.to_string());
+        let encrypted = encrypt_answer(&answer, &scanner_key.public_key()).unwrap();
+        assert!(!encrypted
+            .0
+            .windows(b"0123456789".len())
+            .any(|w| w == b"0123456789"));
+        assert_eq!(scanner_key.decrypt(&encrypted), Ok(Some(answer.clone())));
+        assert_eq!(scanner_key.decrypt(&answer), Ok(None)); // not encrypted
+
+        let other_key = AnswerKey::generate().unwrap();
+        assert_eq!(other_key.decrypt(&encrypted), Err(DecryptionError::BadTag));
+        let mut corrupted = encrypted.0.to_vec();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            scanner_key.decrypt(&Answer::from(corrupted.as_slice())),
+            Err(DecryptionError::BadTag)
+        );
+        assert_eq!(
+            scanner_key.decrypt(&Answer::from(&b"IPDX short"[..])),
+            Err(DecryptionError::TooShort)
+        );
+    }
+}
@@ -1,9 +1,37 @@
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use std::io::Read;
+use std::os::unix::process::CommandExt;
 use std::path::Path;
-use std::process::Command;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+use thiserror::Error;
 use tracing::{error, warn};
 
+pub const TIMEOUT_DEFAULT: Duration = Duration::from_secs(10);
+pub const MAX_OUTPUT_DEFAULT: usize = 2usize.pow(16); // 64KiB
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(1); // between SIGTERM and SIGKILL
+const WAIT_POLL_PERIOD: Duration = Duration::from_millis(10);
+
+/// Reason of an inventory file execution failure.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum InventoryError {
+    #[error("cannot execute: {0}")]
+    Spawn(String),
+    #[error("killed after {0:?} timeout")]
+    Timeout(Duration),
+    #[error("stdout longer than {0} bytes")]
+    OutputTooLarge(usize),
+    #[error("unsuccessful termination: {0}")]
+    ExitStatus(String),
+}
+
 pub struct InventoryCommand {
     cmd: Command,
+    timeout: Duration,
+    max_output: usize,
 }
 
 impl InventoryCommand {
@@ -11,30 +39,216 @@ impl InventoryCommand {
     where
         P: AsRef<Path>,
     {
-        let cmd = Command::new(path.as_ref());
-        Self { cmd }
+        let mut cmd = Command::new(path.as_ref());
+        cmd.process_group(0) // to kill the whole group on timeout
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        Self {
+            cmd,
+            timeout: TIMEOUT_DEFAULT,
+            max_output: MAX_OUTPUT_DEFAULT,
+        }
+    }
+
+    /// Terminate the process group if the execution lasts longer than `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
     }
 
-    /// Return raw output string, an empty string if the execution is not successful.
-    pub fn output(&mut self) -> String {
-        match self.cmd.output() {
-            Ok(output) => {
-                if !output.stderr.is_empty() {
-                    // warn!(?self.cmd, stderr = String::from_utf8_lossy(&output.stderr), "Inventory file wrote on stderr.");
-                    warn!(?self.cmd, ?output.stderr, "Inventory file wrote on stderr.");
+    /// Maximum captured length of stdout and stderr, in bytes.
+    pub fn max_output(mut self, max_output: usize) -> Self {
+        self.max_output = max_output;
+        self
+    }
+
+    /// Return raw output string, or the reason why the execution is not successful.
+    pub fn output(&mut self) -> Result<String, InventoryError> {
+        let deadline = Instant::now() + self.timeout;
+        let mut child = self.cmd.spawn().map_err(|e| {
+            error!(?self.cmd, error = ?e, "Failed executing inventory file.");
+            InventoryError::Spawn(e.to_string())
+        })?;
+        let stdout = spawn_reader(child.stdout.take(), self.max_output);
+        let stderr = spawn_reader(child.stderr.take(), self.max_output);
+        let captured = wait_until(&mut child, deadline).and_then(|status| {
+            // Pipes may be kept open by background processes after the main one exits.
+            let stdout = stdout.recv_timeout(remaining(deadline)).ok()?;
+            let stderr = stderr.recv_timeout(remaining(deadline)).ok()?;
+            Some((status, stdout, stderr))
+        });
+        let (status, stdout, stderr) = match captured {
+            Some(c) => c,
+            None => {
+                error!(?self.cmd, ?self.timeout, "Inventory file timed out, terminating.");
+                terminate(&mut child);
+                return Err(InventoryError::Timeout(self.timeout));
+            }
+        };
+        if !stderr.data.is_empty() {
+            warn!(
+                ?self.cmd,
+                stderr = %String::from_utf8_lossy(&stderr.data),
+                truncated = stderr.truncated,
+                "Inventory file wrote on stderr."
+            );
+        }
+        if !status.success() {
+            error!(?self.cmd, ?status, "Inventory file: non-0 exit code.");
+            return Err(InventoryError::ExitStatus(status.to_string()));
+        }
+        if stdout.truncated {
+            error!(?self.cmd, %self.max_output, "Inventory file: output too large.");
+            return Err(InventoryError::OutputTooLarge(self.max_output));
+        }
+        Ok(String::from_utf8_lossy(&stdout.data).into())
+    }
+}
+
+struct Captured {
+    data: Vec<u8>,
+    truncated: bool,
+}
+
+/// Read the stream on a separate thread, keeping at most `max_len` bytes.
+/// The rest is read and discarded, so that the child is not blocked writing.
+fn spawn_reader<R>(stream: Option<R>, max_len: usize) -> Receiver<Captured>
+where
+    R: Read + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut captured = Captured {
+            data: Vec::new(),
+            truncated: false,
+        };
+        if let Some(mut stream) = stream {
+            let mut buf = [0; 4096];
+            while let Ok(length) = stream.read(&mut buf) {
+                if length == 0 {
+                    break;
                 }
-                match output.status.success() {
-                    true => String::from_utf8_lossy(&output.stdout).into(),
-                    false => {
-                        error!(?self.cmd, ?output.status, "Inventory file: non-0 exit code.");
-                        String::new()
-                    }
+                let available = max_len.saturating_sub(captured.data.len());
+                if length > available {
+                    captured.truncated = true;
                 }
+                captured
+                    .data
+                    .extend_from_slice(&buf[..length.min(available)]);
             }
+        }
+        let _ = sender.send(captured); // receiver is gone on timeout
+    });
+    receiver
+}
+
+/// Wait for the child to exit, return None if the deadline is reached first.
+fn wait_until(child: &mut Child, deadline: Instant) -> Option<ExitStatus> {
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Some(status),
+            Ok(None) if Instant::now() >= deadline => return None,
+            Ok(None) => sleep(WAIT_POLL_PERIOD),
             Err(error) => {
-                error!(?self.cmd, ?error, "Failed executing inventory file.");
-                String::new()
+                error!(?error, "Failed waiting inventory file.");
+                return None;
+            }
+        }
+    }
+}
+
+fn remaining(deadline: Instant) -> Duration {
+    deadline.saturating_duration_since(Instant::now())
+}
+
+/// Send SIGTERM to the process group of the child, then SIGKILL if it does not exit.
+fn terminate(child: &mut Child) {
+    let group = Pid::from_raw(child.id() as i32);
+    if let Err(error) = killpg(group, Signal::SIGTERM) {
+        warn!(?error, %group, "Failed sending SIGTERM to inventory file.");
+    }
+    let exited = wait_until(child, Instant::now() + KILL_GRACE_PERIOD).is_some();
+    // Kill the rest of the group anyway, the main process may have left children behind.
+    if let Err(error) = killpg(group, Signal::SIGKILL) {
+        if exited {
+            return; // the whole group is gone already
+        }
+        error!(?error, %group, "Failed sending SIGKILL to inventory file.");
+    }
+    if let Err(error) = child.wait() {
+        error!(?error, %group, "Failed waiting killed inventory file.");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    fn write_script(filename: &str, content: &str) -> PathBuf {
+        let datadir = std::env::temp_dir()
+            .as_path()
+            .join("rust-ipdisserver-test-exec-datadir/");
+        // TODO: windows
+        if let Err(error) = std::fs::create_dir(&datadir) {
+            match error.kind() {
+                std::io::ErrorKind::AlreadyExists => (),
+                _ => panic!(),
             }
+        };
+        let path = datadir.join(filename);
+        std::fs::write(&path, content).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_output() {
+        let path = write_script("echo", "#!/bin/sh\necho 'foo=bar'\necho 'on stderr' >&2");
+        assert_eq!(InventoryCommand::new(path).output().unwrap(), "foo=bar\n");
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_output_errors() {
+        let path = write_script("fail", "#!/bin/sh\necho 'foo=bar'\nexit 3");
+        assert!(matches!(
+            InventoryCommand::new(path).output(),
+            Err(InventoryError::ExitStatus(_))
+        ));
+        assert!(matches!(
+            InventoryCommand::new("non-existing-file").output(),
+            Err(InventoryError::Spawn(_))
+        ));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_output_too_large() {
+        let path = write_script("chatty", "#!/bin/sh\nyes 'foo=bar' | head -c 100000");
+        assert_eq!(
+            InventoryCommand::new(path).max_output(1000).output(),
+            Err(InventoryError::OutputTooLarge(1000))
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_output_timeout() {
+        let timeout = Duration::from_millis(200);
+        let hung = write_script("hung", "#!/bin/sh\nsleep 30");
+        let ignoring_sigterm = write_script("hung-no-sigterm", "#!/bin/sh\ntrap '' TERM\nsleep 30");
+        let background = write_script("background", "#!/bin/sh\necho 'foo=bar'\nsleep 30 &");
+        for path in [hung, ignoring_sigterm, background] {
+            let start = Instant::now();
+            assert_eq!(
+                InventoryCommand::new(&path).timeout(timeout).output(),
+                Err(InventoryError::Timeout(timeout))
+            );
+            assert!(start.elapsed() < Duration::from_secs(5), "{:?}", path);
         }
     }
 }
@@ -0,0 +1,316 @@
+use crate::answers::Answer;
+use crate::protocol::MessageType;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use color_eyre::eyre::{Report, WrapErr};
+use ed25519_dalek::{Signer as _, SigningKey, VerifyingKey, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+pub const DEVICE_KEY_FILE_DEFAULT: &str = "/var/lib/ipdisserver/device.key";
+/// Start of every signed answer. Unsigned answers are JSON objects, starting with `{`.
+pub const SIGNED_ANSWER_MAGIC: &[u8; 4] = b"IPDS";
+/// Magic, public key, signature, timestamp (u64, milliseconds since the Unix epoch,
+/// big-endian), then the payload.
+pub const SIGNED_ANSWER_HEADER_LENGTH: usize =
+    SIGNED_ANSWER_MAGIC.len() + PUBLIC_KEY_LENGTH + SIGNATURE_LENGTH + TIMESTAMP_LENGTH;
+const TIMESTAMP_LENGTH: usize = 8;
+const SECRET_KEY_LENGTH: usize = ed25519_dalek::SECRET_KEY_LENGTH;
+
+/// Message a signature is bound to, besides the payload and the timestamp, so that it cannot
+/// be replayed as another message type or to another request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignatureContext {
+    pub message_type: MessageType,
+    /// Of the request answered, 0 for unsolicited messages.
+    pub request_id: u32,
+}
+
+impl SignatureContext {
+    /// Signed data: message type (u8), request id (u32), timestamp (u64), all big-endian,
+    /// then the payload.
+    fn signed_data(&self, timestamp: u64, payload: &[u8]) -> Vec<u8> {
+        let mut res = Vec::with_capacity(13 + payload.len());
+        res.put_u8(self.message_type as u8);
+        res.put_u32(self.request_id);
+        res.put_u64(timestamp);
+        res.put_slice(payload);
+        res
+    }
+}
+
+/// Milliseconds since the Unix epoch, as carried by signed answers.
+pub fn timestamp_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Ed25519 key identifying the device, used to sign answers.
+#[derive(Clone, PartialEq, Eq)]
+pub struct DeviceKey(SigningKey);
+
+impl fmt::Debug for DeviceKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DeviceKey({})", self.public_key()) // never log the secret key
+    }
+}
+
+impl DeviceKey {
+    pub fn generate() -> Result<Self, Report> {
+        let mut secret = [0; SECRET_KEY_LENGTH];
+        getrandom::getrandom(&mut secret)
+            .map_err(|e| Report::msg(format!("Cannot generate device key: {}", e)))?;
+        Ok(Self(SigningKey::from_bytes(&secret)))
+    }
+
+    /// Read the secret key from `path`, generate and save it there if the file does not exist.
+    pub fn load_or_generate(path: &Path) -> Result<Self, Report> {
+        match fs::read(path) {
+            Ok(secret) => {
+                let secret: [u8; SECRET_KEY_LENGTH] = secret.try_into().map_err(|_| {
+                    Report::msg(format!("Invalid device key file {:?}: wrong length", path))
+                })?;
+                Ok(Self(SigningKey::from_bytes(&secret)))
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                let key = Self::generate()?;
+                key.save(path)
+                    .wrap_err_with(|| format!("Cannot save device key to {:?}", path))?;
+                info!(?path, public_key = %key.public_key(), "Device key generated.");
+                Ok(key)
+            }
+            Err(error) => {
+                Err(error).wrap_err_with(|| format!("Cannot read device key file {:?}", path))
+            }
+        }
+    }
+
+    /// Like `load_or_generate`, None with a warning on failure: answers are then sent unsigned,
+    /// e.g. when running as a user who cannot write the default key file.
+    pub fn load_or_warn(path: &Path) -> Option<Self> {
+        match Self::load_or_generate(path) {
+            Ok(key) => Some(key),
+            Err(error) => {
+                warn!(?error, "No device key, answers will not be signed.");
+                None
+            }
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<(), Report> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        file.write_all(self.0.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn public_key(&self) -> DevicePublicKey {
+        DevicePublicKey(self.0.verifying_key())
+    }
+
+    /// Answer carrying `answer` as payload, with the public key, the signature of the payload in
+    /// its context and the current time.
+    pub fn sign_answer(&self, answer: &Answer, context: &SignatureContext) -> Answer {
+        self.sign_answer_at(answer, context, timestamp_now())
+    }
+
+    /// Like `sign_answer`, with the given timestamp.
+    pub fn sign_answer_at(
+        &self,
+        answer: &Answer,
+        context: &SignatureContext,
+        timestamp: u64,
+    ) -> Answer {
+        let signature = self.0.sign(&context.signed_data(timestamp, &answer.0));
+        let mut res = BytesMut::with_capacity(SIGNED_ANSWER_HEADER_LENGTH + answer.0.len());
+        res.put_slice(SIGNED_ANSWER_MAGIC);
+        res.put_slice(self.0.verifying_key().as_bytes());
+        res.put_slice(&signature.to_bytes());
+        res.put_u64(timestamp);
+        res.put_slice(&answer.0);
+        Answer(res.freeze())
+    }
+}
+
+/// Public part of a `DeviceKey`, formatted as hexadecimal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DevicePublicKey(pub VerifyingKey);
+
+impl fmt::Display for DevicePublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0.as_bytes() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for DevicePublicKey {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Report::msg(format!("Invalid public key {:?}", s));
+        if s.len() != PUBLIC_KEY_LENGTH * 2 || !s.is_ascii() {
+            return Err(invalid());
+        }
+        let mut bytes = [0; PUBLIC_KEY_LENGTH];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(
+            VerifyingKey::from_bytes(&bytes).map_err(|_| invalid())?,
+        ))
+    }
+}
+
+/// Who signed an answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnswerSigner {
+    /// The answer is not signed, e.g. sent by an older ipdisserver.
+    Unsigned,
+    /// The signature matches the public key sent along. It is up to the receiver to trust it,
+    /// and to check that `timestamp` (milliseconds since the Unix epoch) is not a replay.
+    Valid {
+        key: DevicePublicKey,
+        timestamp: u64,
+    },
+    /// The answer is forged or corrupted.
+    Invalid,
+}
+
+/// Split a received answer into its payload and the result of the signature verification, in
+/// the context of the message carrying it.
+pub fn open_signed_answer(answer: &Answer, context: &SignatureContext) -> (Answer, AnswerSigner) {
+    if !answer.0.starts_with(SIGNED_ANSWER_MAGIC) {
+        return (answer.clone(), AnswerSigner::Unsigned);
+    }
+    if answer.0.len() < SIGNED_ANSWER_HEADER_LENGTH {
+        warn!(length = answer.0.len(), "Signed answer too short.");
+        return (Answer(Bytes::new()), AnswerSigner::Invalid);
+    }
+    let key_end = SIGNED_ANSWER_MAGIC.len() + PUBLIC_KEY_LENGTH;
+    let signature_end = key_end + SIGNATURE_LENGTH;
+    let payload = Answer(answer.0.slice(SIGNED_ANSWER_HEADER_LENGTH..));
+    let key: &[u8; PUBLIC_KEY_LENGTH] = answer.0[SIGNED_ANSWER_MAGIC.len()..key_end]
+        .try_into()
+        .expect("slice length is PUBLIC_KEY_LENGTH");
+    let signature: &[u8; SIGNATURE_LENGTH] = answer.0[key_end..signature_end]
+        .try_into()
+        .expect("slice length is SIGNATURE_LENGTH");
+    let timestamp = (&answer.0[signature_end..SIGNED_ANSWER_HEADER_LENGTH]).get_u64();
+    let key = match VerifyingKey::from_bytes(key) {
+        Ok(k) => k,
+        Err(_) => return (payload, AnswerSigner::Invalid),
+    };
+    let signature = ed25519_dalek::Signature::from_bytes(signature);
+    match key.verify_strict(&context.signed_data(timestamp, &payload.0), &signature) {
+        Ok(()) => (
+            payload,
+            AnswerSigner::Valid {
+                key: DevicePublicKey(key),
+                timestamp,
+            },
+        ),
+        Err(_) => (payload, AnswerSigner::Invalid),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_sign_and_open() {
+        let key = DeviceKey::generate().unwrap();
+        let answer = Answer::from(r#"{"hostname":"dummy"}"#.to_string());
+        let context = SignatureContext {
+            message_type: MessageType::Answer,
+            request_id: 42,
+        };
+        let signed = key.sign_answer_at(&answer, &context, 1234);
+        assert_eq!(
+            open_signed_answer(&signed, &context),
+            (
+                answer.clone(),
+                AnswerSigner::Valid {
+                    key: key.public_key(),
+                    timestamp: 1234
+                }
+            )
+        );
+        assert_eq!(
+            open_signed_answer(&answer, &context),
+            (answer.clone(), AnswerSigner::Unsigned)
+        );
+        let mut forged = signed.0.to_vec();
+        *forged.last_mut().unwrap() = b']';
+        let (payload, signer) = open_signed_answer(&Answer::from(forged.as_slice()), &context);
+        assert_eq!(signer, AnswerSigner::Invalid);
+        assert_eq!(payload.0.len(), answer.0.len());
+        // Replayed to another request, as another message type, or with another timestamp.
+        for other in [
+            SignatureContext {
+                request_id: 43,
+                ..context
+            },
+            SignatureContext {
+                message_type: MessageType::Goodbye,
+                ..context
+            },
+        ] {
+            assert_eq!(open_signed_answer(&signed, &other).1, AnswerSigner::Invalid);
+        }
+        let mut later = signed.0.to_vec();
+        later[SIGNED_ANSWER_HEADER_LENGTH - 1] += 1;
+        assert_eq!(
+            open_signed_answer(&Answer::from(later.as_slice()), &context).1,
+            AnswerSigner::Invalid
+        );
+        assert_eq!(
+            open_signed_answer(&Answer::from(&b"IPDS short"[..]), &context).1,
+            AnswerSigner::Invalid
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_load_or_generate() {
+        let datadir = std::env::temp_dir()
+            .as_path()
+            .join("rust-ipdisserver-test-identity-datadir/");
+        let _ = fs::remove_dir_all(&datadir);
+        let path = datadir.join("device.key");
+        let key = DeviceKey::load_or_generate(&path).unwrap();
+        assert_eq!(DeviceKey::load_or_generate(&path).unwrap(), key);
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        fs::write(&path, "too short").unwrap();
+        assert!(DeviceKey::load_or_generate(&path).is_err());
+        assert_eq!(DeviceKey::load_or_warn(&path), None);
+        assert!(logs_contain("answers will not be signed"));
+    }
+
+    #[test]
+    fn test_public_key_hex() {
+        let key = DeviceKey::generate().unwrap().public_key();
+        assert_eq!(key.to_string().parse::<DevicePublicKey>().unwrap(), key);
+        assert!("not hex".parse::<DevicePublicKey>().is_err());
+        assert!(format!("{:?}", DeviceKey::generate().unwrap()).starts_with("DeviceKey("));
+    }
+}
@@ -0,0 +1,200 @@
+use nix::ifaddrs::{getifaddrs, InterfaceAddress};
+use nix::net::if_::{if_nametoindex, InterfaceFlags};
+use nix::sys::socket::SockaddrStorage;
+use serde_json::value::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddrV4, SocketAddrV6};
+use std::path::Path;
+use tracing::{error, trace};
+
+const SYS_CLASS_NET: &str = "/sys/class/net";
+
+/// Network interface information reported in the answer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkInterface {
+    pub name: String,
+    /// Kernel interface index, 0 if unknown. Not reported in the answer.
+    pub index: u32,
+    pub mac: Option<String>,
+    pub addresses: Vec<NetworkAddress>,
+    pub up: bool,
+    /// True if the interface supports multicast. Not reported in the answer.
+    pub multicast: bool,
+    pub mtu: Option<u32>,
+}
+
+/// IP address with its prefix length, formatted as `addr/prefix`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkAddress {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl fmt::Display for NetworkAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl From<&NetworkInterface> for Value {
+    fn from(interface: &NetworkInterface) -> Self {
+        let mut res = serde_json::map::Map::new();
+        res.insert(
+            "mac".into(),
+            interface.mac.clone().map_or(Value::Null, Value::String),
+        );
+        res.insert(
+            "addresses".into(),
+            Value::Array(
+                interface
+                    .addresses
+                    .iter()
+                    .map(|a| Value::String(a.to_string()))
+                    .collect(),
+            ),
+        );
+        res.insert("up".into(), Value::Bool(interface.up));
+        res.insert("mtu".into(), interface.mtu.map_or(Value::Null, Value::from));
+        Value::Object(res)
+    }
+}
+
+/// All network interfaces as a JSON object, keyed by interface name.
+pub fn get_interfaces_json() -> Value {
+    Value::Object(
+        get_interfaces()
+            .iter()
+            .map(|i| (i.name.clone(), Value::from(i)))
+            .collect(),
+    )
+}
+
+/// List the network interfaces of the system, sorted by name.
+/// An empty list is returned if interfaces cannot be enumerated.
+pub fn get_interfaces() -> Vec<NetworkInterface> {
+    let ifaddrs = match getifaddrs() {
+        Ok(i) => i,
+        Err(error) => {
+            error!(?error, "Failed enumerating network interfaces.");
+            return Vec::new();
+        }
+    };
+    let mut interfaces = collect_interfaces(ifaddrs);
+    for interface in interfaces.iter_mut() {
+        interface.mtu = read_mtu(Path::new(SYS_CLASS_NET), &interface.name);
+        interface.index = if_nametoindex(interface.name.as_str()).unwrap_or(0);
+    }
+    trace!(?interfaces, "Network interfaces enumerated.");
+    interfaces
+}
+
+/// Group `getifaddrs` entries (one per address) by interface name.
+fn collect_interfaces<I>(ifaddrs: I) -> Vec<NetworkInterface>
+where
+    I: IntoIterator<Item = InterfaceAddress>,
+{
+    let mut interfaces = BTreeMap::<String, NetworkInterface>::new();
+    for ifaddr in ifaddrs {
+        let interface = interfaces
+            .entry(ifaddr.interface_name.clone())
+            .or_insert_with(|| NetworkInterface {
+                name: ifaddr.interface_name.clone(),
+                ..Default::default()
+            });
+        interface.up = ifaddr.flags.contains(InterfaceFlags::IFF_UP);
+        interface.multicast = ifaddr.flags.contains(InterfaceFlags::IFF_MULTICAST);
+        let address = match ifaddr.address {
+            Some(a) => a,
+            None => continue,
+        };
+        if let Some(mac) = address.as_link_addr().and_then(|l| l.addr()) {
+            interface.mac = Some(format_mac(&mac));
+        } else if let Some(addr) = get_ip(&address) {
+            let prefix = ifaddr.netmask.as_ref().map_or(0, get_prefix_length);
+            interface.addresses.push(NetworkAddress { addr, prefix });
+        }
+    }
+    interfaces.into_values().collect()
+}
+
+fn get_ip(address: &SockaddrStorage) -> Option<IpAddr> {
+    if let Some(sin) = address.as_sockaddr_in() {
+        return Some(IpAddr::V4(*SocketAddrV4::from(*sin).ip()));
+    }
+    if let Some(sin6) = address.as_sockaddr_in6() {
+        return Some(IpAddr::V6(*SocketAddrV6::from(*sin6).ip()));
+    }
+    None
+}
+
+fn get_prefix_length(netmask: &SockaddrStorage) -> u8 {
+    match get_ip(netmask) {
+        Some(IpAddr::V4(mask)) => u32::from(mask).count_ones() as u8,
+        Some(IpAddr::V6(mask)) => u128::from(mask).count_ones() as u8,
+        None => 0,
+    }
+}
+
+fn format_mac(mac: &[u8; 6]) -> String {
+    mac.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+fn read_mtu(sys_class_net: &Path, interface_name: &str) -> Option<u32> {
+    let path = sys_class_net.join(interface_name).join("mtu");
+    match fs::read_to_string(&path) {
+        Ok(mtu) => mtu.trim().parse().ok(),
+        Err(error) => {
+            trace!(?error, ?path, "Cannot read interface MTU.");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_get_interfaces_loopback() {
+        let interfaces = get_interfaces();
+        let lo = interfaces.iter().find(|i| i.name == "lo").unwrap();
+        assert!(lo.up);
+        assert_ne!(lo.index, 0);
+        assert!(lo.addresses.contains(&NetworkAddress {
+            addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            prefix: 8
+        }));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_interface_to_json() {
+        let interface = NetworkInterface {
+            name: "eth0".into(),
+            index: 2,
+            mac: Some(format_mac(&[0x00, 0x1b, 0x2c, 0xaa, 0xbb, 0xcc])),
+            addresses: vec![
+                NetworkAddress {
+                    addr: "192.168.1.10".parse().unwrap(),
+                    prefix: 24,
+                },
+                NetworkAddress {
+                    addr: "fe80::21b:2cff:feaa:bbcc".parse().unwrap(),
+                    prefix: 64,
+                },
+            ],
+            up: true,
+            multicast: true,
+            mtu: Some(1500),
+        };
+        let expected = r#"{"addresses":["192.168.1.10/24","fe80::21b:2cff:feaa:bbcc/64"],"mac":"00:1b:2c:aa:bb:cc","mtu":1500,"up":true}"#;
+        assert_eq!(Value::from(&interface).to_string(), expected);
+    }
+}
@@ -1,52 +1,147 @@
 use crate::answers::{BeaconInfos, FromCmdOutput};
-use crate::exec::InventoryCommand;
+use crate::conf::{INTERNAL_INVENTORY_TTL, INVENTORY_TTL_DEFAULT};
+use crate::exec::{InventoryCommand, InventoryError, MAX_OUTPUT_DEFAULT, TIMEOUT_DEFAULT};
 use crate::hostname::get_hostname;
+use crate::interfaces::get_interfaces_json;
+use serde_json::value::Value;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
+/// Inventory source built into ipdisserver, providing the value of a single key.
 pub struct InternalInventory {
     pub key: String,
-    pub source: Box<dyn Fn() -> String>,
+    pub source: Box<dyn Fn() -> Value + Send + Sync>,
+    pub ttl: Duration,
 }
 
 impl Default for InternalInventory {
     fn default() -> Self {
         Self {
             key: "hostname".into(),
-            source: Box::from(get_hostname),
+            source: Box::new(|| get_hostname().into()),
+            ttl: INTERNAL_INVENTORY_TTL,
         }
     }
 }
 
-#[derive(Debug, Clone, Default)]
+impl InternalInventory {
+    /// Network interfaces with their MAC, IP addresses, state and MTU.
+    pub fn interfaces() -> Self {
+        Self {
+            key: "interfaces".into(),
+            source: Box::from(get_interfaces_json),
+            ttl: INTERNAL_INVENTORY_TTL,
+        }
+    }
+
+    /// All the built-in inventory sources.
+    pub fn builtins() -> Vec<Self> {
+        vec![Self::default(), Self::interfaces()]
+    }
+}
+
+/// Executable file whose output is added to the answer.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct InventoryFile {
     pub path: PathBuf,
+    pub ttl: Duration,
+    pub timeout: Duration,
+    pub max_output: usize,
 }
 
 impl From<&Path> for InventoryFile {
     fn from(path: &Path) -> Self {
-        Self { path: path.into() }
+        Self {
+            path: path.into(),
+            ttl: INVENTORY_TTL_DEFAULT,
+            timeout: TIMEOUT_DEFAULT,
+            max_output: MAX_OUTPUT_DEFAULT,
+        }
     }
 }
 
 pub trait ExecuteInventory {
     fn execute(&self) -> InventoryOutput;
+
+    /// Name identifying the inventory source in logs.
+    fn name(&self) -> String;
+
+    /// How long the output of an execution stays valid.
+    fn ttl(&self) -> Duration;
+}
+
+impl<T> ExecuteInventory for Arc<T>
+where
+    T: ExecuteInventory + ?Sized,
+{
+    fn execute(&self) -> InventoryOutput {
+        (**self).execute()
+    }
+
+    fn name(&self) -> String {
+        (**self).name()
+    }
+
+    fn ttl(&self) -> Duration {
+        (**self).ttl()
+    }
 }
 
 impl ExecuteInventory for InternalInventory {
+    fn name(&self) -> String {
+        self.key.clone()
+    }
+
+    fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
     fn execute(&self) -> InventoryOutput {
-        let raw_output = (*self.source)();
+        let value = (*self.source)();
+        let raw_output = match &value {
+            Value::String(s) => s.clone(),
+            v => v.to_string(),
+        };
         let mut output = BeaconInfos::new();
-        output.insert(self.key.clone(), raw_output.clone().into());
-        InventoryOutput { raw_output, output }
+        output.insert(self.key.clone(), value);
+        InventoryOutput {
+            raw_output,
+            output,
+            error: None,
+        }
     }
 }
 
 impl ExecuteInventory for InventoryFile {
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
     fn execute(&self) -> InventoryOutput {
-        let mut command = InventoryCommand::new(&self.path);
-        let raw_output = command.output();
-        let output = BeaconInfos::from_cmd_output(&raw_output).unwrap_or_default();
-        InventoryOutput { raw_output, output }
+        let mut command = InventoryCommand::new(&self.path)
+            .timeout(self.timeout)
+            .max_output(self.max_output);
+        match command.output() {
+            Ok(raw_output) => {
+                let output = BeaconInfos::from_cmd_output(&raw_output).unwrap_or_default();
+                InventoryOutput {
+                    raw_output,
+                    output,
+                    error: None,
+                }
+            }
+            Err(error) => InventoryOutput {
+                error: Some(error),
+                ..Default::default()
+            },
+        }
     }
 }
 
@@ -54,4 +149,138 @@ impl ExecuteInventory for InventoryFile {
 pub struct InventoryOutput {
     pub raw_output: String,
     pub output: BeaconInfos,
+    /// Why the execution failed, if it did.
+    pub error: Option<InventoryError>,
+}
+
+/// Execute the inventories concurrently, on at most `max_workers` threads.
+/// Outputs are returned in the same order as the inventories.
+pub fn execute_parallel<T>(inventories: &[T], max_workers: usize) -> Vec<InventoryOutput>
+where
+    T: ExecuteInventory + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let workers = max_workers.clamp(1, inventories.len().max(1));
+    let mut outputs = vec![InventoryOutput::default(); inventories.len()];
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut executed = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        match inventories.get(index) {
+                            Some(inventory) => executed.push((index, inventory.execute())),
+                            None => return executed,
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            for (index, output) in handle.join().expect("Inventory worker panicked") {
+                outputs[index] = output;
+            }
+        }
+    });
+    outputs
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+
+    /// Running inventories, and the most seen at once.
+    #[derive(Default)]
+    struct Concurrency {
+        running: AtomicUsize,
+        max: AtomicUsize,
+    }
+
+    /// Inventory waiting until `expected` inventories ran at once, or giving up after 5s.
+    fn waiting_inventory(
+        key: &str,
+        value: usize,
+        concurrency: Arc<Concurrency>,
+        expected: usize,
+    ) -> InternalInventory {
+        InternalInventory {
+            key: key.into(),
+            source: Box::new(move || {
+                let running = concurrency.running.fetch_add(1, Ordering::SeqCst) + 1;
+                concurrency.max.fetch_max(running, Ordering::SeqCst);
+                let deadline = Instant::now() + Duration::from_secs(5);
+                while concurrency.max.load(Ordering::SeqCst) < expected && Instant::now() < deadline
+                {
+                    thread::yield_now();
+                }
+                concurrency.running.fetch_sub(1, Ordering::SeqCst);
+                value.into()
+            }),
+            ttl: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_execute_parallel() {
+        let concurrency = Arc::new(Concurrency::default());
+        let inventories: Vec<InternalInventory> = (0..8)
+            .map(|i| waiting_inventory(&format!("key{}", i % 3), i, concurrency.clone(), 4))
+            .collect();
+        let outputs = execute_parallel(&inventories, 4);
+        assert_eq!(concurrency.max.load(Ordering::SeqCst), 4); // never more than the workers
+        let values: Vec<String> = outputs.iter().map(|o| o.raw_output.clone()).collect();
+        assert_eq!(values, ["0", "1", "2", "3", "4", "5", "6", "7"]);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_execute_file_errors() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join("rust-ipdisserver-test-inventory-errors");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let script = |name: &str, content: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, content).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            path
+        };
+        let failing = InventoryFile::from(
+            script(
+                "failing",
+                "#!/bin/sh
+echo a=b
+exit 3",
+            )
+            .as_path(),
+        );
+        let output = failing.execute();
+        assert!(output.output.is_empty());
+        assert_eq!(
+            output.error,
+            Some(InventoryError::ExitStatus("exit status: 3".into()))
+        );
+        let timeout = Duration::from_millis(200);
+        let hung = InventoryFile {
+            timeout,
+            ..InventoryFile::from(
+                script(
+                    "hung",
+                    "#!/bin/sh
+sleep 30",
+                )
+                .as_path(),
+            )
+        };
+        assert_eq!(hung.execute().error, Some(InventoryError::Timeout(timeout)));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_execute_parallel_empty() {
+        assert!(execute_parallel::<InternalInventory>(&[], 4).is_empty());
+    }
 }
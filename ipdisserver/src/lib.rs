@@ -1,11 +1,29 @@
+pub mod announce;
 pub mod answers;
+pub mod auth;
 pub mod bytes;
+pub mod cache;
+pub mod chunks;
+pub mod client_filter;
 pub mod conf;
+pub mod control;
+pub mod encryption;
 pub mod exec;
 pub mod hostname;
+pub mod identity;
+pub mod interfaces;
 pub mod inventory;
+pub mod multicast;
+pub mod pktinfo;
+pub mod profiles;
+pub mod protocol;
+pub mod query;
+pub mod rate_limit;
+pub mod requesters;
 pub mod server;
+pub mod settings;
 pub mod signature;
+pub mod systemd;
 
 pub use answers::Answer;
 pub use conf::SERVER_PORT_DEFAULT;
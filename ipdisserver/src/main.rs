@@ -1,20 +1,44 @@
 mod setup;
 
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
 use color_eyre::eyre::Report;
+use figment::providers::Serialized;
+use ipdisserver::announce::ANNOUNCE_INTERVAL_DEFAULT;
 use ipdisserver::conf::{
-    ServerConfig, LISTENING_ADDR_DEFAULT, SERVER_PORT_DEFAULT, SIGNATURE_DEFAULT,
+    ServerConfig, INVENTORY_DIR_PATTERN_DEFAULT, INVENTORY_TTL_DEFAULT, INVENTORY_WORKERS_DEFAULT,
+    LISTENING_ADDR_DEFAULT, MULTICAST_V6_ADDR_DEFAULT, RATE_LIMIT_TIMEOUT_DEFAULT,
+    SERVER_PORT_DEFAULT,
 };
-use ipdisserver::{server, Signature};
-use std::net::Ipv4Addr;
+use ipdisserver::control::{send_command, ControlCommand, CONTROL_SOCKET_DEFAULT};
+use ipdisserver::exec::{MAX_OUTPUT_DEFAULT, TIMEOUT_DEFAULT};
+use ipdisserver::identity::DEVICE_KEY_FILE_DEFAULT;
+use ipdisserver::rate_limit::{
+    RATE_LIMIT_BURST_DEFAULT, RATE_LIMIT_GLOBAL_DEFAULT, RATE_LIMIT_IPV4_PREFIX_DEFAULT,
+    RATE_LIMIT_IPV6_PREFIX_DEFAULT, RATE_LIMIT_MAX_CLIENTS_DEFAULT,
+};
+use ipdisserver::server;
+use ipdisserver::settings::{InventoryFileSettings, Settings};
+use ipnet::IpNet;
+use serde_json::{json, Value};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::path::PathBuf;
 use tracing::{debug, info};
 
 // use color_eyre::{eyre::WrapErr};
 
+/// Options given on the command line override the configuration file
+/// and the `IPDISSERVER_*` environment variables.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Path of a TOML configuration file, e.g. `/etc/ipdisserver.toml`.
+    #[arg(short, long, value_hint = clap::ValueHint::FilePath)]
+    config: Option<PathBuf>,
+
     /// Listening port.
     #[arg(short, long, default_value_t = SERVER_PORT_DEFAULT)]
     port: u16,
@@ -23,6 +47,96 @@ struct Cli {
     #[arg(short, long, default_value_t = LISTENING_ADDR_DEFAULT)]
     addr: Ipv4Addr,
 
+    /// Only answer requests received on this network interface, e.g. `eth1`.
+    /// Repeat the option for each interface. Interfaces can be missing at start,
+    /// requests are answered once they appear.
+    /// If not specified, requests received on any interface are answered.
+    #[arg(short, long, action = clap::ArgAction::Append)]
+    interface: Vec<String>,
+
+    /// IPv4 multicast group joined to receive requests, e.g. `239.255.19.1`.
+    /// Requests sent to the broadcast address or unicast are still answered.
+    #[arg(long)]
+    multicast_group: Option<Ipv4Addr>,
+
+    /// Network interface where the IPv4 multicast group is joined, e.g. `eth0`.
+    /// Repeat the option for each interface.
+    /// If not specified, the `--interface` ones or all the multicast capable interfaces
+    /// are used.
+    #[arg(long, action = clap::ArgAction::Append)]
+    multicast_interface: Vec<String>,
+
+    /// Do not listen for requests sent over IPv6.
+    #[arg(long)]
+    no_ipv6: bool,
+
+    /// IPv6 multicast group joined to receive requests from scanners on the link.
+    #[arg(long, default_value_t = MULTICAST_V6_ADDR_DEFAULT)]
+    ipv6_group: Ipv6Addr,
+
+    /// Network interface where the IPv6 multicast group is joined, e.g. `eth0`.
+    /// Repeat the option for each interface.
+    /// If not specified, the `--interface` ones or all the multicast capable interfaces
+    /// are used.
+    #[arg(long, action = clap::ArgAction::Append)]
+    ipv6_interface: Vec<String>,
+
+    /// Only answer clients in this network, e.g. `10.0.0.0/8` or `fd00::/8`.
+    /// Repeat the option for each network.
+    /// If not specified, all the clients not denied are answered.
+    #[arg(long, action = clap::ArgAction::Append)]
+    allow_from: Vec<IpNet>,
+
+    /// Never answer clients in this network, e.g. `192.168.0.0/16`.
+    /// Takes precedence over `--allow-from`. Repeat the option for each network.
+    #[arg(long, action = clap::ArgAction::Append)]
+    deny_from: Vec<IpNet>,
+
+    /// Seconds for a client to earn a new answer, after `--rate-limit-burst` answers in a row.
+    #[arg(long, default_value_t = RATE_LIMIT_TIMEOUT_DEFAULT.as_secs_f64())]
+    rate_limit_timeout: f64,
+
+    /// Answers a client can get in a row.
+    #[arg(long, default_value_t = RATE_LIMIT_BURST_DEFAULT)]
+    rate_limit_burst: u32,
+
+    /// Prefix length grouping IPv4 clients in a bucket, 32 limits each address.
+    #[arg(long, default_value_t = RATE_LIMIT_IPV4_PREFIX_DEFAULT)]
+    rate_limit_ipv4_prefix: u8,
+
+    /// Prefix length grouping IPv6 clients in a bucket, e.g. 64 for a whole subnet.
+    #[arg(long, default_value_t = RATE_LIMIT_IPV6_PREFIX_DEFAULT)]
+    rate_limit_ipv6_prefix: u8,
+
+    /// Answers per second to all the clients together, 0 for no limit.
+    #[arg(long, default_value_t = RATE_LIMIT_GLOBAL_DEFAULT)]
+    rate_limit_global_rate: u32,
+
+    /// Clients remembered by the rate limiter, the least recently seen ones are forgotten first.
+    #[arg(long, default_value_t = RATE_LIMIT_MAX_CLIENTS_DEFAULT)]
+    rate_limit_max_clients: usize,
+
+    /// Send the answer unsolicited to this broadcast or multicast address, e.g.
+    /// `255.255.255.255` or `ff02::1901`, on the scanner port, at start, when the inventory
+    /// changes and every `--announce-interval` seconds.
+    /// Repeat the option for each address.
+    #[arg(long, action = clap::ArgAction::Append)]
+    announce: Vec<IpAddr>,
+
+    /// Seconds between announcements of an unchanged inventory, 0 to announce only at start
+    /// and on changes.
+    #[arg(long, default_value_t = ANNOUNCE_INTERVAL_DEFAULT.as_secs_f64())]
+    announce_interval: f64,
+
+    /// Path of the local control socket used by `ipdisserver ctl`.
+    /// Only the server user can use it, unless `control.mode` is set in the configuration file.
+    #[arg(long, default_value = CONTROL_SOCKET_DEFAULT, value_hint = clap::ValueHint::FilePath)]
+    control_socket: PathBuf,
+
+    /// Do not create the control socket.
+    #[arg(long)]
+    no_control_socket: bool,
+
     /// Path of a file with accepted signatures, one per line.
     /// UTF-8 characters are allowed.
     /// Each signature length must be 128 bytes at most.
@@ -30,6 +144,22 @@ struct Cli {
     #[arg(short, long)]
     signatures_file: Option<PathBuf>,
 
+    /// Path of the file with the secret key signing the answers.
+    /// A new key is generated and saved there if the file does not exist.
+    #[arg(short, long, default_value = DEVICE_KEY_FILE_DEFAULT, value_hint = clap::ValueHint::FilePath)]
+    key_file: PathBuf,
+
+    /// Path of a file with keys accepted for authenticated requests, one per line.
+    /// When set, requests with a plain signature are ignored,
+    /// unless `--allow-unauthenticated` is given.
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    auth_keys_file: Option<PathBuf>,
+
+    /// Answer requests with a plain signature even if authentication keys are set,
+    /// e.g. while scanners are being updated.
+    #[arg(long)]
+    allow_unauthenticated: bool,
+
     /// Send logs to systemd-journald instead of stderr.
     #[arg(short, long)]
     journald: bool,
@@ -37,26 +167,215 @@ struct Cli {
     /// Specify a list of files to execute, the output will be added to the answer.
     /// The output must be in the format `key0=value0\nkey1=value1\n...`.
     /// Repeat the option for each file.
+    /// Append `:TTL` to the path to cache the output for TTL seconds
+    /// instead of `--inventory-ttl`, e.g. `/usr/bin/inventory-disk:300`.
     #[arg(short = 'f', long, action = clap::ArgAction::Append, value_hint = clap::ValueHint::FilePath)]
-    inventory: Vec<PathBuf>,
+    inventory: Vec<InventoryFileSettings>,
+
+    /// Directory where to look for inventory files, e.g. `/usr/share/ipdisserver/inventory.d/`.
+    /// Executable files matching `--inventory-pattern` are used, sorted by name,
+    /// before the ones specified with `--inventory`.
+    /// Repeat the option for each directory.
+    #[arg(long, action = clap::ArgAction::Append, value_hint = clap::ValueHint::DirPath)]
+    inventory_dir: Vec<PathBuf>,
+
+    /// Glob pattern of the inventory files names in `--inventory-dir`.
+    #[arg(long, default_value = INVENTORY_DIR_PATTERN_DEFAULT)]
+    inventory_pattern: String,
+
+    /// Seconds the output of an inventory file is cached before being refreshed.
+    /// Files are re-executed in background, answers are always served from cache.
+    #[arg(long, default_value_t = INVENTORY_TTL_DEFAULT.as_secs())]
+    inventory_ttl: u64,
+
+    /// Seconds an inventory file can run before being terminated (SIGTERM, then SIGKILL
+    /// to its whole process group).
+    #[arg(long, default_value_t = TIMEOUT_DEFAULT.as_secs_f64())]
+    inventory_timeout: f64,
+
+    /// Maximum length of an inventory file output, in bytes.
+    /// If stdout is longer, the file output is discarded.
+    #[arg(long, default_value_t = MAX_OUTPUT_DEFAULT)]
+    inventory_max_output: usize,
+
+    /// Maximum number of inventory files executed concurrently.
+    #[arg(long, default_value_t = INVENTORY_WORKERS_DEFAULT)]
+    inventory_workers: usize,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Send a command to the running server through its control socket.
+    Ctl {
+        /// Path of the control socket. If not specified, the one of the configuration is used.
+        #[arg(long, value_hint = clap::ValueHint::FilePath)]
+        socket: Option<PathBuf>,
+
+        #[command(subcommand)]
+        action: CtlAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CtlAction {
+    /// Print the answer currently served to the scanners matching a profile.
+    ShowAnswer {
+        /// Name of the access profile, `full` if not specified.
+        profile: Option<String>,
+    },
+    /// Print the request counters, as JSON.
+    Stats,
+    /// Reload the configuration, like SIGHUP. Changes are logged by the server.
+    Reload,
+    /// Execute all the inventory files again, without waiting for their TTL.
+    FlushCache,
+}
+
+impl From<&CtlAction> for ControlCommand {
+    fn from(action: &CtlAction) -> Self {
+        match action {
+            CtlAction::ShowAnswer { profile } => Self::ShowAnswer(profile.clone()),
+            CtlAction::Stats => Self::Stats,
+            CtlAction::Reload => Self::Reload,
+            CtlAction::FlushCache => Self::FlushCache,
+        }
+    }
+}
+
+impl Cli {
+    /// Settings explicitly given on the command line, defaults excluded.
+    fn overrides(&self, matches: &ArgMatches) -> Value {
+        let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        let mut res = json!({});
+        if given("port") {
+            res["port"] = json!(self.port);
+        }
+        if given("addr") {
+            res["addr"] = json!(self.addr);
+        }
+        if given("interface") {
+            res["interfaces"] = json!(self.interface);
+        }
+        if given("multicast_group") {
+            res["multicast"]["group"] = json!(self.multicast_group);
+        }
+        if given("multicast_interface") {
+            res["multicast"]["interfaces"] = json!(self.multicast_interface);
+        }
+        if given("no_ipv6") {
+            res["ipv6"]["enabled"] = json!(!self.no_ipv6);
+        }
+        if given("ipv6_group") {
+            res["ipv6"]["group"] = json!(self.ipv6_group);
+        }
+        if given("ipv6_interface") {
+            res["ipv6"]["interfaces"] = json!(self.ipv6_interface);
+        }
+        if given("allow_from") {
+            res["clients"]["allow"] = json!(self.allow_from);
+        }
+        if given("deny_from") {
+            res["clients"]["deny"] = json!(self.deny_from);
+        }
+        if given("rate_limit_timeout") {
+            res["rate_limit"]["timeout"] = json!(self.rate_limit_timeout);
+        }
+        if given("rate_limit_burst") {
+            res["rate_limit"]["burst"] = json!(self.rate_limit_burst);
+        }
+        if given("rate_limit_ipv4_prefix") {
+            res["rate_limit"]["ipv4_prefix"] = json!(self.rate_limit_ipv4_prefix);
+        }
+        if given("rate_limit_ipv6_prefix") {
+            res["rate_limit"]["ipv6_prefix"] = json!(self.rate_limit_ipv6_prefix);
+        }
+        if given("rate_limit_global_rate") {
+            res["rate_limit"]["global_rate"] = json!(self.rate_limit_global_rate);
+        }
+        if given("rate_limit_max_clients") {
+            res["rate_limit"]["max_clients"] = json!(self.rate_limit_max_clients);
+        }
+        if given("announce") {
+            res["announce"]["addrs"] = json!(self.announce);
+        }
+        if given("announce_interval") {
+            res["announce"]["interval"] = json!(self.announce_interval);
+        }
+        if given("control_socket") {
+            res["control"]["path"] = json!(self.control_socket);
+        }
+        if given("no_control_socket") {
+            res["control"]["enabled"] = json!(!self.no_control_socket);
+        }
+        if given("signatures_file") {
+            res["signatures_file"] = json!(self.signatures_file);
+        }
+        if given("key_file") {
+            res["key_file"] = json!(self.key_file);
+        }
+        if given("auth_keys_file") {
+            res["auth"]["keys_file"] = json!(self.auth_keys_file);
+        }
+        if given("allow_unauthenticated") {
+            res["auth"]["allow_unauthenticated"] = json!(self.allow_unauthenticated);
+        }
+        if given("journald") {
+            res["log"]["journald"] = json!(self.journald);
+        }
+        if given("inventory") {
+            res["inventory"]["files"] = json!(self.inventory);
+        }
+        if given("inventory_dir") {
+            res["inventory"]["dirs"] = json!(self.inventory_dir);
+        }
+        if given("inventory_pattern") {
+            res["inventory"]["pattern"] = json!(self.inventory_pattern);
+        }
+        if given("inventory_ttl") {
+            res["inventory"]["ttl"] = json!(self.inventory_ttl);
+        }
+        if given("inventory_timeout") {
+            res["inventory"]["timeout"] = json!(self.inventory_timeout);
+        }
+        if given("inventory_max_output") {
+            res["inventory"]["max_output"] = json!(self.inventory_max_output);
+        }
+        if given("inventory_workers") {
+            res["inventory"]["workers"] = json!(self.inventory_workers);
+        }
+        res
+    }
 }
 
 fn main() -> Result<(), Report> {
-    let cli = Cli::parse();
-    let do_log_to_journald = cli.journald;
-    setup::setup(do_log_to_journald)?;
-    let signatures = match cli.signatures_file {
-        Some(p) => ServerConfig::parse_signatures_file(&p)?,
-        None => vec![Signature::from(SIGNATURE_DEFAULT)],
-    };
-    info!("Accepted signatures: {:?}", signatures);
-    let conf = ServerConfig {
-        port: cli.port,
-        listening_addr: cli.addr,
-        inventory_files: cli.inventory,
-        signatures,
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches)?;
+    let config_file = cli.config.clone();
+    let overrides = cli.overrides(&matches);
+    let load_settings = move || -> Result<Settings, Report> {
+        let settings = Settings::figment(config_file.as_deref())?
+            .merge(Serialized::defaults(overrides.clone()))
+            .extract()?;
+        debug!(?settings);
+        Ok(settings)
     };
+    if let Some(Command::Ctl { socket, action }) = &cli.command {
+        setup::setup(false, None)?;
+        let path = match socket {
+            Some(p) => p.clone(),
+            None => load_settings()?.control.path,
+        };
+        print!("{}", send_command(&path, &ControlCommand::from(action))?);
+        return Ok(());
+    }
+    let settings = load_settings()?;
+    setup::setup(settings.log.journald, settings.log.level.as_deref())?;
+    let conf = ServerConfig::from_settings(&settings)?;
+    info!("Accepted signatures: {:?}", conf.signatures);
     debug!("Starting IP discovery server.");
-    server::run(&conf)?;
+    server::run(
+        &conf,
+        Box::new(move || ServerConfig::from_settings(&load_settings()?)),
+    )?;
     Ok(())
 }
@@ -0,0 +1,254 @@
+use crate::interfaces::{get_interfaces, NetworkInterface};
+use color_eyre::eyre::Report;
+use socket2::{Domain, InterfaceIndexOrAddress, Protocol, SockRef, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use tracing::{info, warn};
+
+/// Bind an IPv6 only UDP socket on all the addresses, so that an IPv4 socket can be bound
+/// on the same port.
+pub fn bind_v6(port: u16) -> Result<UdpSocket, Report> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    Ok(socket.into())
+}
+
+/// Interfaces named in `names`, or all the up and multicast capable ones if `names` is empty.
+pub fn multicast_interfaces(names: &[String]) -> Result<Vec<NetworkInterface>, Report> {
+    select_interfaces(get_interfaces(), names)
+}
+
+/// Like `multicast_interfaces`, skipping the named interfaces that do not exist (yet).
+pub fn present_interfaces(names: &[String]) -> Vec<NetworkInterface> {
+    get_interfaces()
+        .into_iter()
+        .filter(|i| match names.is_empty() {
+            true => is_multicast_capable(i),
+            false => names.contains(&i.name),
+        })
+        .collect()
+}
+
+fn is_multicast_capable(interface: &NetworkInterface) -> bool {
+    interface.up && interface.multicast && interface.index != 0
+}
+
+fn select_interfaces(
+    interfaces: Vec<NetworkInterface>,
+    names: &[String],
+) -> Result<Vec<NetworkInterface>, Report> {
+    if names.is_empty() {
+        return Ok(interfaces
+            .into_iter()
+            .filter(is_multicast_capable)
+            .collect());
+    }
+    names
+        .iter()
+        .map(|name| {
+            interfaces
+                .iter()
+                .find(|i| &i.name == name)
+                .cloned()
+                .ok_or_else(|| Report::msg(format!("Network interface {} not found", name)))
+        })
+        .collect()
+}
+
+/// First IPv4 address of the interface, used to select it in IPv4 multicast socket options.
+pub fn interface_ipv4(interface: &NetworkInterface) -> Option<Ipv4Addr> {
+    interface.addresses.iter().find_map(|a| match a.addr {
+        IpAddr::V4(addr) => Some(addr),
+        IpAddr::V6(_) => None,
+    })
+}
+
+/// Send IPv4 multicast datagrams with the given TTL (number of routers they can cross)
+/// from `interface`, or from the one chosen by the routing table if None.
+pub fn set_multicast_sender_v4(
+    socket: &UdpSocket,
+    ttl: u32,
+    interface: Option<&NetworkInterface>,
+) -> Result<(), Report> {
+    socket.set_multicast_ttl_v4(ttl)?;
+    if let Some(interface) = interface {
+        let addr = interface_ipv4(interface).ok_or_else(|| {
+            Report::msg(format!(
+                "Network interface {} has no IPv4 address",
+                interface.name
+            ))
+        })?;
+        SockRef::from(socket).set_multicast_if_v4(&addr)?;
+    }
+    Ok(())
+}
+
+/// Join `group` on each interface, return the number of interfaces joined.
+/// Failures are logged, not fatal: other interfaces may still be usable.
+pub fn join_v4(socket: &UdpSocket, group: &Ipv4Addr, interfaces: &[NetworkInterface]) -> usize {
+    let group = IpAddr::V4(*group);
+    interfaces
+        .iter()
+        .filter(|i| join(socket, &group, i))
+        .count()
+}
+
+/// Join `group` on each interface, return the number of interfaces joined.
+/// Failures are logged, not fatal: other interfaces may still be usable.
+pub fn join_v6(socket: &UdpSocket, group: &Ipv6Addr, interfaces: &[NetworkInterface]) -> usize {
+    let group = IpAddr::V6(*group);
+    interfaces
+        .iter()
+        .filter(|i| join(socket, &group, i))
+        .count()
+}
+
+fn join(socket: &UdpSocket, group: &IpAddr, interface: &NetworkInterface) -> bool {
+    let res = match group {
+        IpAddr::V4(group) => {
+            let Some(addr) = interface_ipv4(interface) else {
+                warn!(%group, interface = %interface.name, "No IPv4 address, cannot join multicast group.");
+                return false;
+            };
+            socket.join_multicast_v4(group, &addr)
+        }
+        IpAddr::V6(group) => socket.join_multicast_v6(group, interface.index),
+    };
+    match res {
+        Ok(()) => {
+            info!(%group, interface = %interface.name, "Joined multicast group.");
+            true
+        }
+        Err(error) => {
+            warn!(?error, %group, interface = %interface.name, "Cannot join multicast group.");
+            false
+        }
+    }
+}
+
+/// Multicast group joined on interfaces that can come and go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Membership {
+    pub group: IpAddr,
+    /// Interface names, all the multicast capable interfaces if empty.
+    pub interfaces: Vec<String>,
+    /// Indexes of the interfaces where the group is joined.
+    joined: Vec<u32>,
+}
+
+impl Membership {
+    pub fn new(group: IpAddr, interfaces: Vec<String>) -> Self {
+        Self {
+            group,
+            interfaces,
+            joined: Vec::new(),
+        }
+    }
+
+    /// Join the group on the interfaces that appeared since the last call, forget the ones that
+    /// disappeared (the kernel drops their memberships). Return the number of interfaces where
+    /// the group is joined.
+    pub fn refresh(&mut self, socket: &UdpSocket) -> usize {
+        let present = present_interfaces(&self.interfaces);
+        self.joined
+            .retain(|index| present.iter().any(|i| i.index == *index));
+        for interface in present {
+            if !self.joined.contains(&interface.index) && join(socket, &self.group, &interface) {
+                self.joined.push(interface.index);
+            }
+        }
+        self.joined.len()
+    }
+
+    /// Leave the group on the interfaces where it is joined, to join another one on the same
+    /// socket. Failures are logged only.
+    pub fn leave(&self, socket: &UdpSocket) {
+        for index in self.joined.iter() {
+            let res = match self.group {
+                IpAddr::V4(group) => SockRef::from(socket)
+                    .leave_multicast_v4_n(&group, &InterfaceIndexOrAddress::Index(*index)),
+                IpAddr::V6(group) => socket.leave_multicast_v6(&group, *index),
+            };
+            match res {
+                Ok(()) => info!(group = %self.group, index, "Left multicast group."),
+                Err(error) => {
+                    warn!(?error, group = %self.group, index, "Cannot leave multicast group.")
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interfaces::NetworkAddress;
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_bind_v6_along_v4() {
+        let socket_v4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let port = socket_v4.local_addr().unwrap().port();
+        let socket_v6 = bind_v6(port).unwrap();
+        assert_eq!(socket_v6.local_addr().unwrap().port(), port);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_join_v4_loopback() {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let lo = NetworkInterface {
+            name: "lo".into(),
+            addresses: vec![NetworkAddress {
+                addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                prefix: 8,
+            }],
+            ..Default::default()
+        };
+        let no_ipv4 = NetworkInterface {
+            name: "no-ipv4".into(),
+            ..Default::default()
+        };
+        let group = Ipv4Addr::new(239, 255, 19, 1);
+        assert_eq!(join_v4(&socket, &group, &[lo, no_ipv4]), 1);
+        assert!(logs_contain("No IPv4 address"));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_membership_refresh() {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let group = IpAddr::V4(Ipv4Addr::new(239, 255, 19, 1));
+        let mut missing = Membership::new(group, vec!["eth-missing".into()]);
+        assert_eq!(missing.refresh(&socket), 0);
+        let mut membership = Membership::new(group, vec!["lo".into(), "eth-missing".into()]);
+        assert_eq!(membership.refresh(&socket), 1);
+        // Already joined: not joined again, which would fail.
+        assert_eq!(membership.refresh(&socket), 1);
+        assert!(!logs_contain("Cannot join"));
+    }
+
+    #[test]
+    fn test_select_interfaces() {
+        let interface = |name: &str, index, up, multicast| NetworkInterface {
+            name: name.into(),
+            index,
+            up,
+            multicast,
+            ..Default::default()
+        };
+        let interfaces = vec![
+            interface("eth0", 2, true, true),
+            interface("eth1", 3, false, true),
+            interface("lo", 1, true, false),
+        ];
+        let names = |selected: &[NetworkInterface]| -> Vec<String> {
+            selected.iter().map(|i| i.name.clone()).collect()
+        };
+        let all = select_interfaces(interfaces.clone(), &[]).unwrap();
+        assert_eq!(names(&all), ["eth0"]);
+        let chosen = select_interfaces(interfaces.clone(), &["lo".into(), "eth1".into()]).unwrap();
+        assert_eq!(names(&chosen), ["lo", "eth1"]);
+        assert!(select_interfaces(interfaces, &["wlan0".into()]).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+use color_eyre::eyre::Report;
+use nix::libc;
+use nix::net::if_::{if_nameindex, if_nametoindex};
+use nix::sys::socket::{
+    recvmsg, sendmsg, setsockopt, sockopt, AddressFamily, ControlMessage, ControlMessageOwned,
+    MsgFlags, SockaddrLike, SockaddrStorage,
+};
+use std::fmt;
+use std::io::{self, IoSlice, IoSliceMut};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::os::fd::AsRawFd;
+
+/// Where a datagram was received, as reported by IP_PKTINFO or IPV6_PKTINFO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketInfo {
+    /// Kernel index of the receiving interface.
+    pub interface: u32,
+    /// Destination address of the datagram: a local, broadcast or multicast address.
+    pub destination: IpAddr,
+    /// Local address the datagram was received on, None for IPv6 multicast.
+    pub local_addr: Option<IpAddr>,
+}
+
+/// How a datagram was addressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    Unicast,
+    Broadcast,
+    Multicast,
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unicast => write!(f, "unicast"),
+            Self::Broadcast => write!(f, "broadcast"),
+            Self::Multicast => write!(f, "multicast"),
+        }
+    }
+}
+
+impl PacketInfo {
+    pub fn destination_kind(&self) -> Destination {
+        if self.destination.is_multicast() {
+            Destination::Multicast
+        } else if self.local_addr.is_some_and(|a| a != self.destination) {
+            // Limited or subnet-directed broadcast: not one of the local addresses.
+            Destination::Broadcast
+        } else {
+            Destination::Unicast
+        }
+    }
+
+    /// Name of the receiving interface, None if it disappeared meanwhile.
+    pub fn interface_name(&self) -> Option<String> {
+        if_nameindex()
+            .ok()?
+            .iter()
+            .find(|i| i.index() == self.interface)
+            .map(|i| i.name().to_string_lossy().into_owned())
+    }
+
+    /// True if the datagram was received on one of the named interfaces.
+    /// Names are resolved at each call, following interfaces that are re-created.
+    pub fn is_on(&self, names: &[String]) -> bool {
+        names
+            .iter()
+            .any(|n| if_nametoindex(n.as_str()).is_ok_and(|i| i == self.interface))
+    }
+}
+
+/// Ask the kernel to report the `PacketInfo` of the datagrams received on the socket.
+pub fn enable_packet_info(socket: &UdpSocket) -> Result<(), Report> {
+    match socket.local_addr()? {
+        SocketAddr::V4(_) => setsockopt(socket, sockopt::Ipv4PacketInfo, &true)?,
+        SocketAddr::V6(_) => setsockopt(socket, sockopt::Ipv6RecvPacketInfo, &true)?,
+    }
+    Ok(())
+}
+
+/// Receive a datagram in `buf`, like `UdpSocket::recv_from`, along with its `PacketInfo`
+/// if enabled on the socket.
+pub fn recv_with_info(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<PacketInfo>)> {
+    let mut iov = [IoSliceMut::new(buf)];
+    let mut cmsg_buffer = nix::cmsg_space!(libc::in6_pktinfo);
+    let msg = recvmsg::<SockaddrStorage>(
+        socket.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg_buffer),
+        MsgFlags::empty(),
+    )?;
+    let source = msg
+        .address
+        .as_ref()
+        .and_then(to_socket_addr)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no source address"))?;
+    let mut info = None;
+    for cmsg in msg.cmsgs() {
+        match cmsg {
+            ControlMessageOwned::Ipv4PacketInfo(i) => {
+                info = Some(PacketInfo {
+                    interface: i.ipi_ifindex as u32,
+                    destination: IpAddr::V4(Ipv4Addr::from(u32::from_be(i.ipi_addr.s_addr))),
+                    local_addr: Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                        i.ipi_spec_dst.s_addr,
+                    )))),
+                })
+            }
+            ControlMessageOwned::Ipv6PacketInfo(i) => {
+                let destination = Ipv6Addr::from(i.ipi6_addr.s6_addr);
+                info = Some(PacketInfo {
+                    interface: i.ipi6_ifindex,
+                    destination: IpAddr::V6(destination),
+                    local_addr: match destination.is_multicast() {
+                        true => None,
+                        false => Some(IpAddr::V6(destination)),
+                    },
+                })
+            }
+            _ => (),
+        }
+    }
+    Ok((msg.bytes, source, info))
+}
+
+/// Send a datagram like `UdpSocket::send_to`, from the local address and interface a request
+/// was received on, so that the scanner sees the answer coming from the address it reached.
+pub fn send_from(
+    socket: &UdpSocket,
+    buf: &[u8],
+    addr: &SocketAddr,
+    info: Option<&PacketInfo>,
+) -> io::Result<usize> {
+    let iov = [IoSlice::new(buf)];
+    let destination = SockaddrStorage::from(*addr);
+    let send = |cmsgs: &[ControlMessage]| {
+        sendmsg(
+            socket.as_raw_fd(),
+            &iov,
+            cmsgs,
+            MsgFlags::empty(),
+            Some(&destination),
+        )
+        .map_err(io::Error::from)
+    };
+    match info.map(|i| (i.interface, i.local_addr)) {
+        Some((_, Some(IpAddr::V4(local)))) => {
+            let pktinfo = libc::in_pktinfo {
+                ipi_ifindex: 0, // routed as usual, only the source address is set
+                ipi_spec_dst: libc::in_addr {
+                    s_addr: u32::from(local).to_be(),
+                },
+                ipi_addr: libc::in_addr { s_addr: 0 },
+            };
+            send(&[ControlMessage::Ipv4PacketInfo(&pktinfo)])
+        }
+        Some((interface, local)) if addr.is_ipv6() => {
+            let local = match local {
+                Some(IpAddr::V6(local)) => local,
+                _ => Ipv6Addr::UNSPECIFIED, // chosen by the kernel on `interface`
+            };
+            let pktinfo = libc::in6_pktinfo {
+                ipi6_addr: libc::in6_addr {
+                    s6_addr: local.octets(),
+                },
+                ipi6_ifindex: interface,
+            };
+            send(&[ControlMessage::Ipv6PacketInfo(&pktinfo)])
+        }
+        _ => send(&[]),
+    }
+}
+
+fn to_socket_addr(addr: &SockaddrStorage) -> Option<SocketAddr> {
+    match addr.family()? {
+        AddressFamily::Inet => addr.as_sockaddr_in().map(|a| SocketAddr::V4((*a).into())),
+        AddressFamily::Inet6 => addr.as_sockaddr_in6().map(|a| SocketAddr::V6((*a).into())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::multicast::bind_v6;
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_recv_with_info() {
+        let lo = if_nametoindex("lo").unwrap();
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        enable_packet_info(&socket).unwrap();
+        let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        sender
+            .send_to(
+                b"ping",
+                (Ipv4Addr::LOCALHOST, socket.local_addr().unwrap().port()),
+            )
+            .unwrap();
+        let mut buf = [0; 16];
+        let (length, source, info) = recv_with_info(&socket, &mut buf).unwrap();
+        assert_eq!(&buf[..length], b"ping");
+        assert_eq!(source, sender.local_addr().unwrap());
+        let info = info.unwrap();
+        assert_eq!(
+            info,
+            PacketInfo {
+                interface: lo,
+                destination: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                local_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            }
+        );
+        assert_eq!(info.destination_kind(), Destination::Unicast);
+        assert_eq!(info.interface_name().as_deref(), Some("lo"));
+        assert!(info.is_on(&["eth-missing".into(), "lo".into()]));
+        assert!(!info.is_on(&["eth-missing".into()]));
+
+        send_from(&socket, b"pong", &source, Some(&info)).unwrap();
+        let (length, reply_source) = sender.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..length], b"pong");
+        assert_eq!(reply_source.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        let Ok(socket) = bind_v6(0) else {
+            return; // no IPv6 on this system
+        };
+        enable_packet_info(&socket).unwrap();
+        let sender = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+        sender
+            .send_to(
+                b"ping",
+                (Ipv6Addr::LOCALHOST, socket.local_addr().unwrap().port()),
+            )
+            .unwrap();
+        let (_, source, info) = recv_with_info(&socket, &mut buf).unwrap();
+        assert_eq!(source, sender.local_addr().unwrap());
+        let info = info.unwrap();
+        assert_eq!(info.destination, IpAddr::V6(Ipv6Addr::LOCALHOST));
+        assert_eq!(info.local_addr, Some(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        send_from(&socket, b"pong", &source, Some(&info)).unwrap();
+        assert_eq!(sender.recv_from(&mut buf).unwrap().0, 4);
+    }
+
+    #[test]
+    fn test_destination_kind() {
+        let info = |destination: &str, local: Option<&str>| PacketInfo {
+            interface: 2,
+            destination: destination.parse().unwrap(),
+            local_addr: local.map(|l| l.parse().unwrap()),
+        };
+        let kind = |i: PacketInfo| i.destination_kind();
+        assert_eq!(
+            kind(info("192.168.1.10", Some("192.168.1.10"))),
+            Destination::Unicast
+        );
+        assert_eq!(
+            kind(info("255.255.255.255", Some("192.168.1.10"))),
+            Destination::Broadcast
+        );
+        assert_eq!(
+            kind(info("192.168.1.255", Some("192.168.1.10"))),
+            Destination::Broadcast
+        );
+        assert_eq!(
+            kind(info("239.255.19.1", Some("192.168.1.10"))),
+            Destination::Multicast
+        );
+        assert_eq!(kind(info("ff02::1901", None)), Destination::Multicast);
+        assert_eq!(kind(info("fe80::1", Some("fe80::1"))), Destination::Unicast);
+    }
+}
@@ -0,0 +1,76 @@
+use crate::auth::AuthKey;
+use crate::signature::Signature;
+use glob::Pattern;
+
+/// Name of the profile answering everything to `ServerConfig.signatures` and `auth_keys`.
+pub const FULL_PROFILE_NAME: &str = "full";
+
+/// Inventory subset answered to the requests with one of the profile signatures or keys.
+/// Keys and sources are glob patterns. Empty allow lists allow everything, deny lists take
+/// precedence.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessProfile {
+    pub name: String,
+    pub signatures: Vec<Signature>,
+    pub auth_keys: Vec<AuthKey>,
+    /// Keys of the answer.
+    pub allow_keys: Vec<Pattern>,
+    pub deny_keys: Vec<Pattern>,
+    /// Inventory file paths, or names of the built-in sources (e.g. `hostname`).
+    pub allow_sources: Vec<Pattern>,
+    pub deny_sources: Vec<Pattern>,
+}
+
+impl AccessProfile {
+    /// Whole inventory.
+    pub fn full(signatures: Vec<Signature>, auth_keys: Vec<AuthKey>) -> Self {
+        Self {
+            name: FULL_PROFILE_NAME.into(),
+            signatures,
+            auth_keys,
+            allow_keys: Vec::new(),
+            deny_keys: Vec::new(),
+            allow_sources: Vec::new(),
+            deny_sources: Vec::new(),
+        }
+    }
+
+    pub fn allows_key(&self, key: &str) -> bool {
+        is_allowed(key, &self.allow_keys, &self.deny_keys)
+    }
+
+    pub fn allows_source(&self, name: &str) -> bool {
+        is_allowed(name, &self.allow_sources, &self.deny_sources)
+    }
+}
+
+fn is_allowed(name: &str, allow: &[Pattern], deny: &[Pattern]) -> bool {
+    (allow.is_empty() || allow.iter().any(|p| p.matches(name)))
+        && !deny.iter().any(|p| p.matches(name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_allows() {
+        let patterns = |p: &[&str]| p.iter().map(|p| Pattern::new(p).unwrap()).collect();
+        let full = AccessProfile::full(Vec::new(), Vec::new());
+        assert!(full.allows_key("serial"));
+        assert!(full.allows_source("/usr/bin/inventory-secret"));
+        let public = AccessProfile {
+            name: "public".into(),
+            allow_keys: patterns(&["hostname", "fw_*"]),
+            deny_keys: patterns(&["fw_secret"]),
+            deny_sources: patterns(&["/usr/bin/inventory-secret*"]),
+            ..full
+        };
+        assert!(public.allows_key("hostname"));
+        assert!(public.allows_key("fw_version"));
+        assert!(!public.allows_key("fw_secret"));
+        assert!(!public.allows_key("serial"));
+        assert!(public.allows_source("hostname"));
+        assert!(!public.allows_source("/usr/bin/inventory-secret-keys"));
+    }
+}
@@ -0,0 +1,468 @@
+//! Framing of the messages exchanged by scanners and servers.
+//!
+//! Every message starts with a header: magic, version, message type, flags and request id.
+//! Datagrams without the magic are in the legacy format: the bare signature for requests,
+//! the bare JSON answer for answers. Legacy requests are answered in the legacy format.
+//!
+//! Later protocol versions keep the meaning and layout of the existing flags, and only add
+//! flags: messages of a newer version are decoded as messages of this one, and answered with
+//! this version, telling the scanner which flags the server knows.
+
+use crate::answers::Answer;
+use crate::query::{Query, QUERY_MAX_LENGTH};
+use crate::signature::Signature;
+use bytes::{Buf, BufMut, BytesMut};
+use std::fmt;
+use std::ops::BitOr;
+use thiserror::Error;
+use x25519_dalek::PublicKey;
+
+pub const PROTOCOL_MAGIC: &[u8; 4] = b"IPDP";
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Version of the messages in the legacy format, without header.
+pub const LEGACY_VERSION: u8 = 0;
+/// Magic, version (u8), message type (u8), flags (u16), request id (u32), all big-endian.
+pub const HEADER_LENGTH: usize = 12;
+const KEY_LENGTH: usize = 32; // X25519
+/// Longest credential accepted: plain signature or authenticated request.
+pub const CREDENTIAL_MAX_LENGTH: usize = 128;
+pub const REQUEST_MAX_LENGTH: usize =
+    HEADER_LENGTH + KEY_LENGTH + 2 + QUERY_MAX_LENGTH + CREDENTIAL_MAX_LENGTH;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    /// From scanners.
+    Request = 1,
+    /// From servers, to a request.
+    Answer = 2,
+    /// From servers, unsolicited: the answer sent at start, on inventory change and
+    /// periodically.
+    Announcement = 3,
+    /// From servers, unsolicited: the server is going away.
+    Goodbye = 4,
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Self::Request),
+            2 => Ok(Self::Answer),
+            3 => Ok(Self::Announcement),
+            4 => Ok(Self::Goodbye),
+            other => Err(ProtocolError::UnknownMessageType(other)),
+        }
+    }
+}
+
+/// Capabilities of the scanner in requests, what was applied to the payload in answers.
+/// New flags may add fields to the message body, that would be misparsed (e.g. as the
+/// credential): messages with flags unknown to this version are rejected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flags(pub u16);
+
+impl Flags {
+    pub const NONE: Self = Self(0);
+    /// Request: the scanner verifies signed answers. Answer: the payload is signed.
+    pub const SIGNED: Self = Self(1);
+    /// Request: the scanner public key follows the header. Answer: the payload is encrypted.
+    pub const ENCRYPTED: Self = Self(1 << 1);
+    /// Request: the list of requested keys follows the header (and the scanner key).
+    pub const QUERY: Self = Self(1 << 2);
+    /// All the flags of this protocol version.
+    pub const KNOWN: Self = Self(Self::SIGNED.0 | Self::ENCRYPTED.0 | Self::QUERY.0);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Flags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#06x}", self.0)
+    }
+}
+
+/// Reason why a message cannot be decoded.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ProtocolError {
+    #[error("message too short")]
+    TooShort,
+    #[error("unsupported protocol version {0}")]
+    UnsupportedVersion(u8),
+    #[error("unknown flags {0}")]
+    UnknownFlags(Flags),
+    #[error("unknown message type {0}")]
+    UnknownMessageType(u8),
+    #[error("unexpected message type {0:?}")]
+    UnexpectedMessageType(MessageType),
+    #[error("malformed {0}")]
+    Malformed(&'static str),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub message_type: MessageType,
+    pub flags: Flags,
+    /// Chosen by the scanner, repeated in the answer.
+    pub request_id: u32,
+}
+
+impl Header {
+    pub fn new(message_type: MessageType, flags: Flags, request_id: u32) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            message_type,
+            flags,
+            request_id,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut BytesMut) {
+        buf.put_slice(PROTOCOL_MAGIC);
+        buf.put_u8(self.version);
+        buf.put_u8(self.message_type as u8);
+        buf.put_u16(self.flags.0);
+        buf.put_u32(self.request_id);
+    }
+
+    /// Header and the rest of the message, None if the message is in the legacy format.
+    pub fn decode(message: &[u8]) -> Option<Result<(Self, &[u8]), ProtocolError>> {
+        if !message.starts_with(PROTOCOL_MAGIC) {
+            return None;
+        }
+        if message.len() < HEADER_LENGTH {
+            return Some(Err(ProtocolError::TooShort));
+        }
+        let mut fields = &message[PROTOCOL_MAGIC.len()..HEADER_LENGTH];
+        let version = fields.get_u8();
+        if version < PROTOCOL_VERSION {
+            return Some(Err(ProtocolError::UnsupportedVersion(version)));
+        }
+        let message_type = match MessageType::try_from(fields.get_u8()) {
+            Ok(t) => t,
+            Err(error) => return Some(Err(error)),
+        };
+        let flags = Flags(fields.get_u16());
+        if !Flags::KNOWN.contains(flags) {
+            return Some(Err(ProtocolError::UnknownFlags(flags)));
+        }
+        let header = Self {
+            version,
+            message_type,
+            flags,
+            request_id: fields.get_u32(),
+        };
+        Some(Ok((header, &message[HEADER_LENGTH..])))
+    }
+}
+
+/// Discovery request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    /// `LEGACY_VERSION` if received in the legacy format.
+    pub version: u8,
+    pub request_id: u32,
+    /// Capabilities of the scanner. `ENCRYPTED` and `QUERY` are set from `answer_key` and
+    /// `query` when encoding.
+    pub flags: Flags,
+    /// Key to encrypt the answer to.
+    pub answer_key: Option<PublicKey>,
+    /// Requested keys, the whole answer if empty.
+    pub query: Query,
+    /// Plain signature or authenticated request.
+    pub credential: Signature,
+}
+
+impl Request {
+    /// Request in the current format, asking for signed answers.
+    pub fn new(request_id: u32, credential: Signature) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            request_id,
+            flags: Flags::SIGNED,
+            answer_key: None,
+            query: Query::default(),
+            credential,
+        }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == LEGACY_VERSION
+    }
+
+    /// The bare credential for legacy requests.
+    pub fn encode(&self) -> Signature {
+        if self.is_legacy() {
+            return self.credential.clone();
+        }
+        let mut flags = self.flags;
+        if self.answer_key.is_some() {
+            flags = flags | Flags::ENCRYPTED;
+        }
+        if !self.query.is_empty() {
+            flags = flags | Flags::QUERY;
+        }
+        let list = self.query.keys.join(",");
+        let mut res = BytesMut::with_capacity(
+            HEADER_LENGTH + KEY_LENGTH + 2 + list.len() + self.credential.0.len(),
+        );
+        Header::new(MessageType::Request, flags, self.request_id).encode(&mut res);
+        if let Some(key) = &self.answer_key {
+            res.put_slice(key.as_bytes());
+        }
+        if !self.query.is_empty() {
+            res.put_u16(list.len() as u16);
+            res.put_slice(list.as_bytes());
+        }
+        res.put_slice(&self.credential.0);
+        Signature(res.freeze())
+    }
+
+    /// Datagrams without header are legacy requests, the whole datagram being the credential.
+    pub fn decode(datagram: &[u8]) -> Result<Self, ProtocolError> {
+        let (header, mut body) = match Header::decode(datagram) {
+            None => {
+                return Ok(Self {
+                    version: LEGACY_VERSION,
+                    flags: Flags::NONE,
+                    ..Self::new(0, Signature::from(datagram))
+                })
+            }
+            Some(decoded) => decoded?,
+        };
+        if header.message_type != MessageType::Request {
+            return Err(ProtocolError::UnexpectedMessageType(header.message_type));
+        }
+        let answer_key = match header.flags.contains(Flags::ENCRYPTED) {
+            true if body.len() < KEY_LENGTH => return Err(ProtocolError::Malformed("answer key")),
+            true => {
+                let mut key = [0; KEY_LENGTH];
+                body.copy_to_slice(&mut key);
+                Some(PublicKey::from(key))
+            }
+            false => None,
+        };
+        let query = match header.flags.contains(Flags::QUERY) {
+            true => decode_query(&mut body)?,
+            false => Query::default(),
+        };
+        Ok(Self {
+            version: header.version,
+            request_id: header.request_id,
+            flags: header.flags,
+            answer_key,
+            query,
+            credential: Signature::from(body),
+        })
+    }
+}
+
+fn decode_query(body: &mut &[u8]) -> Result<Query, ProtocolError> {
+    if body.len() < 2 {
+        return Err(ProtocolError::Malformed("query"));
+    }
+    let length = body.get_u16() as usize;
+    if length > QUERY_MAX_LENGTH || body.len() < length {
+        return Err(ProtocolError::Malformed("query"));
+    }
+    let list =
+        std::str::from_utf8(&body[..length]).map_err(|_| ProtocolError::Malformed("query"))?;
+    let query = Query::parse(list).map_err(|_| ProtocolError::Malformed("query"))?;
+    body.advance(length);
+    Ok(query)
+}
+
+/// Message sent by servers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerMessage {
+    /// `LEGACY_VERSION` for bare answers, sent to legacy requests.
+    pub version: u8,
+    pub message_type: MessageType,
+    /// What was applied to the payload: `SIGNED`, `ENCRYPTED`.
+    pub flags: Flags,
+    /// Of the request answered, 0 for unsolicited messages.
+    pub request_id: u32,
+    pub payload: Answer,
+}
+
+impl ServerMessage {
+    /// Answer to `request`, in the same format.
+    pub fn answer(request: &Request, flags: Flags, payload: Answer) -> Self {
+        Self {
+            version: match request.is_legacy() {
+                true => LEGACY_VERSION,
+                false => PROTOCOL_VERSION,
+            },
+            message_type: MessageType::Answer,
+            flags,
+            request_id: request.request_id,
+            payload,
+        }
+    }
+
+    /// Unsolicited answer, always in the current format.
+    pub fn announcement(flags: Flags, payload: Answer) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            message_type: MessageType::Announcement,
+            flags,
+            request_id: 0,
+            payload,
+        }
+    }
+
+    /// Sent when stopping, the payload is signed to be told apart from forged ones.
+    pub fn goodbye(flags: Flags, payload: Answer) -> Self {
+        Self {
+            message_type: MessageType::Goodbye,
+            ..Self::announcement(flags, payload)
+        }
+    }
+
+    pub fn encode(&self) -> Answer {
+        if self.version == LEGACY_VERSION {
+            return self.payload.clone();
+        }
+        let mut res = BytesMut::with_capacity(HEADER_LENGTH + self.payload.0.len());
+        Header::new(self.message_type, self.flags, self.request_id).encode(&mut res);
+        res.put_slice(&self.payload.0);
+        Answer(res.freeze())
+    }
+
+    /// Messages without header are bare answers from legacy servers.
+    pub fn decode(message: &Answer) -> Result<Self, ProtocolError> {
+        let (header, body) = match Header::decode(&message.0) {
+            None => {
+                return Ok(Self {
+                    version: LEGACY_VERSION,
+                    message_type: MessageType::Answer,
+                    flags: Flags::NONE,
+                    request_id: 0,
+                    payload: message.clone(),
+                })
+            }
+            Some(decoded) => decoded?,
+        };
+        if header.message_type == MessageType::Request {
+            return Err(ProtocolError::UnexpectedMessageType(header.message_type));
+        }
+        Ok(Self {
+            version: header.version,
+            message_type: header.message_type,
+            flags: header.flags,
+            request_id: header.request_id,
+            payload: Answer(message.0.slice(message.0.len() - body.len()..)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        let mut request = Request::new(42, Signature::from("ipdisbeacon"));
+        assert_eq!(Request::decode(&request.encode().0), Ok(request.clone()));
+        request.answer_key = Some(PublicKey::from([7; KEY_LENGTH]));
+        request.query = Query::parse("hostname,fw_*").unwrap();
+        let encoded = request.encode();
+        let decoded = Request::decode(&encoded.0).unwrap();
+        assert_eq!(
+            decoded.flags,
+            Flags::SIGNED | Flags::ENCRYPTED | Flags::QUERY
+        );
+        assert_eq!(
+            decoded,
+            Request {
+                flags: decoded.flags,
+                ..request.clone()
+            }
+        );
+        assert_eq!(
+            Request::decode(&encoded.0[..HEADER_LENGTH + 4]),
+            Err(ProtocolError::Malformed("answer key"))
+        );
+        // Newer scanner: decoded, and answered with this version.
+        let mut newer = encoded.0.to_vec();
+        newer[PROTOCOL_MAGIC.len()] = PROTOCOL_VERSION + 1;
+        let decoded = Request::decode(&newer).unwrap();
+        assert_eq!(decoded.version, PROTOCOL_VERSION + 1);
+        assert_eq!(decoded.query, request.query);
+        let answer = ServerMessage::answer(&decoded, Flags::NONE, Answer::from(&b"{}"[..]));
+        assert_eq!(answer.version, PROTOCOL_VERSION);
+        // Unknown flag, its fields would be taken for the credential.
+        let mut unknown_flag = newer;
+        unknown_flag[PROTOCOL_MAGIC.len() + 2] = 0x80;
+        assert!(matches!(
+            Request::decode(&unknown_flag),
+            Err(ProtocolError::UnknownFlags(_))
+        ));
+    }
+
+    #[test]
+    fn test_legacy_request() {
+        let request = Request::decode(b"ipdisbeacon").unwrap();
+        assert!(request.is_legacy());
+        assert_eq!(request.credential, Signature::from("ipdisbeacon"));
+        assert_eq!(request.encode(), Signature::from("ipdisbeacon"));
+        let answer = ServerMessage::answer(&request, Flags::NONE, Answer::from(&b"{}"[..]));
+        assert_eq!(answer.encode(), Answer::from(&b"{}"[..]));
+        assert_eq!(ServerMessage::decode(&answer.encode()), Ok(answer));
+    }
+
+    #[test]
+    fn test_answer() {
+        let request = Request::new(42, Signature::from("ipdisbeacon"));
+        let answer = ServerMessage::answer(&request, Flags::SIGNED, Answer::from(&b"{}"[..]));
+        let encoded = answer.encode();
+        assert_eq!(encoded.0.len(), HEADER_LENGTH + 2);
+        assert_eq!(ServerMessage::decode(&encoded), Ok(answer.clone()));
+        assert_eq!(
+            ServerMessage::decode(&Answer::from(&request.encode().0[..])),
+            Err(ProtocolError::UnexpectedMessageType(MessageType::Request))
+        );
+        let mut newer = encoded.0.to_vec();
+        newer[PROTOCOL_MAGIC.len()] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            ServerMessage::decode(&Answer::from(newer.as_slice())),
+            Ok(ServerMessage {
+                version: PROTOCOL_VERSION + 1,
+                ..answer
+            })
+        );
+        let mut unknown_flag = encoded.0.to_vec();
+        unknown_flag[PROTOCOL_MAGIC.len() + 3] |= 1 << 3;
+        assert_eq!(
+            ServerMessage::decode(&Answer::from(unknown_flag.as_slice())),
+            Err(ProtocolError::UnknownFlags(Flags::SIGNED | Flags(1 << 3)))
+        );
+        let mut older = encoded.0.to_vec();
+        older[PROTOCOL_MAGIC.len()] = LEGACY_VERSION;
+        assert_eq!(
+            ServerMessage::decode(&Answer::from(older.as_slice())),
+            Err(ProtocolError::UnsupportedVersion(LEGACY_VERSION))
+        );
+        assert_eq!(
+            ServerMessage::decode(&Answer::from(&b"IPDP"[..])),
+            Err(ProtocolError::TooShort)
+        );
+        let announcement = ServerMessage::announcement(Flags::SIGNED, Answer::from(&b"{}"[..]));
+        let encoded = announcement.encode();
+        assert_eq!(encoded.0[PROTOCOL_MAGIC.len() + 1], 3);
+        assert_eq!(ServerMessage::decode(&encoded), Ok(announcement));
+        let goodbye = ServerMessage::goodbye(Flags::SIGNED, Answer::from(&b"{}"[..]));
+        assert_eq!(ServerMessage::decode(&goodbye.encode()), Ok(goodbye));
+    }
+}
@@ -0,0 +1,62 @@
+use color_eyre::eyre::Report;
+
+/// Maximum length of the comma separated key list, keeping requests in a small datagram.
+pub const QUERY_MAX_LENGTH: usize = 512;
+
+/// Keys requested by the scanner. Keys ending with `*` are prefixes.
+/// An empty query requests the whole answer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Query {
+    pub keys: Vec<String>,
+}
+
+impl Query {
+    /// Parse a comma separated list of keys, e.g. `hostname,fw_*`.
+    pub fn parse(list: &str) -> Result<Self, Report> {
+        let query = Self {
+            keys: list
+                .split(',')
+                .map(str::trim)
+                .filter(|k| !k.is_empty())
+                .map(String::from)
+                .collect(),
+        };
+        let length = query.keys.join(",").len();
+        if length > QUERY_MAX_LENGTH {
+            return Err(Report::msg(format!(
+                "Query too long: {} bytes, maximum is {}",
+                length, QUERY_MAX_LENGTH
+            )));
+        }
+        Ok(query)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        self.is_empty()
+            || self.keys.iter().any(|k| match k.strip_suffix('*') {
+                Some(prefix) => key.starts_with(prefix),
+                None => key == k,
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let query = Query::parse("hostname, fw_*,").unwrap();
+        assert_eq!(query.keys, vec!["hostname", "fw_*"]);
+        assert!(query.matches("hostname"));
+        assert!(query.matches("fw_version"));
+        assert!(!query.matches("hostname2"));
+        assert!(!query.matches("serial"));
+        assert!(Query::parse("").unwrap().matches("serial"));
+        assert!(Query::parse(&"k,".repeat(QUERY_MAX_LENGTH)).is_err());
+    }
+}
@@ -0,0 +1,310 @@
+use crate::conf::RATE_LIMIT_TIMEOUT_DEFAULT;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, trace};
+
+pub const RATE_LIMIT_BURST_DEFAULT: u32 = 1;
+pub const RATE_LIMIT_GLOBAL_DEFAULT: u32 = 100; // answers per second
+pub const RATE_LIMIT_MAX_CLIENTS_DEFAULT: usize = 4096;
+pub const RATE_LIMIT_IPV4_PREFIX_DEFAULT: u8 = 32; // each address
+pub const RATE_LIMIT_IPV6_PREFIX_DEFAULT: u8 = 128;
+
+/// Token buckets limiting the answers, for each client and for all of them together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    /// Time for a client to earn a new answer.
+    pub interval: Duration,
+    /// Answers a client can get in a row.
+    pub burst: u32,
+    /// Prefix length grouping IPv4 clients: 32 limits each address, 24 each /24 subnet.
+    pub ipv4_prefix: u8,
+    /// Prefix length grouping IPv6 clients, e.g. 64 for a whole subnet.
+    pub ipv6_prefix: u8,
+    /// Answers per second to all the clients together, 0 for no limit.
+    pub global_rate: u32,
+    /// Clients remembered, the least recently seen ones are forgotten first.
+    pub max_clients: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            interval: RATE_LIMIT_TIMEOUT_DEFAULT,
+            burst: RATE_LIMIT_BURST_DEFAULT,
+            ipv4_prefix: RATE_LIMIT_IPV4_PREFIX_DEFAULT,
+            ipv6_prefix: RATE_LIMIT_IPV6_PREFIX_DEFAULT,
+            global_rate: RATE_LIMIT_GLOBAL_DEFAULT,
+            max_clients: RATE_LIMIT_MAX_CLIENTS_DEFAULT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: SystemTime,
+    last_use: u64,
+}
+
+impl Bucket {
+    fn full(capacity: f64, now: SystemTime) -> Self {
+        Self {
+            tokens: capacity,
+            updated: now,
+            last_use: 0,
+        }
+    }
+
+    /// Add the tokens earned since the last update, up to `capacity`.
+    fn refill(&mut self, now: SystemTime, interval: Duration, capacity: f64) {
+        // Nothing earned if the clock went backwards. A zero interval refills everything.
+        let elapsed = now.duration_since(self.updated).unwrap_or_default();
+        self.tokens = (self.tokens + elapsed.as_secs_f64() / interval.as_secs_f64()).min(capacity);
+        self.updated = now;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimiter<'a> {
+    pub config: RateLimitConfig,
+    pub clock: &'a dyn WrappedSystemTime,
+    clients: HashMap<IpAddr, Bucket>,
+    /// Clients by last use, oldest first.
+    lru: BTreeMap<u64, IpAddr>,
+    uses: u64,
+    global: Bucket,
+    /// Requests not answered because of the limits.
+    pub limited: u64,
+}
+
+impl<'a> RateLimiter<'a> {
+    pub fn new(clock: &'a dyn WrappedSystemTime, config: RateLimitConfig) -> Self {
+        let global = Bucket::full(f64::from(config.global_rate), clock.now());
+        Self {
+            config,
+            clock,
+            clients: HashMap::default(),
+            lru: BTreeMap::default(),
+            uses: 0,
+            global,
+            limited: 0,
+        }
+    }
+}
+
+impl RateLimiter<'_> {
+    /// Return true if the client can be answered now, and count the answer.
+    pub fn check(&mut self, addr: &SocketAddr) -> bool {
+        let now = self.clock.now();
+        let client = client_key(addr.ip(), &self.config);
+        if self.client_tokens(client, now) < 1.0 {
+            trace!(%client, "Client rate limited.");
+            self.limited += 1;
+            return false;
+        }
+        if !self.take_global(now) {
+            debug!(%client, "Global rate limit reached.");
+            self.limited += 1;
+            return false;
+        }
+        self.clients
+            .get_mut(&client)
+            .expect("inserted by client_tokens")
+            .tokens -= 1.0;
+        trace!(%client, "Client checked.");
+        true
+    }
+
+    /// Number of clients remembered.
+    pub fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Answers available to the client, after refilling its bucket.
+    fn client_tokens(&mut self, client: IpAddr, now: SystemTime) -> f64 {
+        let capacity = f64::from(self.config.burst);
+        self.uses += 1;
+        let bucket = self
+            .clients
+            .entry(client)
+            .or_insert_with(|| Bucket::full(capacity, now));
+        self.lru.remove(&bucket.last_use);
+        bucket.last_use = self.uses;
+        self.lru.insert(self.uses, client);
+        bucket.refill(now, self.config.interval, capacity);
+        let tokens = bucket.tokens;
+        while self.clients.len() > self.config.max_clients.max(1) {
+            let (_, oldest) = self.lru.pop_first().expect("one entry per client");
+            self.clients.remove(&oldest);
+        }
+        tokens
+    }
+
+    /// Take a token from the bucket shared by all the clients, false if empty.
+    fn take_global(&mut self, now: SystemTime) -> bool {
+        let rate = self.config.global_rate;
+        if rate == 0 {
+            return true;
+        }
+        self.global
+            .refill(now, Duration::from_secs(1) / rate, f64::from(rate));
+        if self.global.tokens < 1.0 {
+            return false;
+        }
+        self.global.tokens -= 1.0;
+        true
+    }
+}
+
+/// Address identifying the client: its own one, or its subnet with a shorter prefix.
+fn client_key(ip: IpAddr, config: &RateLimitConfig) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(config.ipv4_prefix.min(32)))
+                .unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(config.ipv6_prefix.min(128)))
+                .unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+    }
+}
+
+pub trait WrappedSystemTime: std::fmt::Debug {
+    // NB: supertrait
+    fn now(&self) -> SystemTime;
+}
+
+#[derive(Debug, Default)]
+pub struct Clock;
+
+impl WrappedSystemTime for Clock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+pub struct DummyClock {
+    pub time: SystemTime,
+}
+
+#[cfg(test)]
+impl WrappedSystemTime for DummyClock {
+    fn now(&self) -> SystemTime {
+        self.time
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_rate_limiter() {
+        let time = SystemTime::now();
+        let clock = DummyClock { time };
+        let mut rate_limiter = RateLimiter::new(&clock, RateLimitConfig::default());
+        let ip = SocketAddr::from(([10, 11, 12, 13], 1234));
+        assert!(rate_limiter.check(&ip));
+        assert!(!rate_limiter.check(&ip));
+        // Changing source port does not help.
+        assert!(!rate_limiter.check(&SocketAddr::from(([10, 11, 12, 13], 1235))));
+        assert!(rate_limiter.check(&SocketAddr::from(([10, 11, 12, 14], 1234))));
+        assert_eq!(rate_limiter.limited, 2);
+        let time = time + RATE_LIMIT_TIMEOUT_DEFAULT + Duration::from_millis(1);
+        let clock = DummyClock { time };
+        rate_limiter.clock = &clock;
+        assert!(rate_limiter.check(&ip));
+        assert!(!rate_limiter.check(&ip));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_burst_and_subnet() {
+        let time = SystemTime::now();
+        let clock = DummyClock { time };
+        let config = RateLimitConfig {
+            interval: Duration::from_secs(1),
+            burst: 3,
+            ipv4_prefix: 24,
+            ..Default::default()
+        };
+        let mut rate_limiter = RateLimiter::new(&clock, config);
+        let addr = |host| SocketAddr::from(([192, 168, 1, host], 1902));
+        assert!(rate_limiter.check(&addr(1)));
+        assert!(rate_limiter.check(&addr(2)));
+        assert!(rate_limiter.check(&addr(3)));
+        assert!(!rate_limiter.check(&addr(4))); // same /24, burst spent
+        assert_eq!(rate_limiter.clients(), 1);
+        let clock = DummyClock {
+            time: time + Duration::from_millis(1500),
+        };
+        rate_limiter.clock = &clock;
+        assert!(rate_limiter.check(&addr(4)));
+        assert!(!rate_limiter.check(&addr(4)));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_global_rate() {
+        let clock = DummyClock {
+            time: SystemTime::now(),
+        };
+        let config = RateLimitConfig {
+            global_rate: 2,
+            ..Default::default()
+        };
+        let mut rate_limiter = RateLimiter::new(&clock, config);
+        assert!(rate_limiter.check(&SocketAddr::from(([10, 0, 0, 1], 1902))));
+        assert!(rate_limiter.check(&SocketAddr::from(([10, 0, 0, 2], 1902))));
+        assert!(!rate_limiter.check(&SocketAddr::from(([10, 0, 0, 3], 1902))));
+        assert!(logs_contain("Global rate limit reached."));
+        // The refused client did not spend its own token.
+        rate_limiter.config.global_rate = 0;
+        assert!(rate_limiter.check(&SocketAddr::from(([10, 0, 0, 3], 1902))));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_max_clients() {
+        let clock = DummyClock {
+            time: SystemTime::now(),
+        };
+        let config = RateLimitConfig {
+            max_clients: 2,
+            ..Default::default()
+        };
+        let mut rate_limiter = RateLimiter::new(&clock, config);
+        let addr = |host| SocketAddr::from(([10, 0, 0, host], 1902));
+        assert!(rate_limiter.check(&addr(1)));
+        assert!(rate_limiter.check(&addr(2)));
+        assert!(!rate_limiter.check(&addr(1))); // 1 is now the most recent
+        assert!(rate_limiter.check(&addr(3))); // 2 forgotten
+        assert_eq!(rate_limiter.clients(), 2);
+        assert!(!rate_limiter.check(&addr(1)));
+        assert!(rate_limiter.check(&addr(2)));
+    }
+
+    #[test]
+    fn test_client_key() {
+        let config = RateLimitConfig {
+            ipv4_prefix: 16,
+            ipv6_prefix: 64,
+            ..Default::default()
+        };
+        let key = |ip: &str| client_key(ip.parse().unwrap(), &config).to_string();
+        assert_eq!(key("192.168.1.17"), "192.168.0.0");
+        assert_eq!(key("fe80::1:2:3:4"), "fe80::");
+        let key = |ip: &str| client_key(ip.parse().unwrap(), &RateLimitConfig::default());
+        assert_eq!(key("192.168.1.17").to_string(), "192.168.1.17");
+        assert_eq!(key("fe80::1:2:3:4").to_string(), "fe80::1:2:3:4");
+    }
+}
@@ -0,0 +1,62 @@
+use crate::pktinfo::PacketInfo;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+pub const REQUESTERS_MAX: usize = 256;
+pub const REQUESTERS_TIMEOUT: Duration = Duration::from_secs(600); // scanners silent for longer are likely gone
+
+/// Scanners answered recently, with where their request was received, told when the server
+/// goes away.
+#[derive(Debug, Default)]
+pub struct Requesters {
+    seen: HashMap<SocketAddr, (Option<PacketInfo>, Instant)>,
+}
+
+impl Requesters {
+    /// Remember the scanner, forgetting the least recently seen one if there are too many.
+    pub fn insert(&mut self, addr: SocketAddr, info: Option<PacketInfo>, now: Instant) {
+        self.seen.insert(addr, (info, now));
+        if self.seen.len() > REQUESTERS_MAX {
+            let oldest = self
+                .seen
+                .iter()
+                .min_by_key(|(_, (_, seen))| *seen)
+                .map(|(addr, _)| *addr)
+                .expect("not empty");
+            self.seen.remove(&oldest);
+        }
+    }
+
+    /// Scanners seen within `REQUESTERS_TIMEOUT`.
+    pub fn recent(&self, now: Instant) -> Vec<(SocketAddr, Option<PacketInfo>)> {
+        self.seen
+            .iter()
+            .filter(|(_, (_, seen))| now.saturating_duration_since(*seen) < REQUESTERS_TIMEOUT)
+            .map(|(addr, (info, _))| (*addr, *info))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_recent() {
+        let start = Instant::now();
+        let addr = |i: u16| SocketAddr::from(([10, 0, 0, 1], 2000 + i));
+        let mut requesters = Requesters::default();
+        for i in 0..REQUESTERS_MAX as u16 + 1 {
+            requesters.insert(addr(i), None, start + Duration::from_millis(i.into()));
+        }
+        let recent = requesters.recent(start);
+        assert_eq!(recent.len(), REQUESTERS_MAX);
+        assert!(!recent.iter().any(|(a, _)| *a == addr(0))); // oldest forgotten
+        requesters.insert(addr(0), None, start + REQUESTERS_TIMEOUT);
+        assert_eq!(
+            requesters.recent(start + REQUESTERS_TIMEOUT + Duration::from_secs(1)),
+            vec![(addr(0), None)]
+        );
+    }
+}
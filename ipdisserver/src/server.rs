@@ -1,150 +1,900 @@
-use crate::answers::get_answer;
-use crate::answers::Answer;
+use crate::announce::Announcer;
+use crate::answers::{Answer, BeaconInfos};
+use crate::auth::{AuthError, Authenticator};
+use crate::cache::{spawn_refresh_worker, InventoryCache};
+use crate::chunks::{next_message_id, DATAGRAM_MAX_LENGTH};
 use crate::conf::ServerConfig;
+use crate::control::{reply, ControlCommand, ControlSocket};
+use crate::encryption::encrypt_answer;
+use crate::identity::{DeviceKey, SignatureContext};
+use crate::multicast::{bind_v6, present_interfaces, Membership};
+use crate::pktinfo::{enable_packet_info, recv_with_info, send_from, PacketInfo};
+use crate::profiles::{AccessProfile, FULL_PROFILE_NAME};
+use crate::protocol::{Flags, MessageType, Request, ServerMessage, REQUEST_MAX_LENGTH};
+use crate::query::Query;
+use crate::rate_limit::{Clock, RateLimiter};
+use crate::requesters::Requesters;
 use crate::signature::Signature;
-use color_eyre::eyre::Report;
-use std::collections::HashSet;
+use crate::systemd::{InheritedSockets, Notifier};
+use color_eyre::eyre::{Report, WrapErr};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use serde_json::json;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use socket2::{Domain, Socket, Type};
+use std::io;
 use std::net::SocketAddr;
-use std::net::UdpSocket;
-use std::path::PathBuf;
-use std::time::{Duration, SystemTime};
-use tracing::{info, instrument, trace};
+use std::net::{IpAddr, Ipv6Addr, SocketAddrV6, UdpSocket};
+use std::os::unix::net::UnixListener;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, error, info, instrument, trace, warn};
 
-const RECV_BUFFER_LENGHT: usize = 128; // update ipdisserver and ipdisscan CLI documentation if changed
-const RATE_LIMIT_TIMEOUT: Duration = Duration::from_secs(10); // do not accept more than a request every 10 s from each IP
+const RECV_BUFFER_LENGHT: usize = REQUEST_MAX_LENGTH; // update ipdisserver and ipdisscan CLI documentation if changed
+const RECV_TIMEOUT: Duration = Duration::from_secs(1); // max delay before a reload request is handled
+/// Answer key describing where the request was received.
+pub const RECEIVED_KEY: &str = "received";
+const INTERFACES_CHECK_PERIOD: Duration = Duration::from_secs(10); // max delay before joining multicast groups on new interfaces
+const STATUS_PERIOD: Duration = Duration::from_secs(5); // max delay before systemd status shows the counters
+/// Receive buffer of legacy ipdisscan. Legacy scanners cannot reassemble chunks, their answers
+/// are sent in a single datagram.
+const LEGACY_ANSWER_MAX_LENGTH: usize = 1024;
 
-pub fn run(conf: &ServerConfig) -> Result<(), Report> {
-    let socket = UdpSocket::bind(format!("{}:{}", conf.listening_addr, conf.port))?;
-    info!(?socket, "Listening for scanner requests.");
+/// Build a new configuration, e.g. re-reading the configuration file.
+pub type ConfigLoader = Box<dyn Fn() -> Result<ServerConfig, Report> + Send + Sync>;
+
+/// Serve requests until SIGTERM or SIGINT, then say goodbye to the scanners. On SIGHUP the
+/// configuration is replaced by the one returned by `load_conf`, the current one is kept if
+/// loading fails.
+pub fn run(conf: &ServerConfig, load_conf: ConfigLoader) -> Result<(), Report> {
+    let inherited = Arc::new(InheritedSockets::from_env()?);
+    let mut notifier = Notifier::from_env();
+    let reload_requested = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGHUP, reload_requested.clone())?;
+    let stop_requested = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        // A second signal terminates immediately, e.g. if saying goodbye hangs.
+        signal_hook::flag::register_conditional_shutdown(signal, 1, stop_requested.clone())?;
+        signal_hook::flag::register(signal, stop_requested.clone())?;
+    }
+    // Read once, a reload does not change the device identity.
+    let device_key = DeviceKey::load_or_warn(&conf.key_file);
+    let mut state = ServerState::with_inherited(conf.clone(), device_key, inherited)?;
     let clock = Clock;
-    let mut rate_limiter = RateLimiter::new(&clock);
+    let mut rate_limiter = RateLimiter::new(&clock, conf.rate_limit.clone());
+    let mut authenticator = new_authenticator(conf);
+    let mut counters = Counters::default();
+    let mut announcer = Announcer::default();
+    let mut requesters = Requesters::default();
+    let mut next_interfaces_check = Instant::now() + INTERFACES_CHECK_PERIOD;
+    let mut status = status_text(&counters, rate_limiter.limited);
+    let mut next_status = Instant::now() + STATUS_PERIOD;
+    notifier.ready(&status);
     loop {
-        rate_limiter.conditional_reset();
-        rate_limiter = serve_single(
-            &socket,
-            &conf.signatures,
-            &conf.inventory_files,
-            rate_limiter,
-        )?;
+        notifier.watchdog(state.cache.last_refresh());
+        if Instant::now() >= next_status {
+            let new_status = status_text(&counters, rate_limiter.limited);
+            if new_status != status {
+                notifier.status(&new_status);
+                status = new_status;
+            }
+            next_status = Instant::now() + STATUS_PERIOD;
+        }
+        if stop_requested.load(Ordering::Relaxed) {
+            notifier.stopping();
+            say_goodbye(&state, &requesters);
+            info!(answered = counters.answered, "Stopping.");
+            return Ok(());
+        }
+        if reload_requested.swap(false, Ordering::Relaxed) {
+            state = state.reload(&load_conf);
+            rate_limiter.config = state.conf.rate_limit.clone();
+            // Keep the seen nonces, replays must be rejected across reloads.
+            authenticator.keys = state.conf.all_auth_keys();
+            authenticator.window = state.conf.auth_window;
+            authenticator.allow_unauthenticated = state.conf.allow_unauthenticated;
+        }
+        state.swap_cache_if_ready();
+        if Instant::now() >= next_interfaces_check {
+            state.refresh_memberships();
+            next_interfaces_check = Instant::now() + INTERFACES_CHECK_PERIOD;
+        }
+        if state.conf.announce.is_enabled() {
+            if let Err(error) = announce(&state, &mut announcer) {
+                warn!(?error, "Announcement failed.");
+            }
+        }
+        let control = state.control.as_deref();
+        let (sockets, control_ready) =
+            wait_readable(&state.sockets(), control.map(|c| &c.listener), RECV_TIMEOUT)?;
+        for socket in sockets {
+            rate_limiter = serve_single(
+                socket,
+                &state,
+                &mut authenticator,
+                rate_limiter,
+                &mut counters,
+                &mut requesters,
+            )?;
+        }
+        if let (Some(control), true) = (control, control_ready) {
+            while let Some((stream, command)) = control.accept() {
+                let result = command.and_then(|c| {
+                    handle_control(&c, &state, &counters, &rate_limiter, &reload_requested)
+                });
+                reply(stream, result);
+            }
+        }
     }
 }
 
-#[derive(Debug, Clone)]
-struct RateLimiter<'a> {
-    served_ips: HashSet<SocketAddr>,
-    clock: &'a dyn WrappedSystemTime,
-    next_reset: SystemTime,
+/// Everything depending on the configuration, replaced as a whole on reload.
+#[derive(Debug)]
+struct ServerState {
+    conf: ServerConfig,
+    listener: Listener,
+    listener_v6: Option<Listener>,
+    cache: InventoryCache,
+    /// Cache of a reloaded inventory, replacing `cache` once refreshed in background.
+    next_cache: Option<InventoryCache>,
+    profiles: Vec<AccessProfile>,
+    /// Signing the answers, None if it cannot be read nor generated.
+    device_key: Option<DeviceKey>,
+    /// Used instead of binding, whatever the configured address and port.
+    inherited: Arc<InheritedSockets>,
+    /// Shared with the previous state if its configuration is unchanged.
+    control: Option<Arc<ControlSocket>>,
 }
 
-impl<'a> RateLimiter<'a> {
-    fn new(clock: &'a dyn WrappedSystemTime) -> Self {
-        Self {
-            served_ips: HashSet::default(),
-            clock,
-            next_reset: clock.now(),
+impl ServerState {
+    #[cfg(test)]
+    fn new(conf: ServerConfig) -> Result<Self, Report> {
+        Self::with_inherited(conf, DeviceKey::generate().ok(), Arc::default())
+    }
+
+    fn with_inherited(
+        conf: ServerConfig,
+        device_key: Option<DeviceKey>,
+        inherited: Arc<InheritedSockets>,
+    ) -> Result<Self, Report> {
+        let cache = start_cache(&conf)?;
+        let listener = bind(&conf, inherited.v4.as_ref())?;
+        let listener_v6 = bind_multicast_v6(&conf, inherited.v6.as_ref());
+        let profiles = conf.access_profiles();
+        let control = bind_control(&conf);
+        Ok(Self {
+            conf,
+            listener,
+            listener_v6,
+            cache,
+            next_cache: None,
+            profiles,
+            device_key,
+            inherited,
+            control,
+        })
+    }
+
+    fn sockets(&self) -> Vec<&UdpSocket> {
+        let mut res = vec![&self.listener.socket];
+        res.extend(self.listener_v6.as_ref().map(|l| &l.socket));
+        res
+    }
+
+    /// Serve the reloaded inventory once all its sources ran.
+    fn swap_cache_if_ready(&mut self) {
+        if self
+            .next_cache
+            .as_ref()
+            .is_some_and(|c| c.last_refresh().is_some())
+        {
+            self.cache = self.next_cache.take().expect("checked above");
+            info!("Reloaded inventory ready.");
+        }
+    }
+
+    /// Join the multicast groups on the interfaces that appeared.
+    fn refresh_memberships(&mut self) {
+        self.listener.refresh_membership();
+        if let Some(listener) = &mut self.listener_v6 {
+            listener.refresh_membership();
+        }
+    }
+
+    /// Apply the configuration returned by `load_conf`, keep the current state on failure.
+    fn reload(mut self, load_conf: &ConfigLoader) -> Self {
+        info!("Reloading configuration.");
+        match self.try_reload(load_conf) {
+            Ok(state) => state,
+            Err(error) => {
+                error!(
+                    ?error,
+                    "Configuration reload failed, keeping the current one."
+                );
+                self
+            }
+        }
+    }
+
+    fn try_reload(&mut self, load_conf: &ConfigLoader) -> Result<Self, Report> {
+        let conf = load_conf()?;
+        let changes = self.conf.diff(&conf);
+        if changes.is_empty() {
+            info!("Configuration unchanged.");
+        }
+        for change in changes.iter() {
+            info!(%change, "Configuration changed.");
+        }
+        // The current inventory is served until the new one is ready, see `swap_cache_if_ready`.
+        let next_cache = match conf.inventory_files == self.conf.inventory_files
+            && conf.inventory_workers == self.conf.inventory_workers
+        {
+            true => self.next_cache.clone(),
+            false => Some(start_cache_in_background(&conf)?),
+        };
+        // The sockets are kept open while the address and port are unchanged: binding again
+        // would fail, the port is still in use. Only the multicast memberships change.
+        // A new address on the same port is bound once the current socket is closed.
+        let memberships_changed = |group, names, other_group, other_names| {
+            (group, names, &conf.interfaces) != (other_group, other_names, &self.conf.interfaces)
+        };
+        let listener_v6 = match (&self.listener_v6, conf.ipv6_group) {
+            (_, None) => None,
+            (Some(current), Some(group)) if conf.port == self.conf.port => {
+                match memberships_changed(
+                    Some(IpAddr::V6(group)),
+                    &conf.ipv6_interfaces,
+                    self.conf.ipv6_group.map(IpAddr::V6),
+                    &self.conf.ipv6_interfaces,
+                ) {
+                    true => Some(current.rejoin(
+                        Some(IpAddr::V6(group)),
+                        &conf.ipv6_interfaces,
+                        &conf.interfaces,
+                    )?),
+                    false => Some(current.try_clone()?),
+                }
+            }
+            _ => bind_multicast_v6(&conf, self.inherited.v6.as_ref()),
+        };
+        // Last, so that the current socket is not closed when reloading fails for another
+        // reason, see `rebind`.
+        let listener =
+            match (conf.listening_addr, conf.port) == (self.conf.listening_addr, self.conf.port) {
+                false if conf.port == self.conf.port => self.rebind(&conf)?,
+                false => bind(&conf, self.inherited.v4.as_ref())?,
+                true if memberships_changed(
+                    conf.multicast_group.map(IpAddr::V4),
+                    &conf.multicast_interfaces,
+                    self.conf.multicast_group.map(IpAddr::V4),
+                    &self.conf.multicast_interfaces,
+                ) =>
+                {
+                    self.listener.rejoin(
+                        conf.multicast_group.map(IpAddr::V4),
+                        &conf.multicast_interfaces,
+                        &conf.interfaces,
+                    )?
+                }
+                true => self.listener.try_clone()?,
+            };
+        let profiles = conf.access_profiles();
+        let control = match conf.control == self.conf.control {
+            true => self.control.clone(),
+            false => bind_control(&conf),
+        };
+        Ok(Self {
+            conf,
+            listener,
+            listener_v6,
+            cache: self.cache.clone(),
+            next_cache,
+            profiles,
+            device_key: self.device_key.clone(),
+            inherited: self.inherited.clone(),
+            control,
+        })
+    }
+
+    /// IPv4 listener of `conf`, on the same port as the current one but another address: the
+    /// current socket is closed first, the port would still be in use. It is bound again if
+    /// binding `conf` fails.
+    fn rebind(&mut self, conf: &ServerConfig) -> Result<Listener, Report> {
+        if self.inherited.v4.is_some() {
+            return bind(conf, self.inherited.v4.as_ref()); // the address is not ours to change
+        }
+        let unbound = Socket::new(Domain::IPV4, Type::DGRAM, None)?.into();
+        drop(std::mem::replace(&mut self.listener.socket, unbound));
+        match bind(conf, None) {
+            Ok(listener) => Ok(listener),
+            Err(error) => {
+                self.listener = bind(&self.conf, None)
+                    .wrap_err("Cannot listen on the previous address again")?;
+                Err(error)
+            }
         }
     }
 }
 
-impl RateLimiter<'_> {
-    /// Return true if the address is not in served_ips, add it.
-    fn check(&mut self, ip: &SocketAddr) -> bool {
-        self.conditional_reset();
-        let not_already_served = self.served_ips.insert(*ip);
-        trace!(%ip, %not_already_served, "IP checked.");
-        not_already_served
+/// Socket receiving the requests, with the multicast group it joined.
+#[derive(Debug)]
+struct Listener {
+    socket: UdpSocket,
+    membership: Option<Membership>,
+}
+
+impl Listener {
+    fn try_clone(&self) -> Result<Self, Report> {
+        Ok(Self {
+            socket: self.socket.try_clone()?,
+            membership: self.membership.clone(),
+        })
     }
 
-    /// Reset served_ips if timeout has elapsed, set new timeout and return true. Return false
-    /// otherwise.
-    fn conditional_reset(&mut self) -> bool {
-        let now = self.clock.now();
-        if now >= self.next_reset {
-            self.next_reset = now + RATE_LIMIT_TIMEOUT;
-            self.served_ips = HashSet::default();
-            trace!(?self.next_reset, "Cleared served IPs.");
-            return true;
+    /// Same socket, with the current group left and `group` joined instead. See
+    /// `join_multicast`.
+    fn rejoin(
+        &self,
+        group: Option<IpAddr>,
+        names: &[String],
+        scope: &[String],
+    ) -> Result<Self, Report> {
+        let socket = self.socket.try_clone()?;
+        if let Some(membership) = &self.membership {
+            membership.leave(&socket);
+        }
+        let membership = group.map(|g| join_multicast(&socket, g, names, scope));
+        Ok(Self { socket, membership })
+    }
+
+    fn refresh_membership(&mut self) {
+        if let Some(membership) = &mut self.membership {
+            membership.refresh(&self.socket);
         }
-        false
     }
 }
 
-trait WrappedSystemTime: std::fmt::Debug {
-    // NB: supertrait
-    fn now(&self) -> SystemTime;
+/// Requests received since the start.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Counters {
+    /// Datagrams, including the ones received on other interfaces.
+    received: u64,
+    answered: u64,
+    /// Requests from clients not allowed by `ServerConfig.client_filter`.
+    denied: u64,
+    /// Invalid requests, or with a signature or authentication not accepted.
+    rejected: u64,
+    /// Accepted requests whose answer could not be built or sent.
+    failed: u64,
 }
 
-#[derive(Debug, Default)]
-struct Clock;
+/// Service status reported to systemd.
+fn status_text(counters: &Counters, limited: u64) -> String {
+    format!(
+        "Answered {} requests, {} denied, {} rate limited",
+        counters.answered, counters.denied, limited
+    )
+}
+
+fn new_authenticator(conf: &ServerConfig) -> Authenticator {
+    Authenticator::new(
+        conf.all_auth_keys(),
+        conf.auth_window,
+        conf.allow_unauthenticated,
+    )
+}
 
-impl WrappedSystemTime for Clock {
-    fn now(&self) -> SystemTime {
-        SystemTime::now()
+/// Execute all the inventory sources, then keep them updated in background.
+fn start_cache(conf: &ServerConfig) -> Result<InventoryCache, Report> {
+    let cache = InventoryCache::from_inventory_files(&conf.inventory_files, conf.inventory_workers);
+    cache.refresh_expired(Instant::now());
+    spawn_refresh_worker(&cache)?;
+    Ok(cache)
+}
+
+/// Like `start_cache`, without waiting for the sources: its answer is empty until
+/// `InventoryCache::last_refresh` is set.
+fn start_cache_in_background(conf: &ServerConfig) -> Result<InventoryCache, Report> {
+    let cache = InventoryCache::from_inventory_files(&conf.inventory_files, conf.inventory_workers);
+    spawn_refresh_worker(&cache)?;
+    Ok(cache)
+}
+
+/// Control socket, None if disabled or if it cannot be created: the server runs without it.
+fn bind_control(conf: &ServerConfig) -> Option<Arc<ControlSocket>> {
+    let control = conf.control.as_ref()?;
+    match ControlSocket::bind(control) {
+        Ok(socket) => Some(Arc::new(socket)),
+        Err(error) => {
+            warn!(?error, "Cannot create control socket, running without it.");
+            None
+        }
     }
 }
 
-#[derive(Debug)]
-struct DummyClock {
-    time: SystemTime,
+/// Bind the IPv4 socket, or use the `inherited` one.
+fn bind(conf: &ServerConfig, inherited: Option<&UdpSocket>) -> Result<Listener, Report> {
+    let socket = match inherited {
+        Some(socket) => socket.try_clone()?,
+        None => UdpSocket::bind(format!("{}:{}", conf.listening_addr, conf.port))?,
+    };
+    socket.set_read_timeout(Some(RECV_TIMEOUT))?;
+    socket.set_broadcast(true)?; // announcements
+    enable_packet_info(&socket)?;
+    let membership = conf.multicast_group.map(|group| {
+        join_multicast(
+            &socket,
+            IpAddr::V4(group),
+            &conf.multicast_interfaces,
+            &conf.interfaces,
+        )
+    });
+    info!(?socket, "Listening for scanner requests.");
+    Ok(Listener { socket, membership })
+}
+
+/// IPv6 socket joined to the configured multicast group, None if IPv6 is disabled or unavailable.
+fn bind_multicast_v6(conf: &ServerConfig, inherited: Option<&UdpSocket>) -> Option<Listener> {
+    let group = conf.ipv6_group?;
+    match try_bind_multicast_v6(conf, &group, inherited) {
+        Ok(listener) => Some(listener),
+        Err(error) => {
+            warn!(?error, "Cannot listen on IPv6, serving IPv4 requests only.");
+            None
+        }
+    }
+}
+
+fn try_bind_multicast_v6(
+    conf: &ServerConfig,
+    group: &Ipv6Addr,
+    inherited: Option<&UdpSocket>,
+) -> Result<Listener, Report> {
+    let socket = match inherited {
+        Some(socket) => socket.try_clone()?,
+        None => bind_v6(conf.port)?,
+    };
+    socket.set_read_timeout(Some(RECV_TIMEOUT))?;
+    enable_packet_info(&socket)?;
+    let membership = join_multicast(
+        &socket,
+        IpAddr::V6(*group),
+        &conf.ipv6_interfaces,
+        &conf.interfaces,
+    );
+    info!(?socket, "Listening for IPv6 scanner requests.");
+    Ok(Listener {
+        socket,
+        membership: Some(membership),
+    })
 }
 
-impl WrappedSystemTime for DummyClock {
-    fn now(&self) -> SystemTime {
-        self.time
+/// Join `group` on the interfaces named in `names`, or else on the ones requests are accepted
+/// from, or else on all the multicast capable ones. Missing interfaces are joined when they
+/// appear, see `ServerState::refresh_memberships`.
+fn join_multicast(
+    socket: &UdpSocket,
+    group: IpAddr,
+    names: &[String],
+    scope: &[String],
+) -> Membership {
+    let names = match names.is_empty() {
+        true => scope.to_vec(),
+        false => names.to_vec(),
+    };
+    let mut membership = Membership::new(group, names);
+    if membership.refresh(socket) == 0 {
+        warn!(%group, interfaces = ?membership.interfaces, "Multicast group not joined on any interface yet.");
+    }
+    membership
+}
+
+/// Sockets with a datagram to read, and true if a client is waiting on `control`. Nothing is
+/// ready if nothing is received within `timeout` or if interrupted by a signal.
+fn wait_readable<'a>(
+    sockets: &[&'a UdpSocket],
+    control: Option<&UnixListener>,
+    timeout: Duration,
+) -> Result<(Vec<&'a UdpSocket>, bool), Report> {
+    let mut fds: Vec<PollFd> = sockets
+        .iter()
+        .map(|s| PollFd::new(*s, PollFlags::POLLIN))
+        .collect();
+    fds.extend(control.map(|c| PollFd::new(c, PollFlags::POLLIN)));
+    match poll(&mut fds, timeout.as_millis() as i32) {
+        Ok(_) => (),
+        Err(Errno::EINTR) => return Ok((Vec::new(), false)),
+        Err(error) => return Err(error.into()),
+    }
+    let is_ready = |fd: &PollFd| fd.revents().is_some_and(|r| !r.is_empty());
+    let control_ready = control.is_some() && fds.last().is_some_and(is_ready);
+    let ready = sockets
+        .iter()
+        .zip(fds.iter())
+        .filter(|(_, fd)| is_ready(fd))
+        .map(|(s, _)| *s)
+        .collect();
+    Ok((ready, control_ready))
+}
+
+/// Result of a command received on the control socket.
+fn handle_control(
+    command: &ControlCommand,
+    state: &ServerState,
+    counters: &Counters,
+    rate_limiter: &RateLimiter,
+    reload_requested: &AtomicBool,
+) -> Result<String, Report> {
+    info!(%command, "Control command.");
+    match command {
+        ControlCommand::ShowAnswer(name) => {
+            let name = name.as_deref().unwrap_or(FULL_PROFILE_NAME);
+            let profile = state
+                .profiles
+                .iter()
+                .find(|p| p.name == name)
+                .ok_or_else(|| Report::msg(format!("Unknown profile {:?}", name)))?;
+            let answer = state
+                .cache
+                .answer_for(profile, &Query::default(), BeaconInfos::new())?;
+            let answer: serde_json::Value = serde_json::from_slice(&answer.0)?;
+            Ok(format!("{:#}\n", answer))
+        }
+        ControlCommand::Stats => {
+            let sources: Vec<serde_json::Value> = state
+                .cache
+                .status()
+                .iter()
+                .map(|s| {
+                    json!({
+                        "name": s.name,
+                        "error": s.error.as_ref().map(|e| e.to_string()),
+                        "stale": s.stale,
+                    })
+                })
+                .collect();
+            let stats = json!({
+                "received": counters.received,
+                "answered": counters.answered,
+                "denied": counters.denied,
+                "rejected": counters.rejected,
+                "failed": counters.failed,
+                "rate_limited": rate_limiter.limited,
+                "rate_limited_clients": rate_limiter.clients(),
+                "sources": sources,
+            });
+            Ok(format!("{:#}\n", stats))
+        }
+        ControlCommand::Reload => {
+            reload_requested.store(true, Ordering::Relaxed);
+            Ok("Reload requested, changes are logged.\n".into())
+        }
+        ControlCommand::FlushCache => {
+            state.cache.flush();
+            if let Some(cache) = &state.next_cache {
+                cache.flush();
+            }
+            Ok("Inventory files will be executed again.\n".into())
+        }
     }
 }
 
-#[instrument]
+#[instrument(skip(state))]
 fn serve_single<'a>(
     socket: &UdpSocket,
-    expected_signatures: &[Signature],
-    inventory_files: &[PathBuf],
+    state: &ServerState,
+    authenticator: &mut Authenticator,
     mut rate_limiter: RateLimiter<'a>,
+    counters: &mut Counters,
+    requesters: &mut Requesters,
 ) -> Result<RateLimiter<'a>, Report> {
-    let (addr, received) = receive(socket)?;
-    if !is_signature_vaid(&received, expected_signatures) {
-        trace!(%received, %addr, "Bad signature received, not answering.");
+    let (addr, received, info) = match receive(socket) {
+        Ok(r) => r,
+        Err(error) if is_interrupted(&error) => return Ok(rate_limiter),
+        Err(error) => return Err(error),
+    };
+    counters.received += 1;
+    if !is_on_interfaces(info.as_ref(), &state.conf.interfaces) {
+        trace!(%addr, ?info, "Request received on another interface, not answering.");
+        return Ok(rate_limiter);
+    }
+    if !state.conf.client_filter.allows(&addr.ip()) {
+        counters.denied += 1;
+        debug!(%addr, denied = counters.denied, "Client not allowed, not answering.");
         return Ok(rate_limiter);
+    }
+    let now = rate_limiter.clock.now();
+    let request = match Request::decode(&received.0) {
+        Ok(r) => r,
+        Err(error) => {
+            counters.rejected += 1;
+            debug!(%error, %addr, "Invalid request, not answering.");
+            return Ok(rate_limiter);
+        }
+    };
+    let profile = match select_profile(&received, &request, &state.profiles, authenticator, now) {
+        Some(p) => p,
+        None => {
+            counters.rejected += 1;
+            trace!(%received, %addr, "Bad signature received, not answering.");
+            return Ok(rate_limiter);
+        }
     };
     if !rate_limiter.check(&addr) {
         return Ok(rate_limiter);
     }
-    let answer = get_answer(inventory_files)?;
-    respond(socket, &addr, &answer)?;
-    info!(%answer, %addr, "Answered.");
+    let answered = state
+        .cache
+        .answer_for(profile, &request.query, received_metadata(info.as_ref()))
+        .and_then(|answer| {
+            let message = answer_message(&request, &answer, state.device_key.as_ref())?;
+            match request.is_legacy() {
+                true => {
+                    send_from(socket, &message.encode().0, &addr, info.as_ref())?;
+                }
+                false => respond(socket, &addr, info.as_ref(), &message.encode())?,
+            }
+            Ok((answer, message))
+        });
+    let (answer, message) = match answered {
+        Ok(a) => a,
+        Err(error) => {
+            counters.failed += 1;
+            warn!(?error, %addr, failed = counters.failed, "Cannot answer.");
+            return Ok(rate_limiter);
+        }
+    };
+    counters.answered += 1;
+    if !request.is_legacy() {
+        requesters.insert(addr, info, Instant::now()); // legacy scanners cannot read goodbyes
+    }
+    info!(%answer, %addr, profile = %profile.name, ?request.query.keys, version = request.version, flags = %message.flags, "Answered.");
     Ok(rate_limiter)
 }
 
-fn is_signature_vaid(received: &Signature, expected: &[Signature]) -> bool {
-    trace!(%received, ?expected, "Validating received signature.");
-    for signature in expected.iter() {
-        if signature == received {
-            trace!(%received, "Received signature matches.");
-            return true;
+/// Send the signed answer of the announce profile to the announce addresses, if due.
+/// See `Announcer::is_due`.
+fn announce(state: &ServerState, announcer: &mut Announcer) -> Result<(), Report> {
+    let conf = &state.conf.announce;
+    let profile = state
+        .profiles
+        .iter()
+        .find(|p| p.name == conf.profile)
+        .ok_or_else(|| Report::msg(format!("Unknown announce profile {:?}", conf.profile)))?;
+    let answer = state
+        .cache
+        .answer_for(profile, &Query::default(), BeaconInfos::new())?;
+    if !announcer.is_due(&answer, Instant::now(), conf.interval) {
+        return Ok(());
+    }
+    let context = SignatureContext {
+        message_type: MessageType::Announcement,
+        request_id: 0,
+    };
+    let (flags, payload) = sign(state.device_key.as_ref(), &answer, &context);
+    let message = ServerMessage::announcement(flags, payload).encode();
+    for addr in conf.addrs.iter() {
+        for (socket, dest) in announce_destinations(state, *addr, conf.port) {
+            if let Err(error) = respond(socket, &dest, None, &message) {
+                warn!(?error, %dest, "Cannot send announcement.");
+            }
+        }
+    }
+    info!(%answer, addrs = ?conf.addrs, profile = %profile.name, "Announced.");
+    Ok(())
+}
+
+/// Tell the recent requesters and the announcement addresses that the server is going away,
+/// so that scanners stop showing it. The signature covers the current time, so that scanners
+/// reject the goodbye if it is replayed later. Failures are logged only.
+fn say_goodbye(state: &ServerState, requesters: &Requesters) {
+    let context = SignatureContext {
+        message_type: MessageType::Goodbye,
+        request_id: 0,
+    };
+    let (flags, payload) = sign(
+        state.device_key.as_ref(),
+        &Answer::from(&b"{}"[..]),
+        &context,
+    );
+    let message = ServerMessage::goodbye(flags, payload).encode();
+    let mut destinations = Vec::new();
+    for (addr, info) in requesters.recent(Instant::now()) {
+        let socket = match addr {
+            SocketAddr::V4(_) => &state.listener.socket,
+            SocketAddr::V6(_) => match &state.listener_v6 {
+                Some(listener) => &listener.socket,
+                None => continue, // IPv6 disabled by a reload
+            },
+        };
+        destinations.push((socket, addr, info));
+    }
+    if state.conf.announce.is_enabled() {
+        for addr in state.conf.announce.addrs.iter() {
+            for (socket, dest) in announce_destinations(state, *addr, state.conf.announce.port) {
+                destinations.push((socket, dest, None));
+            }
+        }
+    }
+    for (socket, addr, info) in destinations.iter() {
+        if let Err(error) = respond(socket, addr, info.as_ref(), &message) {
+            warn!(?error, %addr, "Cannot send goodbye.");
+        }
+    }
+    info!(destinations = destinations.len(), "Goodbye sent.");
+}
+
+/// IPv4 addresses are reached through the routing table. IPv6 ones are reached on each
+/// interface where the IPv6 group is joined, so that link-local scopes are set.
+fn announce_destinations(
+    state: &ServerState,
+    addr: IpAddr,
+    port: u16,
+) -> Vec<(&UdpSocket, SocketAddr)> {
+    match addr {
+        IpAddr::V4(_) => vec![(&state.listener.socket, SocketAddr::new(addr, port))],
+        IpAddr::V6(addr) => {
+            let Some(listener) = &state.listener_v6 else {
+                debug!(%addr, "IPv6 disabled, not announcing.");
+                return Vec::new();
+            };
+            let names = listener
+                .membership
+                .as_ref()
+                .map(|m| m.interfaces.clone())
+                .unwrap_or_default();
+            present_interfaces(&names)
+                .into_iter()
+                .map(|i| {
+                    let dest = SocketAddrV6::new(addr, port, 0, i.index);
+                    (&listener.socket, SocketAddr::V6(dest))
+                })
+                .collect()
         }
     }
-    false
 }
 
-fn receive(socket: &UdpSocket) -> Result<(SocketAddr, Signature), Report> {
+/// `answer` signed with the device key and the flags telling so, unchanged without a key.
+fn sign(
+    device_key: Option<&DeviceKey>,
+    answer: &Answer,
+    context: &SignatureContext,
+) -> (Flags, Answer) {
+    match device_key {
+        Some(key) => (Flags::SIGNED, key.sign_answer(answer, context)),
+        None => (Flags::NONE, answer.clone()),
+    }
+}
+
+/// Answer signed if the scanner verifies signatures and there is a device key, then encrypted
+/// if it sent a key. Legacy requests get the bare answer, shortened to fit a single datagram.
+fn answer_message(
+    request: &Request,
+    answer: &Answer,
+    device_key: Option<&DeviceKey>,
+) -> Result<ServerMessage, Report> {
+    let mut flags = Flags::NONE;
+    let mut payload = answer.clone();
+    if request.is_legacy() {
+        payload = payload.fit(LEGACY_ANSWER_MAX_LENGTH);
+        return Ok(ServerMessage::answer(request, flags, payload));
+    }
+    if request.flags.contains(Flags::SIGNED) {
+        let context = SignatureContext {
+            message_type: MessageType::Answer,
+            request_id: request.request_id,
+        };
+        (flags, payload) = sign(device_key, &payload, &context);
+    }
+    if let Some(key) = &request.answer_key {
+        payload = encrypt_answer(&payload, key)?;
+        flags = flags | Flags::ENCRYPTED;
+    }
+    Ok(ServerMessage::answer(request, flags, payload))
+}
+
+/// First profile with the key authenticating the request or, if unauthenticated requests are
+/// accepted, with a plain signature matching it. None if the request must not be answered.
+/// `received` is the whole datagram, covered by the authentication.
+fn select_profile<'a>(
+    received: &Signature,
+    request: &Request,
+    profiles: &'a [AccessProfile],
+    authenticator: &mut Authenticator,
+    now: SystemTime,
+) -> Option<&'a AccessProfile> {
+    let accepts_unauthenticated = authenticator.accepts_unauthenticated();
+    match authenticator.verify(&received.0, now) {
+        Ok(key) => return profiles.iter().find(|p| p.auth_keys.contains(key)),
+        Err(AuthError::NotAuthenticated) if accepts_unauthenticated => (),
+        Err(error) => {
+            debug!(%error, "Request not authenticated.");
+            return None;
+        }
+    }
+    let received = &request.credential;
+    trace!(%received, "Validating received signature.");
+    let profile = profiles.iter().find(|p| p.signatures.contains(received))?;
+    trace!(%received, profile = %profile.name, "Received signature matches.");
+    Some(profile)
+}
+
+/// Receiving interface, local address and destination kind (unicast, broadcast or multicast)
+/// of the request, so that the scanner knows which address of a multi-homed device it reached.
+fn received_metadata(info: Option<&PacketInfo>) -> BeaconInfos {
+    let mut res = BeaconInfos::new();
+    let Some(info) = info else {
+        return res;
+    };
+    let mut received = BeaconInfos::new();
+    received.insert("interface".into(), info.interface_name().into());
+    received.insert(
+        "local_addr".into(),
+        info.local_addr.map(|a| a.to_string()).into(),
+    );
+    received.insert(
+        "destination".into(),
+        info.destination_kind().to_string().into(),
+    );
+    res.insert(RECEIVED_KEY.into(), received.into());
+    res
+}
+
+/// True if requests are accepted on all the interfaces or if the datagram was received on
+/// one of `interfaces`.
+fn is_on_interfaces(info: Option<&PacketInfo>, interfaces: &[String]) -> bool {
+    interfaces.is_empty() || info.is_some_and(|i| i.is_on(interfaces))
+}
+
+fn receive(socket: &UdpSocket) -> Result<(SocketAddr, Signature, Option<PacketInfo>), Report> {
     // Receives a single datagram message on the socket. If `buf` is too small to hold
     // the message, it will be cut off.
     let mut buf = [0; RECV_BUFFER_LENGHT];
     trace!(?socket, "Listening.");
-    let (length, source) = socket.recv_from(&mut buf)?;
+    let (length, source, info) = recv_with_info(socket, &mut buf)?;
     let received: Signature = (&buf[..length]).into();
-    trace!(%length, %source, "Datagram received.");
-    Ok((source, received))
+    trace!(%length, %source, ?info, "Datagram received.");
+    Ok((source, received, info))
+}
+
+/// True if receiving timed out or was interrupted by a signal.
+fn is_interrupted(error: &Report) -> bool {
+    matches!(
+        error.downcast_ref::<io::Error>().map(|e| e.kind()),
+        Some(io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted)
+    )
 }
 
-fn respond(socket: &UdpSocket, addr: &SocketAddr, msg: &Answer) -> Result<(), Report> {
-    socket.send_to(&msg.0, addr)?;
+/// Send the answer from the address the request was received on, split in chunks if it does
+/// not fit a single datagram.
+fn respond(
+    socket: &UdpSocket,
+    addr: &SocketAddr,
+    info: Option<&PacketInfo>,
+    msg: &Answer,
+) -> Result<(), Report> {
+    let datagrams = msg.to_datagrams(next_message_id(), DATAGRAM_MAX_LENGTH)?;
+    trace!(%addr, datagrams = datagrams.len(), "Sending answer.");
+    for datagram in datagrams {
+        send_from(socket, &datagram, addr, info)?;
+    }
     Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::announce::AnnounceConfig;
+    use crate::auth::{AuthKey, AuthRequest, AUTH_WINDOW_DEFAULT};
+    use crate::client_filter::ClientFilter;
+    use crate::conf::SIGNATURE_DEFAULT;
+    use crate::control::ControlConfig;
+    use crate::encryption::AnswerKey;
+    use crate::identity::{open_signed_answer, AnswerSigner};
+    use crate::inventory::InventoryFile;
+    use crate::protocol::MessageType;
+    use crate::rate_limit::RateLimitConfig;
     use std::net::Ipv4Addr;
     use std::thread;
     use std::time::Duration;
@@ -152,23 +902,29 @@ mod test {
     #[test]
     #[tracing_test::traced_test]
     fn test_serve_localhost() {
-        let conf = ServerConfig::dummy();
+        let conf = ServerConfig {
+            port: 0,
+            ..ServerConfig::dummy()
+        };
         let sending_socket = UdpSocket::bind(format!("{}:{}", Ipv4Addr::UNSPECIFIED, 0)).unwrap();
         let receiving_socket = sending_socket
             .try_clone()
             .expect("couldn't clone the socket");
-        let beacon_socket = UdpSocket::bind(format!("{}:{}", conf.listening_addr, 0)).unwrap();
-        let server_port = beacon_socket.local_addr().unwrap().port();
-        let conf_clone = conf.clone();
+        let state = ServerState::new(conf.clone()).unwrap();
+        let server_port = state.listener.socket.local_addr().unwrap().port();
         let server_handle = thread::spawn(move || {
             let clock = Clock;
+            let mut counters = Counters::default();
             serve_single(
-                &beacon_socket,
-                &conf_clone.signatures,
-                &conf_clone.inventory_files,
-                RateLimiter::new(&clock),
+                &state.listener.socket,
+                &state,
+                &mut new_authenticator(&state.conf),
+                RateLimiter::new(&clock, state.conf.rate_limit.clone()),
+                &mut counters,
+                &mut Requesters::default(),
             )
             .unwrap();
+            assert_eq!(counters.answered, 1);
         });
         let scanner_handle = thread::spawn(move || {
             thread::sleep(Duration::from_secs_f64(0.1));
@@ -180,23 +936,575 @@ mod test {
         });
         let response = receive(&receiving_socket).unwrap();
         println!("[{}] -> {}", response.0, response.1);
+        assert_eq!(response.0.ip(), Ipv4Addr::LOCALHOST); // sent from the address reached
+        let answer: serde_json::Value = serde_json::from_slice(&response.1 .0).unwrap();
+        assert_eq!(
+            answer[RECEIVED_KEY],
+            serde_json::json!({
+                "interface": "lo",
+                "local_addr": "127.0.0.1",
+                "destination": "unicast",
+            })
+        );
         server_handle.join().unwrap();
         scanner_handle.join().unwrap();
     }
 
     #[test]
     #[tracing_test::traced_test]
-    fn test_rate_limiter() {
-        let time = SystemTime::now();
-        let clock = DummyClock { time };
-        let mut rate_limiter = RateLimiter::new(&clock);
-        let ip = SocketAddr::from(([10, 11, 12, 13], 1234));
-        assert!(rate_limiter.check(&ip));
-        assert!(!rate_limiter.check(&ip));
-        let time = SystemTime::now() + RATE_LIMIT_TIMEOUT + Duration::from_millis(1);
-        let clock = DummyClock { time };
-        rate_limiter.clock = &clock;
-        assert!(rate_limiter.check(&ip));
-        assert!(!rate_limiter.check(&ip));
+    fn test_large_legacy_answer() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join("rust-ipdisserver-test-server-large-legacy");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("inventory-large");
+        let script = format!("#!/bin/sh\necho small=1\necho large={}\n", "x".repeat(3000));
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let scanner_socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let state = ServerState::new(ServerConfig {
+            port: 0,
+            inventory_files: vec![InventoryFile::from(path.as_path())],
+            ..ServerConfig::dummy()
+        })
+        .unwrap();
+        scanner_socket
+            .send_to(
+                SIGNATURE_DEFAULT.as_bytes(),
+                (
+                    Ipv4Addr::LOCALHOST,
+                    state.listener.socket.local_addr().unwrap().port(),
+                ),
+            )
+            .unwrap();
+        let clock = Clock;
+        serve_single(
+            &state.listener.socket,
+            &state,
+            &mut new_authenticator(&state.conf),
+            RateLimiter::new(&clock, state.conf.rate_limit.clone()),
+            &mut Counters::default(),
+            &mut Requesters::default(),
+        )
+        .unwrap();
+        let mut buf = [0; 65536];
+        let (length, _) = scanner_socket.recv_from(&mut buf).unwrap();
+        assert!(length <= LEGACY_ANSWER_MAX_LENGTH);
+        let answer: serde_json::Value = serde_json::from_slice(&buf[..length]).unwrap();
+        assert_eq!(answer["small"], "1");
+        assert!(answer.get("hostname").is_some());
+        assert!(answer.get("large").is_none());
+        assert!(logs_contain("keys omitted"));
+        scanner_socket.set_nonblocking(true).unwrap();
+        assert!(scanner_socket.recv_from(&mut buf).is_err()); // no chunks follow
+    }
+
+    /// Serve a single request sent from localhost.
+    fn serve_localhost_request(conf: ServerConfig) -> Counters {
+        let state = ServerState::new(ServerConfig { port: 0, ..conf }).unwrap();
+        let server_port = state.listener.socket.local_addr().unwrap().port();
+        let sending_socket = UdpSocket::bind(format!("{}:{}", Ipv4Addr::LOCALHOST, 0)).unwrap();
+        sending_socket
+            .send_to(
+                SIGNATURE_DEFAULT.as_bytes(),
+                (Ipv4Addr::LOCALHOST, server_port),
+            )
+            .unwrap();
+        let clock = Clock;
+        let mut counters = Counters::default();
+        serve_single(
+            &state.listener.socket,
+            &state,
+            &mut new_authenticator(&state.conf),
+            RateLimiter::new(&clock, state.conf.rate_limit.clone()),
+            &mut counters,
+            &mut Requesters::default(),
+        )
+        .unwrap();
+        counters
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_client_filter() {
+        let conf = ServerConfig {
+            client_filter: ClientFilter {
+                allow: Vec::new(),
+                deny: vec!["127.0.0.0/8".parse().unwrap()],
+            },
+            ..ServerConfig::dummy()
+        };
+        assert_eq!(
+            serve_localhost_request(conf),
+            Counters {
+                received: 1,
+                denied: 1,
+                ..Default::default()
+            }
+        );
+        assert!(logs_contain("Client not allowed"));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_interface_scope() {
+        let conf = ServerConfig {
+            interfaces: vec!["eth-missing".into()],
+            ..ServerConfig::dummy()
+        };
+        assert_eq!(serve_localhost_request(conf).answered, 0);
+        assert!(logs_contain("Request received on another interface"));
+        let conf = ServerConfig {
+            interfaces: vec!["eth-missing".into(), "lo".into()],
+            ..ServerConfig::dummy()
+        };
+        assert_eq!(serve_localhost_request(conf).answered, 1);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_announce() {
+        let scanner_socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let conf = ServerConfig {
+            port: 0,
+            announce: AnnounceConfig {
+                addrs: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+                port: scanner_socket.local_addr().unwrap().port(),
+                ..Default::default()
+            },
+            ..ServerConfig::dummy()
+        };
+        let state = ServerState::new(conf).unwrap();
+        let mut announcer = Announcer::default();
+        announce(&state, &mut announcer).unwrap();
+        announce(&state, &mut announcer).unwrap(); // not due
+        let mut buf = [0; DATAGRAM_MAX_LENGTH];
+        let (length, source) = scanner_socket.recv_from(&mut buf).unwrap();
+        assert_eq!(
+            source.port(),
+            state.listener.socket.local_addr().unwrap().port()
+        );
+        let message = ServerMessage::decode(&Answer::from(&buf[..length])).unwrap();
+        assert_eq!(message.message_type, MessageType::Announcement);
+        assert_eq!(message.flags, Flags::SIGNED);
+        let context = SignatureContext {
+            message_type: MessageType::Announcement,
+            request_id: 0,
+        };
+        let (answer, signer) = open_signed_answer(&message.payload, &context);
+        assert!(matches!(
+            signer,
+            AnswerSigner::Valid { key, .. } if Some(key) == state.device_key.as_ref().map(|k| k.public_key())
+        ));
+        let answer: serde_json::Value = serde_json::from_slice(&answer.0).unwrap();
+        assert!(answer.get("hostname").is_some());
+        assert!(answer.get(RECEIVED_KEY).is_none());
+        scanner_socket.set_nonblocking(true).unwrap();
+        assert!(scanner_socket.recv_from(&mut buf).is_err());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_goodbye() {
+        let state = ServerState::new(ServerConfig {
+            port: 0,
+            ..ServerConfig::dummy()
+        })
+        .unwrap();
+        let server_port = state.listener.socket.local_addr().unwrap().port();
+        let scanner_socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let request = Request::new(1, Signature::from(SIGNATURE_DEFAULT));
+        scanner_socket
+            .send_to(&request.encode().0, (Ipv4Addr::LOCALHOST, server_port))
+            .unwrap();
+        let clock = Clock;
+        let mut requesters = Requesters::default();
+        serve_single(
+            &state.listener.socket,
+            &state,
+            &mut new_authenticator(&state.conf),
+            RateLimiter::new(&clock, state.conf.rate_limit.clone()),
+            &mut Counters::default(),
+            &mut requesters,
+        )
+        .unwrap();
+        let mut buf = [0; DATAGRAM_MAX_LENGTH];
+        let (length, _) = scanner_socket.recv_from(&mut buf).unwrap();
+        let answer = ServerMessage::decode(&Answer::from(&buf[..length])).unwrap();
+        assert_eq!(answer.message_type, MessageType::Answer);
+        say_goodbye(&state, &requesters);
+        let (length, source) = scanner_socket.recv_from(&mut buf).unwrap();
+        assert_eq!(source.port(), server_port);
+        let goodbye = ServerMessage::decode(&Answer::from(&buf[..length])).unwrap();
+        assert_eq!(goodbye.message_type, MessageType::Goodbye);
+        assert_eq!(goodbye.flags, Flags::SIGNED);
+        let context = SignatureContext {
+            message_type: MessageType::Goodbye,
+            request_id: 0,
+        };
+        assert!(matches!(
+            open_signed_answer(&goodbye.payload, &context).1,
+            AnswerSigner::Valid { key, .. } if Some(key) == state.device_key.as_ref().map(|k| k.public_key())
+        ));
+        assert!(logs_contain("Goodbye sent."));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_inherited_socket() {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let inherited = Arc::new(InheritedSockets {
+            v4: Some(socket),
+            v6: None,
+        });
+        let state = ServerState::with_inherited(ServerConfig::dummy(), None, inherited).unwrap();
+        assert_eq!(state.listener.socket.local_addr().unwrap(), addr);
+        let new_conf = ServerConfig {
+            port: 0,
+            ..ServerConfig::dummy()
+        };
+        let loader: ConfigLoader = Box::new(move || Ok(new_conf.clone()));
+        let state = state.reload(&loader);
+        assert_eq!(state.listener.socket.local_addr().unwrap(), addr);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_reload() {
+        let conf = ServerConfig {
+            port: 0,
+            ..ServerConfig::dummy()
+        };
+        let state = ServerState::new(conf.clone()).unwrap();
+        let failing_loader: ConfigLoader = Box::new(|| Err(Report::msg("invalid configuration")));
+        let state = state.reload(&failing_loader);
+        assert_eq!(state.conf, conf);
+        let new_conf = ServerConfig {
+            signatures: vec![Signature::from("new-signature")],
+            rate_limit: RateLimitConfig {
+                interval: Duration::from_secs(3),
+                ..Default::default()
+            },
+            ..conf.clone()
+        };
+        let new_conf_clone = new_conf.clone();
+        let loader: ConfigLoader = Box::new(move || Ok(new_conf_clone.clone()));
+        let state = state.reload(&loader);
+        assert_eq!(state.conf, new_conf);
+        assert!(logs_contain("signature added: new-signature"));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_reload_fixed_port() {
+        let port = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let conf = ServerConfig {
+            port,
+            multicast_group: Some(Ipv4Addr::new(239, 255, 19, 1)),
+            ipv6_group: Some(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x1901)),
+            ..ServerConfig::dummy()
+        };
+        let state = ServerState::new(conf.clone()).unwrap();
+        let has_v6 = state.listener_v6.is_some();
+        let new_conf = ServerConfig {
+            interfaces: vec!["lo".into()],
+            multicast_group: Some(Ipv4Addr::new(239, 255, 19, 2)),
+            ipv6_group: Some(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x1902)),
+            ..conf.clone()
+        };
+        let new_conf_clone = new_conf.clone();
+        let loader: ConfigLoader = Box::new(move || Ok(new_conf_clone.clone()));
+        let state = state.reload(&loader);
+        assert_eq!(state.conf, new_conf);
+        assert!(!logs_contain("Configuration reload failed"));
+        assert_eq!(state.listener.socket.local_addr().unwrap().port(), port);
+        assert_eq!(
+            state.listener.membership.as_ref().unwrap().group,
+            IpAddr::V4(Ipv4Addr::new(239, 255, 19, 2))
+        );
+        assert_eq!(state.listener_v6.is_some(), has_v6);
+        if let Some(listener) = &state.listener_v6 {
+            assert_eq!(listener.socket.local_addr().unwrap().port(), port);
+        }
+        // IPv6 disabled, then enabled again on the same port.
+        let loader: ConfigLoader = Box::new(move || Ok(conf.clone()));
+        let disabled_conf = ServerConfig {
+            ipv6_group: None,
+            ..new_conf
+        };
+        let disable: ConfigLoader = Box::new(move || Ok(disabled_conf.clone()));
+        let state = state.reload(&disable);
+        assert!(state.listener_v6.is_none());
+        let state = state.reload(&loader);
+        assert!(!logs_contain("Configuration reload failed"));
+        assert_eq!(state.listener.socket.local_addr().unwrap().port(), port);
+        assert_eq!(state.listener_v6.is_some(), has_v6);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_reload_address() {
+        let port = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let conf = ServerConfig {
+            port,
+            listening_addr: Ipv4Addr::UNSPECIFIED,
+            ..ServerConfig::dummy()
+        };
+        let state = ServerState::new(conf.clone()).unwrap();
+        let new_conf = ServerConfig {
+            listening_addr: Ipv4Addr::LOCALHOST,
+            ..conf
+        };
+        let new_conf_clone = new_conf.clone();
+        let loader: ConfigLoader = Box::new(move || Ok(new_conf_clone.clone()));
+        let state = state.reload(&loader);
+        assert!(!logs_contain("Configuration reload failed"));
+        assert_eq!(state.conf, new_conf);
+        let local_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        assert_eq!(state.listener.socket.local_addr().unwrap(), local_addr);
+        // A documentation address, not of this host: the previous one is bound again.
+        let unavailable = ServerConfig {
+            listening_addr: Ipv4Addr::new(203, 0, 113, 1),
+            ..new_conf.clone()
+        };
+        let loader: ConfigLoader = Box::new(move || Ok(unavailable.clone()));
+        let state = state.reload(&loader);
+        assert!(logs_contain("Configuration reload failed"));
+        assert_eq!(state.conf, new_conf);
+        assert_eq!(state.listener.socket.local_addr().unwrap(), local_addr);
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_reload_inventory() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join("rust-ipdisserver-test-server-reload-inventory");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let go = dir.join("go");
+        let path = dir.join("inventory-new");
+        let script = format!(
+            "#!/bin/sh\nwhile [ ! -e {:?} ]; do sleep 0.01; done\necho new=1\n",
+            go
+        );
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let conf = ServerConfig {
+            port: 0,
+            ..ServerConfig::dummy()
+        };
+        let state = ServerState::new(conf.clone()).unwrap();
+        let new_conf = ServerConfig {
+            inventory_files: vec![InventoryFile::from(path.as_path())],
+            ..conf
+        };
+        let loader: ConfigLoader = Box::new(move || Ok(new_conf.clone()));
+        let mut state = state.reload(&loader); // does not wait for the inventory file
+        let answer = |state: &ServerState| -> serde_json::Value {
+            serde_json::from_slice(&state.cache.answer().unwrap().0).unwrap()
+        };
+        assert!(answer(&state).get("hostname").is_some()); // previous inventory still served
+        state.swap_cache_if_ready();
+        assert!(state.next_cache.is_some());
+        std::fs::write(&go, "").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while state.next_cache.is_some() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+            state.swap_cache_if_ready();
+        }
+        assert_eq!(answer(&state)["new"], "1");
+        assert!(logs_contain("Reloaded inventory ready."));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_control() {
+        let dir = std::env::temp_dir().join("rust-ipdisserver-test-server-control");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let control = ControlConfig {
+            path: dir.join("control.sock"),
+            mode: 0o600,
+        };
+        let conf = ServerConfig {
+            port: 0,
+            control: Some(control.clone()),
+            ..ServerConfig::dummy()
+        };
+        let state = ServerState::new(conf.clone()).unwrap();
+        let clock = Clock;
+        let rate_limiter = RateLimiter::new(&clock, conf.rate_limit.clone());
+        let counters = Counters {
+            received: 3,
+            answered: 1,
+            denied: 1,
+            rejected: 1,
+            failed: 1,
+        };
+        let reload_requested = AtomicBool::new(false);
+        let handle = |command| {
+            handle_control(
+                &command,
+                &state,
+                &counters,
+                &rate_limiter,
+                &reload_requested,
+            )
+        };
+        let stats: serde_json::Value =
+            serde_json::from_str(&handle(ControlCommand::Stats).unwrap()).unwrap();
+        assert_eq!(stats["received"], 3);
+        assert_eq!(stats["rejected"], 1);
+        assert_eq!(stats["failed"], 1);
+        assert_eq!(stats["sources"][0]["name"], "hostname");
+        assert!(stats["sources"][0]["error"].is_null());
+        let answer: serde_json::Value =
+            serde_json::from_str(&handle(ControlCommand::ShowAnswer(None)).unwrap()).unwrap();
+        assert!(answer.get("hostname").is_some());
+        assert!(handle(ControlCommand::ShowAnswer(Some("missing".into()))).is_err());
+        handle(ControlCommand::Reload).unwrap();
+        assert!(reload_requested.load(Ordering::Relaxed));
+        // Kept on reload if unchanged, moved if changed.
+        let loader: ConfigLoader = Box::new(move || Ok(conf.clone()));
+        let state = state.reload(&loader);
+        assert!(control.path.exists());
+        let moved = ControlConfig {
+            path: dir.join("moved.sock"),
+            ..control.clone()
+        };
+        let new_conf = ServerConfig {
+            control: Some(moved.clone()),
+            ..state.conf.clone()
+        };
+        let loader: ConfigLoader = Box::new(move || Ok(new_conf.clone()));
+        let state = state.reload(&loader);
+        assert!(!control.path.exists());
+        assert!(moved.path.exists());
+        drop(state);
+        assert!(!moved.path.exists());
+    }
+
+    fn select_datagram<'a>(
+        received: &Signature,
+        profiles: &'a [AccessProfile],
+        authenticator: &mut Authenticator,
+        now: SystemTime,
+    ) -> Option<&'a AccessProfile> {
+        let request = Request::decode(&received.0).unwrap();
+        select_profile(received, &request, profiles, authenticator, now)
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_signature_validation() {
+        let now = SystemTime::now();
+        let signature = Signature::from(SIGNATURE_DEFAULT);
+        let key = AuthKey::from("secret");
+        let authenticated = AuthRequest::new(now)
+            .unwrap()
+            .authenticate(&key, &Request::new(1, Signature::from("")));
+        let profiles = [AccessProfile::full(
+            vec![signature.clone()],
+            vec![key.clone()],
+        )];
+        let mut authenticator = Authenticator::new(Vec::new(), AUTH_WINDOW_DEFAULT, false);
+        assert!(select_datagram(&signature, &profiles, &mut authenticator, now).is_some());
+        assert!(select_datagram(&authenticated, &profiles, &mut authenticator, now).is_none());
+        authenticator.keys = vec![key];
+        assert!(select_datagram(&signature, &profiles, &mut authenticator, now).is_none());
+        assert!(select_datagram(&authenticated, &profiles, &mut authenticator, now).is_some());
+        assert!(select_datagram(&authenticated, &profiles, &mut authenticator, now).is_none());
+        assert!(logs_contain("nonce already used"));
+        authenticator.allow_unauthenticated = true;
+        assert!(select_datagram(&signature, &profiles, &mut authenticator, now).is_some());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_select_profile() {
+        let now = SystemTime::now();
+        let public_key = AuthKey::from("public-secret");
+        let ops_key = AuthKey::from("ops-secret");
+        let public = AccessProfile {
+            name: "public".into(),
+            ..AccessProfile::full(
+                vec![Signature::from("public-beacon")],
+                vec![public_key.clone()],
+            )
+        };
+        let profiles = [
+            public,
+            AccessProfile::full(vec![Signature::from("ops-beacon")], vec![ops_key.clone()]),
+        ];
+        let mut authenticator = Authenticator::new(
+            vec![public_key.clone(), ops_key.clone()],
+            AUTH_WINDOW_DEFAULT,
+            true,
+        );
+        let name = |profile: Option<&AccessProfile>| profile.map(|p| p.name.clone());
+        let mut select = |received: &Signature| {
+            name(select_datagram(
+                received,
+                &profiles,
+                &mut authenticator,
+                now,
+            ))
+        };
+        assert_eq!(
+            select(&Signature::from("public-beacon")),
+            Some("public".into())
+        );
+        assert_eq!(select(&Signature::from("ops-beacon")), Some("full".into()));
+        assert_eq!(select(&Signature::from("unknown")), None);
+        let request = |key| AuthRequest::new(now).unwrap().encode(key);
+        assert_eq!(select(&request(&public_key)), Some("public".into()));
+        assert_eq!(select(&request(&ops_key)), Some("full".into()));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_answer_message() {
+        let device_key = DeviceKey::generate().unwrap();
+        let answer = Answer::from(r#"{"hostname":"dummy"}"#.to_string());
+        let legacy = Request::decode(SIGNATURE_DEFAULT.as_bytes()).unwrap();
+        assert_eq!(
+            answer_message(&legacy, &answer, Some(&device_key))
+                .unwrap()
+                .encode(),
+            answer
+        );
+        let answer_key = AnswerKey::generate().unwrap();
+        let request = Request {
+            answer_key: Some(answer_key.public_key()),
+            ..Request::new(7, Signature::from(SIGNATURE_DEFAULT))
+        };
+        let message = answer_message(&request, &answer, Some(&device_key)).unwrap();
+        let decoded = ServerMessage::decode(&message.encode()).unwrap();
+        assert_eq!(decoded.request_id, 7);
+        assert_eq!(decoded.flags, Flags::SIGNED | Flags::ENCRYPTED);
+        let signed = answer_key.decrypt(&decoded.payload).unwrap().unwrap();
+        let context = SignatureContext {
+            message_type: MessageType::Answer,
+            request_id: 7,
+        };
+        let (payload, signer) = open_signed_answer(&signed, &context);
+        assert_eq!(payload, answer);
+        assert!(matches!(
+            signer,
+            AnswerSigner::Valid { key, .. } if key == device_key.public_key()
+        ));
+        // Without a device key, e.g. if it cannot be saved, answers are sent unsigned.
+        let message = answer_message(&request, &answer, None).unwrap();
+        assert_eq!(message.flags, Flags::ENCRYPTED);
+        assert_eq!(answer_key.decrypt(&message.payload).unwrap(), Some(answer));
     }
 }
@@ -0,0 +1,559 @@
+use crate::announce::AnnounceConfig;
+use crate::auth::AUTH_WINDOW_DEFAULT;
+use crate::conf::{
+    INVENTORY_DIR_PATTERN_DEFAULT, INVENTORY_TTL_DEFAULT, INVENTORY_WORKERS_DEFAULT,
+    LISTENING_ADDR_DEFAULT, MULTICAST_V6_ADDR_DEFAULT, SERVER_PORT_DEFAULT,
+};
+use crate::control::{CONTROL_MODE_DEFAULT, CONTROL_SOCKET_DEFAULT};
+use crate::exec::{MAX_OUTPUT_DEFAULT, TIMEOUT_DEFAULT};
+use crate::identity::DEVICE_KEY_FILE_DEFAULT;
+use crate::rate_limit::RateLimitConfig;
+use color_eyre::eyre::{Report, WrapErr};
+use figment::providers::{Env, Format, Serialized, Toml};
+use figment::Figment;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::{info, warn};
+
+/// Prefix of the environment variables overriding the configuration file,
+/// nested keys are separated by `__`, e.g. `IPDISSERVER_INVENTORY__TTL=30`.
+/// Variables not naming a settings section or key, e.g. `IPDISSERVER_HOME`, are ignored
+/// with a warning.
+pub const ENV_PREFIX: &str = "IPDISSERVER_";
+
+/// Server settings, as read from the configuration file and the environment.
+/// Unknown keys are rejected.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub port: u16,
+    pub addr: Ipv4Addr,
+    /// Interface names where requests are accepted, all if empty.
+    pub interfaces: Vec<String>,
+    pub multicast: MulticastSettings,
+    pub ipv6: Ipv6Settings,
+    pub clients: ClientSettings,
+    /// Accepted signatures, in addition to the ones in `signatures_file`.
+    pub signatures: Vec<String>,
+    pub signatures_file: Option<PathBuf>,
+    /// Secret key signing the answers, generated if missing.
+    pub key_file: PathBuf,
+    pub inventory: InventorySettings,
+    pub rate_limit: RateLimitSettings,
+    pub auth: AuthSettings,
+    /// Tried in order before `signatures` and `auth.keys`, which get the whole inventory.
+    pub profiles: Vec<ProfileSettings>,
+    pub announce: AnnounceSettings,
+    pub control: ControlSettings,
+    pub log: LogSettings,
+}
+
+/// Requests received on an IPv4 multicast group, in addition to broadcast and unicast ones.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MulticastSettings {
+    /// Disabled if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<Ipv4Addr>,
+    /// Interface names, all the multicast capable interfaces if empty.
+    pub interfaces: Vec<String>,
+}
+
+/// Requests received on an IPv6 multicast group, e.g. from hosts with link-local addresses only.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Ipv6Settings {
+    pub enabled: bool,
+    pub group: Ipv6Addr,
+    /// Interface names, all the multicast capable interfaces if empty.
+    pub interfaces: Vec<String>,
+}
+
+/// Networks of the clients allowed to get answers, in CIDR notation, e.g. `10.0.0.0/8`.
+/// An empty allow list allows everything, the deny list takes precedence.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientSettings {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InventorySettings {
+    pub files: Vec<InventoryFileSettings>,
+    pub dirs: Vec<PathBuf>,
+    pub pattern: String,
+    /// Seconds, default for all the inventory files.
+    pub ttl: u64,
+    /// Seconds, default for all the inventory files.
+    pub timeout: f64,
+    /// Bytes, default for all the inventory files.
+    pub max_output: usize,
+    pub workers: usize,
+}
+
+/// Inventory file, unset values default to the ones in `InventorySettings`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InventoryFileSettings {
+    pub path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_output: Option<usize>,
+}
+
+/// Token buckets: each client gets `burst` answers in a row, then one every `timeout`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitSettings {
+    /// Seconds to earn a new answer, for each client.
+    pub timeout: f64,
+    pub burst: u32,
+    /// Clients in the same subnet share their answers, 32 and 128 limit each address.
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    /// Answers per second to all the clients together, 0 for no limit.
+    pub global_rate: u32,
+    /// Clients remembered, the least recently seen ones are forgotten first.
+    pub max_clients: usize,
+}
+
+/// Authenticated requests: HMAC of a timestamp and a nonce, keyed by a shared secret.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSettings {
+    /// Accepted keys, in addition to the ones in `keys_file`.
+    pub keys: Vec<Secret>,
+    pub keys_file: Option<PathBuf>,
+    /// Seconds, maximum difference between the request timestamp and the local clock.
+    pub window: f64,
+    /// Accept plain signatures too. Always true if there are no keys.
+    pub allow_unauthenticated: bool,
+}
+
+/// Secret shared with the scanners, hidden in the debug output so that settings can be logged.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret(<{} bytes>)", self.0.len())
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(string: &str) -> Self {
+        Self(string.to_string())
+    }
+}
+
+/// Inventory subset answered to some signatures or authentication keys.
+/// Keys and sources are glob patterns, empty allow lists allow everything.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfileSettings {
+    pub name: String,
+    pub signatures: Vec<String>,
+    pub auth_keys: Vec<Secret>,
+    pub allow_keys: Vec<String>,
+    pub deny_keys: Vec<String>,
+    /// Inventory file paths, or names of the built-in sources.
+    pub allow_sources: Vec<String>,
+    pub deny_sources: Vec<String>,
+}
+
+/// Answers sent unsolicited to the scanners, at start, on inventory change and periodically.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnnounceSettings {
+    /// Broadcast or multicast addresses, e.g. `255.255.255.255` or `ff02::1901`.
+    /// Announcements are disabled if empty.
+    pub addrs: Vec<IpAddr>,
+    /// Port of the scanners.
+    pub port: u16,
+    /// Seconds between announcements of an unchanged inventory, 0 to announce only at start
+    /// and on changes.
+    pub interval: f64,
+    /// Access profile applied to the announced inventory.
+    pub profile: String,
+}
+
+/// Local socket used by `ipdisserver ctl`, protected by its file permissions.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlSettings {
+    pub enabled: bool,
+    pub path: PathBuf,
+    /// Permissions of the socket file, e.g. `0o660` to allow the group.
+    pub mode: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSettings {
+    pub journald: bool,
+    /// Logging directives, overridden by `RUST_LOG`. See
+    /// https://docs.rs/tracing-subscriber/latest/tracing_subscriber/filter/struct.EnvFilter.html#directives
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            port: SERVER_PORT_DEFAULT,
+            addr: LISTENING_ADDR_DEFAULT,
+            interfaces: Vec::new(),
+            multicast: MulticastSettings::default(),
+            ipv6: Ipv6Settings::default(),
+            clients: ClientSettings::default(),
+            signatures: Vec::new(),
+            signatures_file: None,
+            key_file: PathBuf::from(DEVICE_KEY_FILE_DEFAULT),
+            inventory: InventorySettings::default(),
+            rate_limit: RateLimitSettings::default(),
+            auth: AuthSettings::default(),
+            profiles: Vec::new(),
+            announce: AnnounceSettings::default(),
+            control: ControlSettings::default(),
+            log: LogSettings::default(),
+        }
+    }
+}
+
+impl Default for Ipv6Settings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            group: MULTICAST_V6_ADDR_DEFAULT,
+            interfaces: Vec::new(),
+        }
+    }
+}
+
+impl Default for InventorySettings {
+    fn default() -> Self {
+        Self {
+            files: Vec::new(),
+            dirs: Vec::new(),
+            pattern: INVENTORY_DIR_PATTERN_DEFAULT.into(),
+            ttl: INVENTORY_TTL_DEFAULT.as_secs(),
+            timeout: TIMEOUT_DEFAULT.as_secs_f64(),
+            max_output: MAX_OUTPUT_DEFAULT,
+            workers: INVENTORY_WORKERS_DEFAULT,
+        }
+    }
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        let defaults = RateLimitConfig::default();
+        Self {
+            timeout: defaults.interval.as_secs_f64(),
+            burst: defaults.burst,
+            ipv4_prefix: defaults.ipv4_prefix,
+            ipv6_prefix: defaults.ipv6_prefix,
+            global_rate: defaults.global_rate,
+            max_clients: defaults.max_clients,
+        }
+    }
+}
+
+impl Default for AnnounceSettings {
+    fn default() -> Self {
+        let defaults = AnnounceConfig::default();
+        Self {
+            addrs: defaults.addrs,
+            port: defaults.port,
+            interval: defaults.interval.unwrap_or_default().as_secs_f64(),
+            profile: defaults.profile,
+        }
+    }
+}
+
+impl Default for ControlSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: PathBuf::from(CONTROL_SOCKET_DEFAULT),
+            mode: CONTROL_MODE_DEFAULT,
+        }
+    }
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        Self {
+            keys: Vec::new(),
+            keys_file: None,
+            window: AUTH_WINDOW_DEFAULT.as_secs_f64(),
+            allow_unauthenticated: false,
+        }
+    }
+}
+
+/// Inventory file argument: `PATH` or `PATH:TTL`, TTL in seconds.
+impl FromStr for InventoryFileSettings {
+    type Err = Infallible;
+
+    fn from_str(arg: &str) -> Result<Self, Self::Err> {
+        let (path, ttl) = match arg.rsplit_once(':') {
+            Some((path, ttl)) => match ttl.parse::<u64>() {
+                Ok(seconds) => (path, Some(seconds)),
+                Err(_) => (arg, None),
+            },
+            None => (arg, None),
+        };
+        Ok(Self {
+            path: PathBuf::from(path),
+            ttl,
+            timeout: None,
+            max_output: None,
+        })
+    }
+}
+
+impl Settings {
+    /// Defaults, overridden by the configuration file (if any), overridden by the environment.
+    /// Merge more providers on top of it (e.g. CLI options), then extract the settings.
+    pub fn figment(config_file: Option<&Path>) -> Result<Figment, Report> {
+        let mut figment = Figment::from(Serialized::defaults(Settings::default()));
+        if let Some(path) = config_file {
+            info!(?path, "Reading configuration file.");
+            let path = fs::canonicalize(path)
+                .wrap_err_with(|| format!("Cannot read configuration file {:?}", path))?;
+            figment = figment.merge(Toml::file(path));
+        }
+        Ok(figment.merge(Self::env()))
+    }
+
+    /// `ENV_PREFIX` variables whose first key is a known setting, so that unrelated variables
+    /// do not stop the server. Unknown nested keys are still rejected, they are likely typos.
+    fn env() -> Env {
+        let known: Vec<String> = match serde_json::to_value(Settings::default()) {
+            Ok(serde_json::Value::Object(settings)) => settings.keys().cloned().collect(),
+            _ => Vec::new(),
+        };
+        Env::prefixed(ENV_PREFIX).split("__").filter(move |key| {
+            let first = key.as_str().split('.').next().unwrap_or_default();
+            let is_known = known.iter().any(|k| k.eq_ignore_ascii_case(first));
+            if !is_known {
+                let var = format!("{}{}", ENV_PREFIX, key.as_str().replace('.', "__"));
+                warn!(
+                    var = var.to_uppercase(),
+                    "Ignoring environment variable, not a setting."
+                );
+            }
+            is_known
+        })
+    }
+
+    pub fn load(config_file: Option<&Path>) -> Result<Self, Report> {
+        Ok(Self::figment(config_file)?.extract()?)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::result_large_err)] // imposed by figment::Jail
+mod test {
+    use super::*;
+    use figment::Jail;
+
+    #[test]
+    fn test_defaults() {
+        Jail::expect_with(|_jail| {
+            assert_eq!(Settings::load(None).unwrap(), Settings::default());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_file_and_env() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "ipdisserver.toml",
+                r#"
+                port = 1234
+                addr = "192.168.1.10"
+                interfaces = ["eth1"]
+                signatures = ["sign-a", "sign-b"]
+
+                [multicast]
+                group = "239.255.19.1"
+
+                [ipv6]
+                interfaces = ["eth0", "wlan0"]
+
+                [clients]
+                allow = ["10.0.0.0/8", "fd00::/8"]
+
+                [inventory]
+                dirs = ["/usr/share/ipdisserver/inventory.d"]
+                ttl = 120
+
+                [[inventory.files]]
+                path = "/usr/bin/inventory-disk"
+                ttl = 300
+
+                [[inventory.files]]
+                path = "/usr/bin/inventory-fw"
+
+                [rate_limit]
+                timeout = 3
+                ipv6_prefix = 64
+
+                [auth]
+                keys = ["shared-secret"]
+
+                [[profiles]]
+                name = "public"
+                signatures = ["public-beacon"]
+                auth_keys = ["public-secret"]
+                allow_keys = ["hostname"]
+
+                [announce]
+                addrs = ["255.255.255.255", "ff02::1901"]
+                interval = 0
+
+                [control]
+                mode = 0o660
+
+                [log]
+                journald = true
+                "#,
+            )?;
+            jail.set_env("IPDISSERVER_PORT", 4321);
+            jail.set_env("IPDISSERVER_INVENTORY__WORKERS", 2);
+            let settings = Settings::load(Some(Path::new("ipdisserver.toml"))).unwrap();
+            assert_eq!(settings.port, 4321);
+            assert_eq!(settings.addr, Ipv4Addr::new(192, 168, 1, 10));
+            assert_eq!(settings.interfaces, vec!["eth1"]);
+            assert_eq!(settings.signatures, vec!["sign-a", "sign-b"]);
+            assert_eq!(
+                settings.multicast.group,
+                Some(Ipv4Addr::new(239, 255, 19, 1))
+            );
+            assert!(settings.multicast.interfaces.is_empty());
+            assert!(settings.ipv6.enabled);
+            assert_eq!(settings.ipv6.interfaces, vec!["eth0", "wlan0"]);
+            assert_eq!(
+                settings.clients.allow,
+                vec![
+                    "10.0.0.0/8".parse::<IpNet>().unwrap(),
+                    "fd00::/8".parse().unwrap()
+                ]
+            );
+            assert!(settings.clients.deny.is_empty());
+            assert_eq!(settings.inventory.ttl, 120);
+            assert_eq!(settings.inventory.workers, 2);
+            assert_eq!(
+                settings.inventory.pattern,
+                InventorySettings::default().pattern
+            );
+            assert_eq!(
+                settings.inventory.files,
+                vec![
+                    "/usr/bin/inventory-disk:300".parse().unwrap(),
+                    "/usr/bin/inventory-fw".parse().unwrap(),
+                ]
+            );
+            assert_eq!(settings.rate_limit.timeout, 3.0);
+            assert_eq!(settings.rate_limit.ipv6_prefix, 64);
+            assert_eq!(
+                settings.rate_limit.burst,
+                RateLimitSettings::default().burst
+            );
+            assert_eq!(
+                settings.profiles,
+                vec![ProfileSettings {
+                    name: "public".into(),
+                    signatures: vec!["public-beacon".into()],
+                    auth_keys: vec!["public-secret".into()],
+                    allow_keys: vec!["hostname".into()],
+                    ..Default::default()
+                }]
+            );
+            assert_eq!(settings.auth.keys, vec!["shared-secret".into()]);
+            let logged = format!("{:?}", settings); // see `Secret`
+            assert!(!logged.contains("shared-secret") && !logged.contains("public-secret"));
+            assert_eq!(
+                settings.announce.addrs,
+                vec![
+                    IpAddr::V4(Ipv4Addr::BROADCAST),
+                    IpAddr::V6(MULTICAST_V6_ADDR_DEFAULT)
+                ]
+            );
+            assert_eq!(settings.announce.interval, 0.0);
+            assert_eq!(settings.announce.profile, "full");
+            assert!(settings.control.enabled);
+            assert_eq!(settings.control.mode, 0o660);
+            assert!(settings.log.journald);
+            Ok(())
+        });
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_unknown_keys() {
+        Jail::expect_with(|jail| {
+            jail.create_file("ipdisserver.toml", "[inventory]\nttls = 120\n")?;
+            let error = Settings::load(Some(Path::new("ipdisserver.toml"))).unwrap_err();
+            assert!(error.to_string().contains("unknown field: found `ttls`"));
+            jail.create_file("ipdisserver.toml", "[clients]\nallow = [\"10.0.0.1\"]\n")?;
+            assert!(Settings::load(Some(Path::new("ipdisserver.toml"))).is_err());
+            jail.create_file("ipdisserver.toml", "")?;
+            jail.set_env("IPDISSERVER_HOME", "/var/lib/ipdisserver"); // not a setting, ignored
+            assert_eq!(
+                Settings::load(Some(Path::new("ipdisserver.toml"))).unwrap(),
+                Settings::default()
+            );
+            assert!(logs_contain("IPDISSERVER_HOME"));
+            jail.set_env("IPDISSERVER_INVENTORY__TTLS", 30);
+            let error = Settings::load(Some(Path::new("ipdisserver.toml"))).unwrap_err();
+            assert!(error.to_string().contains("unknown field: found `ttls`"));
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_missing_file() {
+        Jail::expect_with(|_jail| {
+            assert!(Settings::load(Some(Path::new("missing.toml"))).is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_inventory_file_from_str() {
+        let file = |path: &str, ttl| InventoryFileSettings {
+            path: PathBuf::from(path),
+            ttl,
+            timeout: None,
+            max_output: None,
+        };
+        let parse = |arg: &str| arg.parse::<InventoryFileSettings>().unwrap();
+        assert_eq!(
+            parse("/usr/bin/inventory"),
+            file("/usr/bin/inventory", None)
+        );
+        assert_eq!(
+            parse("/usr/bin/inventory:300"),
+            file("/usr/bin/inventory", Some(300))
+        );
+        assert_eq!(
+            parse("/opt/a:b/inventory"),
+            file("/opt/a:b/inventory", None)
+        );
+    }
+}
@@ -7,20 +7,24 @@ use tracing_subscriber::EnvFilter;
 const DEFAULT_STDERR_LOG_LVL: &str = "warn";
 const DEFAULT_JOURNAL_LOG_LVL: &str = "info";
 
-pub fn setup(log_to_journald: bool) -> Result<(), Report> {
+/// `log_level` replaces the default logging directives, `RUST_LOG` still takes precedence.
+pub fn setup(log_to_journald: bool, log_level: Option<&str>) -> Result<(), Report> {
     match log_to_journald {
-        false => install_stderr_tracing(),
-        true => install_journald_tracing().unwrap_or_else(|_| {
-            install_stderr_tracing();
-            error!("Failed to connect to journald, logging to stderr.")
-        }),
+        false => install_stderr_tracing(log_level.unwrap_or(DEFAULT_STDERR_LOG_LVL))?,
+        true => {
+            let level = log_level.unwrap_or(DEFAULT_JOURNAL_LOG_LVL);
+            if install_journald_tracing(level).is_err() {
+                install_stderr_tracing(level)?;
+                error!("Failed to connect to journald, logging to stderr.")
+            }
+        }
     };
     color_eyre::install()?;
     Ok(())
 }
 
-fn install_stderr_tracing() {
-    let filter_layer = get_envfilter(DEFAULT_STDERR_LOG_LVL);
+fn install_stderr_tracing(level: &str) -> Result<(), Report> {
+    let filter_layer = get_envfilter(level)?;
     let fmt_layer = tracing_subscriber::fmt::layer()
         .with_target(false)
         .with_writer(std::io::stderr);
@@ -29,11 +33,12 @@ fn install_stderr_tracing() {
         .with(ErrorLayer::default())
         .with(fmt_layer)
         .init();
+    Ok(())
 }
 
-fn install_journald_tracing() -> Result<(), Report> {
-    let filter_layer = get_envfilter(DEFAULT_JOURNAL_LOG_LVL);
+fn install_journald_tracing(level: &str) -> Result<(), Report> {
     let fmt_layer = tracing_journald::layer()?;
+    let filter_layer = get_envfilter(level)?;
     tracing_subscriber::registry()
         .with(filter_layer)
         .with(ErrorLayer::default())
@@ -44,8 +49,7 @@ fn install_journald_tracing() -> Result<(), Report> {
 
 /// Logging levels configuration as per
 /// https://docs.rs/tracing-subscriber/latest/tracing_subscriber/filter/struct.EnvFilter.html#directives
-fn get_envfilter(default: &str) -> EnvFilter {
-    EnvFilter::try_from_default_env()
-        .or_else(|_| EnvFilter::try_new(default))
-        .unwrap()
+fn get_envfilter(default: &str) -> Result<EnvFilter, Report> {
+    let filter = EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new(default))?;
+    Ok(filter)
 }
@@ -0,0 +1,153 @@
+//! Socket activation and service notifications, see `sd_listen_fds(3)` and `sd_notify(3)`.
+//! Everything is a no-op when not started by systemd.
+
+use color_eyre::eyre::{Report, WrapErr};
+use sd_notify::NotifyState;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::fd::FromRawFd;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Sockets passed by systemd socket activation, used instead of binding new ones.
+#[derive(Debug, Default)]
+pub struct InheritedSockets {
+    pub v4: Option<UdpSocket>,
+    pub v6: Option<UdpSocket>,
+}
+
+impl InheritedSockets {
+    /// Take the sockets listed in `LISTEN_FDS`, at most one IPv4 and one IPv6 datagram
+    /// socket. The variables are unset, so that inventory files do not inherit them.
+    /// Must be called before spawning threads.
+    pub fn from_env() -> Result<Self, Report> {
+        let mut res = Self::default();
+        for fd in sd_notify::listen_fds().wrap_err("Invalid LISTEN_FDS")? {
+            // SAFETY: the descriptors from LISTEN_FDS are handed over to this process.
+            let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+            let slot = match socket.local_addr() {
+                Ok(SocketAddr::V4(_)) => &mut res.v4,
+                Ok(SocketAddr::V6(_)) => &mut res.v6,
+                Err(error) => {
+                    warn!(?error, fd, "Inherited socket is not a UDP one, ignored.");
+                    continue;
+                }
+            };
+            if slot.is_some() {
+                warn!(
+                    ?socket,
+                    "Several inherited sockets of the same family, ignored."
+                );
+                continue;
+            }
+            info!(?socket, "Using socket passed by systemd.");
+            *slot = Some(socket);
+        }
+        Ok(res)
+    }
+}
+
+/// Notifications to the service manager.
+#[derive(Debug)]
+pub struct Notifier {
+    /// Half of `WatchdogSec`, None if the watchdog is disabled.
+    watchdog_period: Option<Duration>,
+    next_ping: Instant,
+    /// At the last check, to warn once when becoming unhealthy.
+    healthy: bool,
+}
+
+impl Notifier {
+    /// Read `WATCHDOG_USEC`, unsetting it. Must be called before spawning threads.
+    pub fn from_env() -> Self {
+        let mut usec = 0;
+        let watchdog_period = match sd_notify::watchdog_enabled(true, &mut usec) {
+            true => Some(Duration::from_micros(usec) / 2),
+            false => None,
+        };
+        debug!(?watchdog_period, "Service notifications set up.");
+        Self {
+            watchdog_period,
+            next_ping: Instant::now(),
+            healthy: true,
+        }
+    }
+
+    pub fn ready(&self, status: &str) {
+        notify(&[NotifyState::Ready, NotifyState::Status(status)]);
+    }
+
+    pub fn status(&self, status: &str) {
+        notify(&[NotifyState::Status(status)]);
+    }
+
+    pub fn stopping(&self) {
+        notify(&[NotifyState::Stopping]);
+    }
+
+    /// Ping the watchdog if due, unless the inventory was not refreshed for `WatchdogSec`
+    /// (`last_refresh`). Without pings, systemd restarts the service after `WatchdogSec`.
+    pub fn watchdog(&mut self, last_refresh: Option<Instant>) {
+        let Some(period) = self.watchdog_period else {
+            return;
+        };
+        if Instant::now() < self.next_ping {
+            return;
+        }
+        let healthy = last_refresh.is_some_and(|t| t.elapsed() < period * 2);
+        if !healthy {
+            if self.healthy {
+                warn!("Inventory refresh stuck, not pinging the watchdog.");
+            }
+            self.healthy = false;
+            return;
+        }
+        self.healthy = true;
+        notify(&[NotifyState::Watchdog]);
+        self.next_ping = Instant::now() + period;
+    }
+}
+
+fn notify(states: &[NotifyState]) {
+    if let Err(error) = sd_notify::notify(false, states) {
+        warn!(?error, "Cannot notify systemd.");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_notifier() {
+        let path = std::env::temp_dir().join(format!(
+            "rust-ipdisserver-test-notify-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        std::env::set_var("NOTIFY_SOCKET", &path);
+        std::env::set_var("WATCHDOG_USEC", "2000000");
+        std::env::set_var("WATCHDOG_PID", std::process::id().to_string());
+        let mut notifier = Notifier::from_env();
+        assert!(std::env::var("WATCHDOG_USEC").is_err());
+        assert_eq!(notifier.watchdog_period, Some(Duration::from_secs(1)));
+        let mut buf = [0; 256];
+        let mut received = || {
+            let length = systemd.recv(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..length]).into_owned()
+        };
+        notifier.ready("Answered 0 requests");
+        assert_eq!(received(), "READY=1\nSTATUS=Answered 0 requests\n");
+        notifier.watchdog(None); // never refreshed
+        assert!(logs_contain("Inventory refresh stuck"));
+        notifier.watchdog(Some(Instant::now()));
+        assert_eq!(received(), "WATCHDOG=1\n");
+        notifier.watchdog(Some(Instant::now())); // not due yet
+        notifier.stopping();
+        assert_eq!(received(), "STOPPING=1\n");
+        std::env::remove_var("NOTIFY_SOCKET");
+        std::fs::remove_file(&path).unwrap();
+    }
+}